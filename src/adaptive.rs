@@ -0,0 +1,214 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::stats::Stats;
+
+/// Smoothing applied to each budget's matched count per bucket, so a single
+/// burst doesn't swing every budget's capacity.
+const SMOOTHING: f64 = 0.5;
+
+/// Tracks recent matched counts to share a target output rate between
+/// budgets. See
+/// [`target_rate`](crate::SamplingLayerBuilder::target_rate).
+pub(crate) struct Adaptive {
+    /// Events to keep per second across all budgets.
+    target: f64,
+    /// Smoothed events matched per bucket, indexed by budget.
+    rates: Vec<f64>,
+}
+
+impl Adaptive {
+    pub(crate) fn new(target: u64, budgets: usize) -> Self {
+        Self {
+            target: target as f64,
+            rates: vec![0.0; budgets],
+        }
+    }
+
+    /// Record the events each budget matched in the bucket just ended, and
+    /// return the factor to scale every budget's limit by so that about the
+    /// target is kept in total. The limits set the budgets' relative shares.
+    pub(crate) fn scale(&mut self, bucket: Duration, seen: &[usize], limits: &[u64]) -> f64 {
+        for (rate, &seen) in self.rates.iter_mut().zip(seen) {
+            *rate += (seen as f64 - *rate) * SMOOTHING;
+        }
+        let secs = bucket.as_secs_f64();
+        let capacities: Vec<f64> = limits.iter().map(|&limit| limit as f64 * secs).collect();
+        water_fill(self.target * secs, &self.rates, &capacities)
+    }
+}
+
+/// The smallest fraction of their capacity budgets are scaled down to
+/// under backpressure, so some events still reach the sink to show whether
+/// it has recovered.
+const MIN_FACTOR: f64 = 1.0 / 64.0;
+
+/// How much of their capacity budgets regain for each bucket the sink
+/// keeps up.
+const RECOVERY: f64 = 1.0 / 16.0;
+
+/// Scales budgets down while the sink struggles, halving their capacities
+/// for each bucket in which writes were slow or failed and restoring them
+/// gradually once it recovers. See
+/// [`backpressure`](crate::SamplingLayerBuilder::backpressure).
+pub(crate) struct Backpressure {
+    /// Smoothed nanoseconds per event above which the sink is struggling.
+    max_cost: u64,
+    factor: f64,
+    /// Write errors and lost events as of the last bucket.
+    failures: u64,
+}
+
+impl Backpressure {
+    pub(crate) fn new(max_cost: Duration) -> Self {
+        Self {
+            max_cost: max_cost.as_nanos().try_into().unwrap_or(u64::MAX),
+            factor: 1.0,
+            failures: 0,
+        }
+    }
+
+    /// Update for the bucket just ended and return the factor to scale
+    /// every budget's capacity by.
+    pub(crate) fn scale(&mut self, stats: &Stats) -> f64 {
+        let failures = stats.write_errors() + stats.lost();
+        let struggling =
+            failures > self.failures || stats.write_cost.load(Ordering::Relaxed) > self.max_cost;
+        self.failures = failures;
+        self.factor = if struggling {
+            (self.factor / 2.0).max(MIN_FACTOR)
+        } else {
+            (self.factor + RECOVERY).min(1.0)
+        };
+        self.factor
+    }
+}
+
+/// A budget's [`burst`](crate::Budget::burst) credit.
+pub(crate) struct Burst {
+    events: usize,
+    every: Duration,
+    credit: usize,
+    /// Extra capacity given to the current bucket.
+    granted: usize,
+    refilled: Instant,
+}
+
+impl Burst {
+    /// A full credit, all of it granted to the first bucket.
+    pub(crate) fn new(events: usize, every: Duration, now: Instant) -> Self {
+        Self {
+            events,
+            every,
+            credit: events,
+            granted: events,
+            refilled: now,
+        }
+    }
+
+    /// Charge the credit for the events a budget kept beyond its usual
+    /// capacity in the bucket just ended, given the bucket's `capacity`
+    /// including the credit granted, refill it if due, and return the extra
+    /// capacity to grant the next bucket.
+    pub(crate) fn next(&mut self, seen: usize, capacity: usize, now: Instant) -> usize {
+        let usual = capacity.saturating_sub(self.granted);
+        self.credit -= seen.saturating_sub(usual).min(self.granted);
+        if now.duration_since(self.refilled) >= self.every {
+            self.credit = self.events;
+            self.refilled = now;
+        }
+        self.granted = self.credit;
+        self.granted
+    }
+}
+
+/// The fraction of its limit a budget ramping up over `ramp_up` gets in a
+/// bucket ending `elapsed` after the layer was built.
+pub(crate) fn ramp(ramp_up: Option<Duration>, elapsed: Duration) -> f64 {
+    match ramp_up {
+        Some(ramp_up) if elapsed < ramp_up => elapsed.as_secs_f64() / ramp_up.as_secs_f64(),
+        _ => 1.0,
+    }
+}
+
+/// The scale `s` at which budgets keeping `min(rate, s * capacity)` events
+/// each keep `target` in total. Budgets that need less than their share
+/// leave the rest to the others.
+///
+/// If every budget fits within the target, the scale is just large enough
+/// for each to keep all it matched, and for the scaled capacities to add up
+/// to at least the target.
+fn water_fill(target: f64, rates: &[f64], capacities: &[f64]) -> f64 {
+    let mut budgets: Vec<(f64, f64)> = (rates.iter().copied())
+        .zip(capacities.iter().copied())
+        .filter(|&(_, capacity)| capacity > 0.0)
+        .collect();
+    let total: f64 = budgets.iter().map(|&(_, capacity)| capacity).sum();
+    if total == 0.0 {
+        return 1.0;
+    }
+    if budgets.iter().map(|&(rate, _)| rate).sum::<f64>() <= target {
+        return (budgets.iter())
+            .map(|&(rate, capacity)| rate / capacity)
+            .fold(target / total, f64::max);
+    }
+    budgets.sort_by(|a, b| (a.0 / a.1).total_cmp(&(b.0 / b.1)));
+    let (mut remaining, mut capacity_left) = (target, total);
+    let mut scale = target / total;
+    for (rate, capacity) in budgets {
+        scale = remaining / capacity_left;
+        if rate >= scale * capacity {
+            break;
+        }
+        remaining -= rate;
+        capacity_left -= capacity;
+    }
+    scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_target_by_capacity_when_all_saturated() {
+        let scale = water_fill(100.0, &[1000.0, 1000.0], &[100.0, 300.0]);
+        assert_eq!(scale, 0.25);
+    }
+
+    #[test]
+    fn quiet_budgets_leave_their_share_to_others() {
+        // The first budget only needs 10 of its 50, leaving 90 for the second.
+        let scale = water_fill(100.0, &[10.0, 1000.0], &[100.0, 100.0]);
+        assert_eq!(scale, 0.9);
+    }
+
+    #[test]
+    fn under_target_keeps_everything() {
+        let scale = water_fill(100.0, &[10.0, 80.0], &[10.0, 40.0]);
+        assert_eq!(scale, 2.0);
+        let scale = water_fill(100.0, &[0.0, 0.0], &[10.0, 40.0]);
+        assert_eq!(scale, 2.0);
+    }
+
+    #[test]
+    fn ramp_is_linear_until_done() {
+        let ramp_up = Some(Duration::from_secs(4));
+        assert_eq!(ramp(ramp_up, Duration::ZERO), 0.0);
+        assert_eq!(ramp(ramp_up, Duration::from_secs(1)), 0.25);
+        assert_eq!(ramp(ramp_up, Duration::from_secs(5)), 1.0);
+        assert_eq!(ramp(None, Duration::ZERO), 1.0);
+    }
+
+    #[test]
+    fn burst_credit_is_spent_and_refilled() {
+        let start = Instant::now();
+        let mut burst = Burst::new(5, Duration::from_secs(10), start);
+        // Three events over the usual capacity of 2.
+        assert_eq!(burst.next(5, 7, start + Duration::from_secs(1)), 2);
+        // Quiet buckets don't spend it.
+        assert_eq!(burst.next(1, 4, start + Duration::from_secs(2)), 2);
+        assert_eq!(burst.next(10, 4, start + Duration::from_secs(3)), 0);
+        assert_eq!(burst.next(10, 2, start + Duration::from_secs(10)), 5);
+    }
+}
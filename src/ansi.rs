@@ -0,0 +1,104 @@
+use std::io::{self, Write};
+
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// A [`MakeWriter`] adapter that removes ANSI escape sequences before
+/// writing, so coloured output can also go to a plain file:
+///
+/// ```
+/// use tracing::Level;
+/// use tracing_log_sample::{SamplingLayer, StripAnsi};
+/// use tracing_subscriber::fmt::writer::MakeWriterExt;
+/// use tracing_subscriber::prelude::*;
+///
+/// let file = std::fs::File::create(std::env::temp_dir().join("sampled.log")).unwrap();
+/// let (layer, _stats) = SamplingLayer::builder()
+///     .budget_level(Level::INFO, 1000)
+///     .writer(std::io::stderr.and(StripAnsi::new(std::sync::Mutex::new(file))))
+///     .build();
+/// let subscriber = tracing_subscriber::registry().with(layer);
+/// ```
+///
+/// Each event is written whole, so escape sequences are assumed not to be
+/// split across writes.
+#[derive(Clone, Debug, Default)]
+pub struct StripAnsi<M> {
+    inner: M,
+}
+
+impl<M> StripAnsi<M> {
+    /// Strip escape sequences from everything written to `inner`.
+    pub fn new(inner: M) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for StripAnsi<M> {
+    type Writer = StripAnsiWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        StripAnsiWriter {
+            inner: self.inner.make_writer(),
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        StripAnsiWriter {
+            inner: self.inner.make_writer_for(meta),
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Writer returned by [`StripAnsi`].
+pub struct StripAnsiWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Write for StripAnsiWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if !data.contains(&ESC) {
+            self.inner.write_all(data)?;
+            return Ok(data.len());
+        }
+        self.buf.clear();
+        strip(data, &mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Append `data` to `out` without its CSI (`ESC [ ... final`), OSC
+/// (`ESC ] ... BEL` or `ESC ] ... ESC \`) and two-byte escape sequences.
+fn strip(data: &[u8], out: &mut Vec<u8>) {
+    let mut bytes = data.iter().copied();
+    while let Some(b) = bytes.next() {
+        if b != ESC {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'[') => {
+                bytes.find(|b| (0x40..=0x7e).contains(b));
+            }
+            Some(b']') => {
+                while let Some(b) = bytes.next() {
+                    if b == BEL || (b == ESC && bytes.next() == Some(b'\\')) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+
+use crate::sink::{Batch, FlushPolicy, OnWriteError};
+use crate::stats::Stats;
+
+/// An [`AsyncWrite`] and the runtime to drive it on, set by
+/// [`async_writer`](crate::SamplingLayerBuilder::async_writer).
+pub(crate) struct AsyncWriterConfig {
+    pub(crate) handle: tokio::runtime::Handle,
+    pub(crate) writer: Pin<Box<dyn AsyncWrite + Send>>,
+    pub(crate) queue_capacity: usize,
+}
+
+enum Message {
+    Write(Batch),
+    Flush,
+}
+
+/// A bounded queue feeding a task that writes to an [`AsyncWrite`].
+pub(crate) struct AsyncWorker {
+    sender: mpsc::Sender<Message>,
+    stats: Stats,
+    /// Bitset of budgets whose events are also captured by Sentry.
+    #[cfg(feature = "sentry")]
+    pub(crate) sentry: u64,
+}
+
+impl AsyncWorker {
+    pub(crate) fn spawn(
+        config: AsyncWriterConfig,
+        flush_policy: FlushPolicy,
+        on_error: Option<OnWriteError>,
+        stats: Stats,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        let task = WriterTask {
+            writer: config.writer,
+            flush_policy,
+            last_flush: Instant::now(),
+            on_error,
+            stats: stats.clone(),
+        };
+        config.handle.spawn(task.run(receiver));
+        Self {
+            sender,
+            stats,
+            #[cfg(feature = "sentry")]
+            sentry: 0,
+        }
+    }
+
+    /// Queue a batch, discarding it if the queue is full. Never blocks, as
+    /// the caller may be on a runtime thread the task needs.
+    pub(crate) fn send(&self, events: Batch) {
+        // Captured on the emitting thread, whose hub the events belong to.
+        #[cfg(feature = "sentry")]
+        crate::sentry::forward(&events, self.sentry);
+        let len = events.len() as u64;
+        if self.sender.try_send(Message::Write(events)).is_err() {
+            self.stats.lost.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+
+    /// Ask the task to flush once it has written everything queued so far.
+    pub(crate) fn flush(&self) {
+        let _ = self.sender.try_send(Message::Flush);
+    }
+}
+
+struct WriterTask {
+    writer: Pin<Box<dyn AsyncWrite + Send>>,
+    flush_policy: FlushPolicy,
+    last_flush: Instant,
+    on_error: Option<OnWriteError>,
+    stats: Stats,
+}
+
+impl WriterTask {
+    async fn run(mut self, mut receiver: mpsc::Receiver<Message>) {
+        let mut buf = Vec::new();
+        while let Some(message) = receiver.recv().await {
+            match message {
+                Message::Write(batch) => {
+                    let start = Instant::now();
+                    buf.clear();
+                    for event in &batch {
+                        buf.extend_from_slice(&event.bytes);
+                    }
+                    let mut result = self.write_all(&buf).await;
+                    if result.is_ok() && self.flush_due() {
+                        result = self.flush().await;
+                    }
+                    self.check(result);
+                    self.stats.record_write(start.elapsed(), batch.len());
+                }
+                Message::Flush => {
+                    let result = self.flush().await;
+                    self.check(result);
+                }
+            }
+        }
+        // The layer has been dropped.
+        let result = poll_fn(|cx| self.writer.as_mut().poll_shutdown(cx)).await;
+        self.check(result);
+    }
+
+    async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let n = poll_fn(|cx| self.writer.as_mut().poll_write(cx, buf)).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        poll_fn(|cx| self.writer.as_mut().poll_flush(cx)).await
+    }
+
+    fn flush_due(&self) -> bool {
+        match self.flush_policy {
+            FlushPolicy::Never => false,
+            FlushPolicy::PerEvent | FlushPolicy::PerBucket => true,
+            FlushPolicy::Every(interval) => self.last_flush.elapsed() >= interval,
+        }
+    }
+
+    fn check(&self, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.stats.write_errors.fetch_add(1, Ordering::Relaxed);
+            if let Some(on_error) = &self.on_error {
+                on_error(&e);
+            }
+        }
+    }
+}
@@ -0,0 +1,567 @@
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use tracing::{Level, Metadata, Subscriber};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::Emit;
+use crate::consistent::SampleKey;
+use crate::env;
+use crate::error::EnvError;
+use crate::filter::{
+    BudgetFilter, Except, FieldFilter, FieldValue, FnFilter, LevelRange, SpanFilter,
+};
+use crate::quota::QuotaProvider;
+use crate::reservoir::{Cost, UNIT, Weighting};
+use crate::summary::level_index;
+
+/// A sampling budget with its own options.
+///
+/// Passed to [`SamplingLayerBuilder::budget_with`](crate::SamplingLayerBuilder::budget_with).
+/// The shorthand builder methods such as
+/// [`budget`](crate::SamplingLayerBuilder::budget) cover the common cases;
+/// this is for budgets that need more than a filter and a limit.
+///
+/// ```
+/// use tracing_log_sample::{Budget, SamplingLayer};
+/// use tracing_subscriber::EnvFilter;
+/// use tracing_subscriber::filter::Targets;
+///
+/// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+///     .budget_with(Budget::new(EnvFilter::new("error")).limit(1000).no_cascade())
+///     .budget_with(
+///         Budget::new(Targets::new().with_target("my_crate", tracing::Level::INFO))
+///             .named("info")
+///             .limit(5000),
+///     );
+/// ```
+pub struct Budget<S> {
+    pub(crate) name: Option<String>,
+    pub(crate) filter: Box<dyn BudgetFilter<S>>,
+    pub(crate) limit_per_second: u64,
+    pub(crate) quota: Option<Box<dyn QuotaProvider>>,
+    pub(crate) cascade: bool,
+    pub(crate) writer: Option<BoxMakeWriter>,
+    pub(crate) emit: Option<Emit>,
+    pub(crate) weighting: Weighting,
+    pub(crate) cost: Cost,
+    pub(crate) max_bytes: Option<usize>,
+    pub(crate) fraction: Option<f64>,
+    pub(crate) sample_key: Option<SampleKey>,
+    pub(crate) head: usize,
+    pub(crate) passthrough: bool,
+    pub(crate) ramp_up: Option<Duration>,
+    pub(crate) hard_limit: Option<u64>,
+    pub(crate) burst: Option<(usize, Duration)>,
+    pub(crate) parent: Option<String>,
+    pub(crate) keyed: Option<(&'static str, usize)>,
+    pub(crate) per_root_span: Option<usize>,
+    #[cfg(feature = "sentry")]
+    pub(crate) sentry: bool,
+}
+
+impl<S: 'static> Budget<S> {
+    /// A budget matching events accepted by `filter`, such as an
+    /// [`EnvFilter`](tracing_subscriber::EnvFilter).
+    ///
+    /// The limit defaults to zero, so [`limit`](Self::limit) should always be
+    /// set.
+    pub fn new(filter: impl BudgetFilter<S>) -> Self {
+        Self {
+            name: None,
+            filter: Box::new(filter),
+            limit_per_second: 0,
+            quota: None,
+            cascade: true,
+            writer: None,
+            emit: None,
+            weighting: Weighting::Uniform,
+            cost: Cost::Events,
+            max_bytes: None,
+            fraction: None,
+            sample_key: None,
+            head: 0,
+            passthrough: false,
+            ramp_up: None,
+            hard_limit: None,
+            burst: None,
+            parent: None,
+            keyed: None,
+            per_root_span: None,
+            #[cfg(feature = "sentry")]
+            sentry: false,
+        }
+    }
+
+    /// A budget matching events for which `f` returns true. See
+    /// [`budget_fn`](crate::SamplingLayerBuilder::budget_fn).
+    pub fn from_fn<F>(f: F) -> Self
+    where
+        F: Fn(&Metadata<'_>, &Context<'_, S>) -> bool + Send + Sync + 'static,
+    {
+        Self::new(FnFilter(f))
+    }
+
+    /// A budget matching events at `level` or more severe. See
+    /// [`budget_level`](crate::SamplingLayerBuilder::budget_level).
+    pub fn level(level: Level) -> Self {
+        Self::new(LevelRange::new(Level::ERROR, level))
+    }
+
+    /// A budget matching events between two levels, inclusive. See
+    /// [`budget_level_range`](crate::SamplingLayerBuilder::budget_level_range).
+    pub fn level_range(levels: RangeInclusive<Level>) -> Self {
+        let (a, b) = levels.into_inner();
+        Self::new(LevelRange::new(a, b))
+    }
+
+    /// Exclude events accepted by `filter` from this budget, e.g. to carve a
+    /// noisy dependency out of a broad budget:
+    ///
+    /// ```
+    /// use tracing_log_sample::{Budget, SamplingLayer};
+    /// use tracing_subscriber::EnvFilter;
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder().budget_with(
+    ///     Budget::new(EnvFilter::new("info"))
+    ///         .exclude(EnvFilter::new("hyper=info"))
+    ///         .limit(1000),
+    /// );
+    /// ```
+    ///
+    /// May be called more than once to exclude several filters.
+    pub fn exclude(mut self, filter: impl BudgetFilter<S>) -> Self {
+        self.filter = Box::new(Except {
+            include: self.filter,
+            exclude: Box::new(filter),
+        });
+        self
+    }
+
+    /// Only accept events whose `field` satisfies `predicate`, e.g. server
+    /// errors logged from a shared request-logging callsite:
+    ///
+    /// ```
+    /// use tracing::Level;
+    /// use tracing_log_sample::{Budget, SamplingLayer};
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder().budget_with(
+    ///     Budget::level(Level::INFO)
+    ///         .field_matches("status_code", |v| v.as_u64().is_some_and(|code| code >= 500))
+    ///         .limit(100),
+    /// );
+    /// ```
+    ///
+    /// Events without the field are not accepted. Field values are only
+    /// available once an event is emitted, so this is checked in
+    /// [`Layer::event_enabled`](tracing_subscriber::Layer::event_enabled)
+    /// rather than when callsites are registered.
+    pub fn field_matches<F>(mut self, field: &'static str, predicate: F) -> Self
+    where
+        F: Fn(FieldValue<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Box::new(FieldFilter {
+            inner: self.filter,
+            field,
+            predicate: Box::new(predicate),
+        });
+        self
+    }
+
+    /// Set the per-second event limit.
+    pub fn limit(mut self, limit_per_second: u64) -> Self {
+        self.limit_per_second = limit_per_second;
+        self.quota = None;
+        if self.cost == Cost::Bytes {
+            self.cost = Cost::Events;
+        }
+        self
+    }
+
+    /// Keep each matching event with probability `fraction`, e.g. `0.01`
+    /// for 1%, however many arrive, rather than up to a fixed limit.
+    /// Replaces [`limit`](Self::limit).
+    ///
+    /// Kept events are still held until their bucket ends, so memory grows
+    /// with the event rate. The budget's reported limit and capacity are
+    /// zero.
+    pub fn fraction(mut self, fraction: f64) -> Self {
+        self.fraction = Some(fraction.clamp(0.0, 1.0));
+        self
+    }
+
+    /// Write the first `events` matching events of each bucket immediately
+    /// and unsampled, and only sample the rest, so quiet periods behave
+    /// exactly like an ordinary fmt layer. These are on top of the budget's
+    /// limit.
+    pub fn head(mut self, events: usize) -> Self {
+        self.head = events;
+        self
+    }
+
+    /// Write matching events immediately while the budget is under its
+    /// limit, and only hold them in the reservoir once a bucket exceeds it.
+    ///
+    /// A bucket that exceeds the limit writes its first `limit` events
+    /// directly and samples up to `limit` more of the rest; the following
+    /// buckets sample as usual until one stays within the limit again.
+    pub fn passthrough(mut self) -> Self {
+        self.passthrough = true;
+        self
+    }
+
+    /// Ramp the budget's capacity up linearly from zero to its limit over
+    /// the first `duration` after the layer is built, so the flood of logs
+    /// at startup doesn't overwhelm a sink whose connections are still
+    /// warming up.
+    ///
+    /// Has no effect on a [`fraction`](Self::fraction) budget.
+    pub fn ramp_up(mut self, duration: Duration) -> Self {
+        self.ramp_up = Some(duration);
+        self
+    }
+
+    /// Drop matching events without formatting them once a bucket has seen
+    /// more than `limit_per_second` of them, as a cheap circuit breaker for
+    /// log storms. Below it, events are sampled down to the budget's
+    /// [`limit`](Self::limit) as usual, so it should be well above that.
+    ///
+    /// Events over the hard limit still cascade to later matching budgets,
+    /// unless [`no_cascade`](Self::no_cascade) is set. They are counted as
+    /// dropped in [`Stats`](crate::Stats), but left out of the
+    /// [drop summary](crate::SamplingLayerBuilder::drop_summary) so they
+    /// never take the layer's lock. Since they are never formatted, they
+    /// aren't written to the
+    /// [archive writer](crate::SamplingLayerBuilder::archive_writer) either.
+    /// Events written with
+    /// [`Priority::Immediate`](crate::Priority::Immediate) aren't limited.
+    pub fn hard_limit(mut self, limit_per_second: u64) -> Self {
+        self.hard_limit = Some(limit_per_second);
+        self
+    }
+
+    /// Let up to `events` more than the limit through, once per `every`, so
+    /// a short spike is kept intact while a sustained flood is still
+    /// sampled.
+    ///
+    /// The credit is added to the capacity of each bucket until spent, and
+    /// refilled in full `every` after it was last refilled. For a
+    /// [`limit_bytes`](Self::limit_bytes) budget it is in bytes. Each
+    /// bucket's reservoir grows by the unspent credit, so a large burst
+    /// costs memory while it is available. Has no effect on a
+    /// [`fraction`](Self::fraction) budget.
+    pub fn burst(mut self, events: usize, every: Duration) -> Self {
+        self.burst = Some((events, every));
+        self
+    }
+
+    /// Make this budget a child of the budget [`named`](Self::named)
+    /// `parent`, so that the events kept by the parent and all its
+    /// descendants together stay within the parent's limit:
+    ///
+    /// ```
+    /// use tracing_log_sample::{Budget, SamplingLayer};
+    /// use tracing_subscriber::EnvFilter;
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_with(Budget::new(EnvFilter::new("db=info")).limit(500).parent("info"))
+    ///     .budget_with(Budget::new(EnvFilter::new("http=info")).limit(500).parent("info"))
+    ///     .budget_named("info", EnvFilter::new("info"), 600);
+    /// ```
+    ///
+    /// Here neither module can use more than 500 events per second, and the
+    /// two together with the rest of `info` no more than 600. As each
+    /// bucket ends, if a parent's subtree kept more than the parent's
+    /// capacity, events are dropped from it at random until it fits.
+    /// Events already written with [`Emit::Immediate`] count but aren't
+    /// dropped.
+    ///
+    /// Since events are offered to budgets in order, the parent must be
+    /// added after its children, or building fails with
+    /// [`BuildError::UnknownParent`](crate::BuildError::UnknownParent).
+    pub fn parent(mut self, parent: impl Into<String>) -> Self {
+        self.parent = Some(parent.into());
+        self
+    }
+
+    /// Sample each value of `field`, such as a tenant or user id, in its own
+    /// reservoir, so that one noisy key can't use up the whole budget and
+    /// every key is represented. See
+    /// [`budget_keyed`](crate::SamplingLayerBuilder::budget_keyed).
+    ///
+    /// As each bucket ends, keys take turns to fill the budget's limit, so
+    /// quiet keys keep everything and the busiest share what's left evenly.
+    /// Events without the field are sampled together, as one more key. At
+    /// most `max_keys` keys are tracked in a bucket; a new key past that
+    /// evicts the least recently seen, dropping the events it held.
+    ///
+    /// Has no effect on a [`fraction`](Self::fraction) budget, or with
+    /// [`unbuffered`](crate::SamplingLayerBuilder::unbuffered).
+    pub fn keyed(mut self, field: &'static str, max_keys: usize) -> Self {
+        self.keyed = Some((field, max_keys));
+        self
+    }
+
+    /// Offer the budget at most `events` from within each root span, such
+    /// as the span of one request, so that what is kept of a request is
+    /// enough to follow rather than a random few lines of many.
+    ///
+    /// The rest are dropped without being formatted, or cascade to later
+    /// matching budgets. This is on top of the budget's limit, which still
+    /// applies across all spans; events outside any span are only limited
+    /// by that. As with [`hard_limit`](Self::hard_limit), events over the
+    /// limit aren't written to the
+    /// [archive writer](crate::SamplingLayerBuilder::archive_writer). Events
+    /// written with [`Priority::Immediate`](crate::Priority::Immediate)
+    /// aren't counted.
+    pub fn per_root_span(mut self, events: usize) -> Self {
+        self.per_root_span = Some(events);
+        self
+    }
+
+    /// Keep or drop events by a hash of `key` rather than at random, so the
+    /// same logical event, such as every event of one trace, is consistently
+    /// kept or dropped across replicas and runs:
+    ///
+    /// ```
+    /// use tracing::Level;
+    /// use tracing_log_sample::{Budget, SampleKey, SamplingLayer};
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_with(
+    ///         Budget::level(Level::INFO)
+    ///             .fraction(0.01)
+    ///             .consistent(SampleKey::Field("trace_id")),
+    ///     );
+    /// ```
+    ///
+    /// Only applies to [`fraction`](Self::fraction) budgets.
+    pub fn consistent(mut self, key: SampleKey) -> Self {
+        self.sample_key = Some(key);
+        self
+    }
+
+    /// Limit the budget to `bytes_per_second` of formatted output rather
+    /// than a number of events, matching how log pipelines are usually
+    /// billed and throttled. Replaces [`limit`](Self::limit) and
+    /// [`level_weight`](Self::level_weight).
+    ///
+    /// The budget's reported limit and capacity are then in bytes. With
+    /// [`re_emit`](crate::SamplingLayerBuilder::re_emit), events aren't
+    /// formatted and each counts as one byte.
+    pub fn limit_bytes(mut self, bytes_per_second: u64) -> Self {
+        self.limit_per_second = bytes_per_second;
+        self.quota = None;
+        self.cost = Cost::Bytes;
+        self
+    }
+
+    /// Take the per-second limit from `limit`, called once at build time and
+    /// again as each bucket ends, so it can follow a feature flag, a config
+    /// service or a load-shedding signal without rebuilding the layer:
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicU64, Ordering};
+    ///
+    /// use tracing::Level;
+    /// use tracing_log_sample::{Budget, SamplingLayer};
+    ///
+    /// let limit = Arc::new(AtomicU64::new(1000));
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder().budget_with(
+    ///     Budget::level(Level::INFO).limit_fn({
+    ///         let limit = limit.clone();
+    ///         move || limit.load(Ordering::Relaxed)
+    ///     }),
+    /// );
+    /// ```
+    ///
+    /// Replaces [`limit`](Self::limit); after
+    /// [`limit_bytes`](Self::limit_bytes) it returns bytes per second. The
+    /// callback runs with the layer's state locked, so it should be cheap
+    /// and must not log. A limit of zero drops every event until it rises.
+    /// [`BudgetInfo`](crate::BudgetInfo) reports the limit at build time.
+    pub fn limit_fn<F>(self, limit: F) -> Self
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        self.quota(limit)
+    }
+
+    /// Take the per-second limit from a [`QuotaProvider`], which is told
+    /// how many events the budget matched as each bucket ends and asked for
+    /// the next bucket's limit. Share an `Arc` of one provider between
+    /// budgets or layers to have them follow the same quota.
+    ///
+    /// Replaces [`limit`](Self::limit), as [`limit_fn`](Self::limit_fn)
+    /// does.
+    pub fn quota(mut self, quota: impl QuotaProvider) -> Self {
+        self.quota = Some(Box::new(quota));
+        self
+    }
+
+    /// Give the budget a name. See
+    /// [`budget_named`](crate::SamplingLayerBuilder::budget_named).
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Write events sampled by this budget to `writer` instead of the layer's
+    /// writer, e.g. to send errors to stderr and everything else to a file.
+    pub fn writer<W>(mut self, writer: W) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.writer = Some(BoxMakeWriter::new(writer));
+        self
+    }
+
+    /// When events sampled by this budget are written, overriding
+    /// [`SamplingLayerBuilder::emit`](crate::SamplingLayerBuilder::emit).
+    pub fn emit(mut self, emit: Emit) -> Self {
+        self.emit = Some(emit);
+        self
+    }
+
+    /// Favour recent events within each bucket using forward decay: an event
+    /// emitted `half_life` after another has twice its weight. Useful with
+    /// long buckets, where the freshest context matters more than a strictly
+    /// uniform sample. Replaces [`weight_field`](Self::weight_field).
+    pub fn forward_decay(mut self, half_life: Duration) -> Self {
+        self.weighting = Weighting::decay(half_life);
+        self
+    }
+
+    /// Keep events in proportion to the numeric value of `field`, e.g.
+    /// `latency_ms` or `bytes`, so bigger events are more likely to be kept.
+    /// Replaces [`forward_decay`](Self::forward_decay).
+    ///
+    /// Events without the field, or with a non-numeric value, have a weight
+    /// of one. Events weighing zero or less are only kept while the
+    /// reservoir has room. Span events are given a weight of one.
+    pub fn weight_field(mut self, field: &'static str) -> Self {
+        self.weighting = Weighting::Field(field);
+        self
+    }
+
+    /// Count each event at `level` as `weight` events toward the budget's
+    /// limit, rather than one.
+    ///
+    /// With `DEBUG` at `0.1`, a flood of debug events takes a tenth of the
+    /// room it otherwise would, so a mixed-level budget keeps more of the
+    /// other levels without needing a budget per level. Weights are rounded
+    /// to the nearest thousandth, and are at least 0.001.
+    pub fn level_weight(mut self, level: Level, weight: f64) -> Self {
+        let mut units = match self.cost {
+            Cost::Levels(units) => units,
+            Cost::Events | Cost::Bytes => [UNIT; 5],
+        };
+        units[level_index(&level)] = ((weight * UNIT as f64).round() as u64).max(1);
+        self.cost = Cost::Levels(units);
+        self
+    }
+
+    /// Hold at most `bytes` of formatted events in the reservoir, on top of
+    /// the limit on events, so events carrying huge payloads can't balloon
+    /// memory. A new event ejects as many events as it needs room for, and
+    /// an event bigger than `bytes` is dropped.
+    ///
+    /// Only formatted output is counted, so this has no effect with
+    /// [`re_emit`](crate::SamplingLayerBuilder::re_emit).
+    pub fn max_bytes(mut self, bytes: usize) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Drop events ejected from this budget's reservoir instead of offering
+    /// them to later matching budgets.
+    ///
+    /// Use this to stop a noisy budget from using up the capacity of a
+    /// broader budget after it.
+    pub fn no_cascade(mut self) -> Self {
+        self.cascade = false;
+        self
+    }
+
+    /// Also capture events sampled by this budget as Sentry events, on the
+    /// current hub, so an error budget doubles as rate-limited error
+    /// reporting. The message is the formatted line, so consider disabling
+    /// [ANSI colours](crate::SamplingLayerBuilder::with_ansi).
+    #[cfg(feature = "sentry")]
+    pub fn forward_to_sentry(mut self) -> Self {
+        self.sentry = true;
+        self
+    }
+}
+
+impl<S> Budget<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    /// Only accept events inside a span named `span`, at any depth, e.g. to
+    /// give `batch_job` spans a budget of their own:
+    ///
+    /// ```
+    /// use tracing::Level;
+    /// use tracing_log_sample::{Budget, SamplingLayer};
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_with(Budget::level(Level::INFO).in_span("batch_job").limit(10))
+    ///     .budget_level(Level::INFO, 1000);
+    /// ```
+    pub fn in_span(mut self, span: &'static str) -> Self {
+        self.filter = Box::new(SpanFilter {
+            inner: self.filter,
+            span,
+        });
+        self
+    }
+}
+
+impl<S: Subscriber> Budget<S> {
+    /// A budget read from the environment variable `var`, written as
+    /// `directives@limit` in [`EnvFilter`] syntax, e.g.
+    /// `APP_ERROR_SAMPLING=error@1000`. The budget is named after `var`.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), tracing_log_sample::EnvError> {
+    /// use tracing_log_sample::{Budget, SamplingLayer};
+    ///
+    /// # unsafe { std::env::set_var("APP_ERROR_SAMPLING", "error@1000") };
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_with(Budget::from_env("APP_ERROR_SAMPLING")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_env(var: &str) -> Result<Self, EnvError> {
+        let spec = env::read(var)?;
+        let (directives, limit) = env::split_budget(&spec)?;
+        Ok(Self::new(EnvFilter::try_new(directives)?)
+            .named(var)
+            .limit(limit))
+    }
+
+    /// Like [`from_env`](Self::from_env), but re-read the limit from `var`
+    /// as buckets end, at most once per `every`, so setting the variable in
+    /// this process, e.g. with [`std::env::set_var`] from an admin endpoint,
+    /// changes the limit without a restart.
+    ///
+    /// The variable is read from this process's own environment, which
+    /// can't be changed from outside it; a config map exposed as
+    /// environment variables only takes effect on restart. To follow one
+    /// mounted as a file, use [`limit_fn`](Self::limit_fn) to read it.
+    ///
+    /// Only the limit is re-read; the filter is fixed once the layer is
+    /// built. While the variable is unset or invalid the last good limit is
+    /// kept.
+    pub fn from_env_every(var: &str, every: Duration) -> Result<Self, EnvError> {
+        let budget = Self::from_env(var)?;
+        let limit = env::reread_limit(var.to_owned(), every, budget.limit_per_second);
+        Ok(budget.limit_fn(limit))
+    }
+}
@@ -1,35 +1,141 @@
 use std::io;
 use std::marker::PhantomData;
-use std::sync::Mutex;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
 use std::time::{Duration, Instant};
 
-use tracing::Subscriber;
-use tracing_subscriber::filter::EnvFilter;
-use tracing_subscriber::fmt::format::{DefaultFields, Format, Full};
+use thread_local::ThreadLocal;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter, ParseError};
+use tracing_subscriber::fmt::format::{DefaultFields, FmtSpan, Format, Full, Pretty};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime, Uptime};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::fmt::{self, FormatFields, MakeWriter};
+use tracing_subscriber::layer::Context;
 use tracing_subscriber::registry::LookupSpan;
 
+use crate::adaptive::{self, Adaptive, Backpressure, Burst};
+#[cfg(feature = "tokio")]
+use crate::async_sink::{AsyncWorker, AsyncWriterConfig};
+use crate::budget::Budget;
 use crate::capture::CaptureMakeWriter;
-use crate::layer::{SamplingLayer, State, Stats};
-use crate::reservoir::Reservoir;
+#[cfg(feature = "serde")]
+use crate::config::{ConfigFields, ConfigFormat, SamplingConfig};
+use crate::env::{self, Spec};
+use crate::error::{BuildError, EnvError, MAX_BUDGETS, MAX_CAPACITY};
+use crate::filter::BudgetFilter;
+use crate::flusher::Flusher;
+use crate::format::{Gelf, Json, JsonFields, Logfmt};
+use crate::handle::{self, Control, Handle, SamplingGuard};
+use crate::keyed::KeyedReservoirs;
+use crate::layer::{
+    BudgetInfo, Emit, HardLimit, Priority, PriorityFn, SamplingLayer, Shared, State,
+};
+use crate::recent::RecentEvents;
+use crate::reemit::ReEmitter;
+use crate::reservoir::{Cost, Reservoir};
+use crate::sink::{Fallback, FlushPolicy, OnWriteError, Sink, Worker, Writers};
+use crate::stats::Stats;
+use crate::summary::{DropSummary, FormatSummary, SummaryConfig};
 
 /// Builder for [`SamplingLayer`](crate::SamplingLayer).
 ///
 /// Created via [`SamplingLayer::builder()`](crate::SamplingLayer::builder).
 pub struct SamplingLayerBuilder<S, N = DefaultFields, E = Format<Full>, W = fn() -> io::Stderr> {
-    budgets: Vec<(EnvFilter, u64)>,
-    bucket_duration: Duration,
+    config: Config<S>,
     writer: W,
     fmt_layer: fmt::Layer<S, N, E, CaptureMakeWriter>,
     _subscriber: PhantomData<fn(S)>,
 }
 
+/// What [`SamplingLayerBuilder::build`] returns.
+type Built<S, N, E, W> = (SamplingLayer<S, N, E, W>, Stats);
+
+/// Builder settings that don't depend on the formatter or writer types.
+struct Config<S> {
+    budgets: Vec<Budget<S>>,
+    bucket_duration: Duration,
+    jitter_buckets: bool,
+    flusher: Option<Flusher>,
+    io_queue: Option<usize>,
+    flush_on_panic: bool,
+    drop_summary: Option<Level>,
+    drop_summary_format: Option<FormatSummary>,
+    report_every: Option<Duration>,
+    recent: Option<RecentEvents>,
+    max_cascade_depth: usize,
+    max_release: usize,
+    max_release_time: Option<Duration>,
+    max_delay: Option<Duration>,
+    emit: Emit,
+    unbuffered: bool,
+    target_rate: Option<u64>,
+    global_limit: Option<(u64, Cost)>,
+    backpressure: Option<Duration>,
+    first_match_only: bool,
+    bypass_sampling: LevelFilter,
+    priority: Option<PriorityFn>,
+    global_filter: Option<Box<dyn BudgetFilter<S>>>,
+    re_emit: bool,
+    span_events: bool,
+    archive: Option<BoxMakeWriter>,
+    flush_policy: FlushPolicy,
+    on_write_error: Option<OnWriteError>,
+    fallback: Option<BoxMakeWriter>,
+    fallback_threshold: u32,
+    fallback_retry_after: Duration,
+    #[cfg(feature = "tokio")]
+    async_writer: Option<AsyncWriterConfig>,
+}
+
+/// Batches queued for re-emission when [`non_blocking`] wasn't set.
+///
+/// [`non_blocking`]: SamplingLayerBuilder::non_blocking
+const RE_EMIT_QUEUE: usize = 64;
+
+/// Keys tracked per bucket by [`budget_keyed`](SamplingLayerBuilder::budget_keyed).
+const DEFAULT_MAX_KEYS: usize = 1024;
+
 impl<S> SamplingLayer<S> {
     /// Create a new [`SamplingLayerBuilder`] with default settings.
     pub fn builder() -> SamplingLayerBuilder<S> {
         SamplingLayerBuilder {
-            budgets: Vec::new(),
-            bucket_duration: Duration::from_millis(50),
+            config: Config {
+                budgets: Vec::new(),
+                bucket_duration: Duration::from_millis(50),
+                jitter_buckets: false,
+                flusher: None,
+                io_queue: None,
+                flush_on_panic: false,
+                drop_summary: None,
+                drop_summary_format: None,
+                report_every: None,
+                recent: None,
+                max_cascade_depth: usize::MAX,
+                max_release: usize::MAX,
+                max_release_time: None,
+                max_delay: None,
+                emit: Emit::Smeared,
+                unbuffered: false,
+                target_rate: None,
+                global_limit: None,
+                backpressure: None,
+                first_match_only: false,
+                bypass_sampling: LevelFilter::OFF,
+                priority: None,
+                global_filter: None,
+                re_emit: false,
+                span_events: false,
+                archive: None,
+                flush_policy: FlushPolicy::PerBucket,
+                on_write_error: None,
+                fallback: None,
+                fallback_threshold: 3,
+                fallback_retry_after: Duration::from_secs(5),
+                #[cfg(feature = "tokio")]
+                async_writer: None,
+            },
             writer: io::stderr as fn() -> io::Stderr,
             fmt_layer: fmt::Layer::default().with_writer(CaptureMakeWriter::default()),
             _subscriber: PhantomData,
@@ -37,26 +143,613 @@ impl<S> SamplingLayer<S> {
     }
 }
 
-impl<S, N, E, W> SamplingLayerBuilder<S, N, E, W> {
-    /// Add a sampling budget with an [`EnvFilter`] and a per-second event limit.
+impl<S: Subscriber> SamplingLayerBuilder<S> {
+    /// A builder configured by the `TRACING_SAMPLE` environment variable,
+    /// much as `RUST_LOG` configures an [`EnvFilter`]. See
+    /// [`parse`](Self::parse) for the syntax.
+    pub fn from_env() -> Result<Self, EnvError> {
+        Self::parse(&env::read(env::DEFAULT_VAR)?)
+    }
+
+    /// A builder configured by `spec`: budgets written as
+    /// `directives@limit` and separated by commas, followed by options
+    /// after semicolons.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), tracing_log_sample::EnvError> {
+    /// use tracing_log_sample::SamplingLayerBuilder;
+    ///
+    /// let builder = SamplingLayerBuilder::<tracing_subscriber::Registry>::parse(
+    ///     "error@1000,info,hyper=warn@5000;bucket=50ms",
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// A budget's directives run until its `@limit`, so `info,hyper=warn@5000`
+    /// is a single budget. The only option is `bucket`, the bucket duration
+    /// with a unit: `ns`, `us`, `ms`, `s`, `m` or `h`.
+    pub fn parse(spec: &str) -> Result<Self, EnvError> {
+        let spec = Spec::parse(spec)?;
+        let mut builder = SamplingLayer::builder();
+        for (directives, limit) in &spec.budgets {
+            builder = builder.budget(EnvFilter::try_new(directives)?, *limit);
+        }
+        if let Some(duration) = spec.bucket_duration {
+            builder = builder.bucket_duration(duration);
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<S> SamplingLayerBuilder<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    /// A builder configured by a deserialized [`SamplingConfig`], e.g. read
+    /// from a TOML file, returning the parse error if a budget's filter
+    /// directives are invalid.
+    ///
+    /// Options the config doesn't cover can still be set on the returned
+    /// builder, apart from those specific to one event format.
+    pub fn from_config(
+        config: &SamplingConfig,
+    ) -> Result<SamplingLayerBuilder<S, ConfigFields, ConfigFormat>, ParseError> {
+        let mut builder = SamplingLayer::builder();
+        if let Some(duration) = config.bucket_duration {
+            builder = builder.bucket_duration(duration);
+        }
+        if let Some(ansi) = config.ansi {
+            builder = builder.with_ansi(ansi);
+        }
+        for budget in &config.budgets {
+            let mut built = Budget::new(EnvFilter::try_new(&budget.filter)?).limit(budget.limit);
+            if let Some(name) = &budget.name {
+                built = built.named(name);
+            }
+            if !budget.cascade {
+                built = built.no_cascade();
+            }
+            if let Some(schedule) = &budget.schedule {
+                built = built.quota(schedule.schedule(budget.limit));
+            }
+            builder = builder.budget_with(built);
+        }
+        let format = ConfigFormat::new(config);
+        Ok(builder.fmt_fields(format.fields()).event_format(format))
+    }
+}
+
+impl<S: Subscriber, N, E, W> SamplingLayerBuilder<S, N, E, W> {
+    /// Add a sampling budget with a filter, such as an [`EnvFilter`], and a
+    /// per-second event limit. See [`BudgetFilter`] for the filters
+    /// supported.
     ///
     /// Budgets whose limit rounds to zero events per bucket are skipped.
-    pub fn budget(mut self, filter: EnvFilter, limit_per_second: u64) -> Self {
-        self.budgets.push((filter, limit_per_second));
+    pub fn budget(self, filter: impl BudgetFilter<S>, limit_per_second: u64) -> Self {
+        self.budget_with(Budget::new(filter).limit(limit_per_second))
+    }
+
+    /// Add a sampling budget for events at `level` or more severe, e.g.
+    /// `Level::WARN` for warnings and errors.
+    ///
+    /// Equivalent to `budget(EnvFilter::new("warn"), ..)`, but matched with a
+    /// plain level comparison instead of evaluating `EnvFilter` directives.
+    pub fn budget_level(self, level: Level, limit_per_second: u64) -> Self {
+        self.budget_with(Budget::level(level).limit(limit_per_second))
+    }
+
+    /// Add a sampling budget for events between two levels, inclusive, e.g.
+    /// `Level::DEBUG..=Level::INFO`. The bounds may be given in either order.
+    pub fn budget_level_range(self, levels: RangeInclusive<Level>, limit_per_second: u64) -> Self {
+        self.budget_with(Budget::level_range(levels).limit(limit_per_second))
+    }
+
+    /// Add a sampling budget for events for which `f` returns true, for
+    /// matching that filter directives can't express:
+    ///
+    /// ```
+    /// use tracing_log_sample::SamplingLayer;
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_fn(|meta, _| meta.target().starts_with("payments"), 200);
+    /// ```
+    ///
+    /// `f` is called for every event, under no lock, so it should be cheap.
+    /// The budget's filter is reported as `<fn>`.
+    pub fn budget_fn<F>(self, f: F, limit_per_second: u64) -> Self
+    where
+        F: Fn(&Metadata<'_>, &Context<'_, S>) -> bool + Send + Sync + 'static,
+    {
+        self.budget_with(Budget::from_fn(f).limit(limit_per_second))
+    }
+
+    /// Like [`budget`](Self::budget), but parse the filter from `EnvFilter`
+    /// directives, returning the parse error if they are invalid.
+    ///
+    /// ```
+    /// # fn main() -> Result<(), tracing_subscriber::filter::ParseError> {
+    /// use tracing_log_sample::SamplingLayer;
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_str("error", 1000)?
+    ///     .budget_str("mycrate=debug", 500)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn budget_str(self, directives: &str, limit_per_second: u64) -> Result<Self, ParseError> {
+        Ok(self.budget(EnvFilter::try_new(directives)?, limit_per_second))
+    }
+
+    /// Add a sampling budget read from the environment variable `var`,
+    /// written as `directives@limit`, e.g. `APP_ERROR_SAMPLING=error@1000`.
+    /// See [`Budget::from_env`], and [`Budget::from_env_every`] to pick up
+    /// changes at runtime.
+    pub fn budget_from_env(self, var: &str) -> Result<Self, EnvError> {
+        Ok(self.budget_with(Budget::from_env(var)?))
+    }
+
+    /// Add a budget that samples each value of the field `key` separately,
+    /// so that a noisy tenant or user can't crowd out the others. Tracks at
+    /// most 1024 keys per bucket; see [`Budget::keyed`] to change that.
+    ///
+    /// ```
+    /// use tracing_log_sample::SamplingLayer;
+    /// use tracing_subscriber::EnvFilter;
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_keyed(EnvFilter::new("info"), "tenant_id", 1000);
+    /// ```
+    pub fn budget_keyed(
+        self,
+        filter: impl BudgetFilter<S>,
+        key: &'static str,
+        limit_per_second: u64,
+    ) -> Self {
+        self.budget_with(
+            Budget::new(filter)
+                .limit(limit_per_second)
+                .keyed(key, DEFAULT_MAX_KEYS),
+        )
+    }
+
+    /// Like [`budget`](Self::budget), but give the budget a name.
+    ///
+    /// Named budgets can be looked up with [`Stats::budget_named`] and
+    /// [`Handle::budget`], and are labelled by name rather than by index in
+    /// exported metrics and stats reports.
+    pub fn budget_named(
+        self,
+        name: impl Into<String>,
+        filter: impl BudgetFilter<S>,
+        limit_per_second: u64,
+    ) -> Self {
+        self.budget_with(Budget::new(filter).named(name).limit(limit_per_second))
+    }
+
+    /// Add a sampling budget limited to `bytes_per_second` of formatted
+    /// output rather than a number of events. See [`Budget::limit_bytes`].
+    ///
+    /// ```
+    /// use tracing_log_sample::SamplingLayer;
+    /// use tracing_subscriber::EnvFilter;
+    ///
+    /// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+    ///     .budget_bytes(EnvFilter::new("info"), 512 * 1024);
+    /// ```
+    pub fn budget_bytes(self, filter: impl BudgetFilter<S>, bytes_per_second: u64) -> Self {
+        self.budget_with(Budget::new(filter).limit_bytes(bytes_per_second))
+    }
+
+    /// Add a sampling budget keeping `fraction` of matching events, e.g.
+    /// `0.01` for 1%. See [`Budget::fraction`].
+    pub fn budget_fraction(self, filter: impl BudgetFilter<S>, fraction: f64) -> Self {
+        self.budget_with(Budget::new(filter).fraction(fraction))
+    }
+
+    /// Add a sampling budget configured with a [`Budget`].
+    pub fn budget_with(mut self, budget: Budget<S>) -> Self {
+        self.config.budgets.push(budget);
+        self
+    }
+
+    /// Reject events not accepted by `filter` before any budget is
+    /// consulted, e.g. `EnvFilter::new("info,hyper=off")` to silence a noisy
+    /// crate without excluding it from every budget.
+    ///
+    /// Rejected events aren't counted as received or dropped.
+    pub fn with_global_filter(mut self, filter: impl BudgetFilter<S>) -> Self {
+        self.config.global_filter = Some(Box::new(filter));
         self
     }
 
     /// Set the time bucket duration. Defaults to 50ms.
     pub fn bucket_duration(mut self, duration: Duration) -> Self {
-        self.bucket_duration = duration;
+        self.config.bucket_duration = duration;
+        self
+    }
+
+    /// Start the first bucket a random fraction of a bucket early, so
+    /// replicas started together don't rotate buckets, and write their
+    /// batches, in lockstep.
+    pub fn jitter_buckets(mut self) -> Self {
+        self.config.jitter_buckets = true;
+        self
+    }
+
+    /// Limit how many smeared events are written at once, e.g. after the
+    /// layer has fallen behind schedule because no events arrived to drive
+    /// it. Further events are released by later ticks.
+    ///
+    /// Events still held back when their bucket ends are carried into the
+    /// next bucket once, and written in full if still held at the end of
+    /// that one. Unlimited by default; a limit of zero is treated as one.
+    pub fn max_release_batch(mut self, events: usize) -> Self {
+        self.config.max_release = events.max(1);
+        self
+    }
+
+    /// Pace smeared releases by how fast the writer is, so each tick
+    /// releases only as many events as can be written in about `budget`,
+    /// judging by how long recent writes took per event. A slow sink then
+    /// gets smaller batches rather than blocking the emitting thread for
+    /// the whole backlog.
+    ///
+    /// Events held back are carried over as with
+    /// [`max_release_batch`](Self::max_release_batch), which also caps the
+    /// batch if both are set.
+    pub fn max_release_time(mut self, budget: Duration) -> Self {
+        self.config.max_release_time = Some(budget);
+        self
+    }
+
+    /// Guarantee that a sampled event is written within `delay` of being
+    /// emitted, at the cost of burstier output, e.g. when logs drive
+    /// alerting.
+    ///
+    /// An event waits up to a bucket in its reservoir and is then smeared
+    /// over the next one, so the smear is shortened to fit the remainder of
+    /// `delay`, ignoring [`max_release_batch`](Self::max_release_batch) and
+    /// [`max_release_time`](Self::max_release_time) once it is due. A delay
+    /// shorter than the [bucket duration](Self::bucket_duration) shortens
+    /// the buckets to match, and events are written as each bucket ends.
+    ///
+    /// Enables [`with_background_flush`](Self::with_background_flush) unless
+    /// another flusher is set, so quiet periods don't hold events back.
+    /// Time spent queued for a [`non_blocking`](Self::non_blocking) writer
+    /// isn't covered.
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.config.max_delay = Some(delay);
+        self
+    }
+
+    /// When sampled events are written. Defaults to [`Emit::Smeared`], which
+    /// spreads each bucket's sample evenly over the next bucket.
+    ///
+    /// Budgets can override this with [`Budget::emit`].
+    pub fn emit(mut self, emit: Emit) -> Self {
+        self.config.emit = emit;
+        self
+    }
+
+    /// Decide on each event as it is emitted, and write the events kept
+    /// straight through, for when no buffering delay can be tolerated.
+    ///
+    /// Each budget keeps an event with probability `limit / rate`, the rate
+    /// measured over the previous bucket, and keeps at most its limit per
+    /// bucket. Unlike a reservoir this isn't a uniform sample: a burst uses
+    /// up the limit early in a bucket. Fraction budgets keep their fraction,
+    /// while emit policies, weights and byte limits are ignored.
+    pub fn unbuffered(mut self) -> Self {
+        self.config.unbuffered = true;
+        self
+    }
+
+    /// Aim to keep about `events_per_second` in total, adapting budget
+    /// capacities to recent traffic so that limits don't need retuning as
+    /// traffic shifts.
+    ///
+    /// Budget limits then only set each budget's share. As each bucket ends,
+    /// every limit is scaled by a common factor chosen, going by the events
+    /// each budget matched recently, so the budgets keep the target between
+    /// them, with budgets that need less than their share leaving the rest
+    /// to the others. Fraction budgets are left alone, and byte budgets are
+    /// counted as if their limit were in events.
+    pub fn target_rate(mut self, events_per_second: u64) -> Self {
+        self.config.target_rate = Some(events_per_second);
+        self
+    }
+
+    /// Cap the events kept per second across all budgets, so that the sum
+    /// of many generous budgets can't exceed what the sink can absorb.
+    ///
+    /// As each bucket ends, if the budgets' reservoirs hold more than the
+    /// cap between them, events are dropped at random until they fit.
+    /// Events already written with [`Emit::Immediate`] count towards the cap
+    /// but can't be dropped, while [`head`](crate::Budget::head) events
+    /// aren't counted. Unlike [`target_rate`](Self::target_rate), this
+    /// never raises what a budget keeps.
+    pub fn global_limit(mut self, events_per_second: u64) -> Self {
+        self.config.global_limit = Some((events_per_second, Cost::Events));
+        self
+    }
+
+    /// Like [`global_limit`](Self::global_limit), but capping the bytes of
+    /// formatted output per second.
+    pub fn global_limit_bytes(mut self, bytes_per_second: u64) -> Self {
+        self.config.global_limit = Some((bytes_per_second, Cost::Bytes));
+        self
+    }
+
+    /// Scale every budget down while the sink struggles, and back up once
+    /// it recovers, so a slow or failing sink sheds log volume rather than
+    /// slowing the application down further.
+    ///
+    /// The sink counts as struggling in a bucket where writing an event
+    /// took longer than `max_write_cost` on average, a write failed, or
+    /// events were lost to a full [`non_blocking`](Self::non_blocking)
+    /// queue. Capacities are halved for each such bucket, down to 1/64, and
+    /// regain 1/16 of their full size for each bucket that follows without
+    /// trouble.
+    pub fn backpressure(mut self, max_write_cost: Duration) -> Self {
+        self.config.backpressure = Some(max_write_cost);
+        self
+    }
+
+    /// Limit how many reservoirs a single event may be offered to. An event
+    /// still held after `depth` matching reservoirs is dropped rather than
+    /// cascaded further.
+    ///
+    /// With many overlapping budgets this bounds the work done, and the time
+    /// the layer's lock is held, per event. Unlimited by default; a depth of
+    /// zero is treated as one.
+    pub fn max_cascade_depth(mut self, depth: usize) -> Self {
+        self.config.max_cascade_depth = depth.max(1);
+        self
+    }
+
+    /// Offer each event only to the first budget whose filter matches, rather
+    /// than to every matching budget.
+    ///
+    /// With overlapping filters such as `error` followed by `info`, errors
+    /// are then only counted against the first budget and never cascade into
+    /// the second, so each budget's limit applies to a disjoint set of events.
+    pub fn first_match_only(mut self) -> Self {
+        self.config.first_match_only = true;
+        self
+    }
+
+    /// Write events at or above `level` as soon as they are emitted, without
+    /// sampling or smearing them, e.g. `LevelFilter::ERROR` so errors are
+    /// never delayed or dropped. They must still match a budget, and are
+    /// routed to that budget's writer, but don't count against its limit.
+    pub fn bypass_sampling(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.config.bypass_sampling = level.into();
+        self
+    }
+
+    /// Decide per event whether it is sampled or written immediately, so
+    /// application-specific signals such as a `fatal = true` field can
+    /// bypass sampling. Checked after
+    /// [`bypass_sampling`](Self::bypass_sampling), for events that match a
+    /// budget.
+    pub fn priority<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&Metadata<'_>, &Event<'_>) -> Priority + Send + Sync + 'static,
+    {
+        self.config.priority = Some(Box::new(f));
+        self
+    }
+
+    /// Rotate buckets and release smeared events from a background thread.
+    ///
+    /// Without this, buckets only advance when new events arrive, so the last
+    /// bucket's samples are held until the next event or until the layer is
+    /// dropped.
+    pub fn with_background_flush(mut self) -> Self {
+        self.config.flusher = Some(Flusher::Thread);
+        self
+    }
+
+    /// Rotate buckets and release smeared events from a task spawned on the
+    /// given tokio runtime, instead of a dedicated OS thread.
+    ///
+    /// Writes happen on the runtime's worker threads, so a slow writer will
+    /// block that worker while a batch is written.
+    #[cfg(feature = "tokio")]
+    pub fn with_tokio_flusher(mut self, handle: tokio::runtime::Handle) -> Self {
+        self.config.flusher = Some(Flusher::Tokio(handle));
+        self
+    }
+
+    /// Write sampled events to `writer` from a task spawned on the given tokio
+    /// runtime, fed by a bounded queue holding up to `queue_capacity`
+    /// batches. For services whose only sensible sink is an async stream,
+    /// such as a network connection.
+    ///
+    /// Emitting threads never block on the writer. If the queue is full, the
+    /// batch is discarded and counted in [`Stats::lost`].
+    /// [`Handle::flush`](crate::Handle::flush) queues a flush rather than
+    /// waiting for it.
+    ///
+    /// This replaces the layer's [`writer`](Self::writer), along with the
+    /// routing by metadata it would do with
+    /// [`make_writer_for`](tracing_subscriber::fmt::MakeWriter::make_writer_for),
+    /// per-budget [`writer`](crate::Budget::writer)s, the
+    /// [fallback writer](Self::fallback_writer) and
+    /// [`non_blocking`](Self::non_blocking), whose thread isn't started.
+    /// Events of budgets set to
+    /// [`forward_to_sentry`](crate::Budget::forward_to_sentry) are still
+    /// captured, on the emitting thread as they are queued. It is ignored if
+    /// [`re_emit`](Self::re_emit) is set.
+    #[cfg(feature = "tokio")]
+    pub fn async_writer<A>(
+        mut self,
+        handle: tokio::runtime::Handle,
+        writer: A,
+        queue_capacity: usize,
+    ) -> Self
+    where
+        A: tokio::io::AsyncWrite + Send + 'static,
+    {
+        self.config.async_writer = Some(AsyncWriterConfig {
+            handle,
+            writer: Box::pin(writer),
+            queue_capacity,
+        });
+        self
+    }
+
+    /// Write sampled events from a dedicated I/O thread fed by a bounded queue
+    /// holding up to `queue_capacity` batches.
+    ///
+    /// Emitting threads never block on the writer. If the queue is full, the
+    /// batch is discarded and counted in [`Stats::lost`]. A `queue_capacity`
+    /// of zero is treated as one, since a queue that can't hold a batch
+    /// would lose every batch sent while the thread is busy.
+    pub fn non_blocking(mut self, queue_capacity: usize) -> Self {
+        self.config.io_queue = Some(queue_capacity.max(1));
+        self
+    }
+
+    /// Re-dispatch sampled events to the subscriber instead of formatting
+    /// and writing them, so layers that need the [`Event`](tracing::Event)
+    /// itself, such as exporters, can consume the sampled stream. Wrap those
+    /// layers in [`ReEmitted`](crate::ReEmitted) so they don't also see the
+    /// original events.
+    ///
+    /// Events are re-dispatched from the I/O thread (see
+    /// [`non_blocking`](Self::non_blocking)), which is always used in this
+    /// mode. Re-emitted events have no parent span, and field values other
+    /// than primitives and strings are passed on in their `Debug` form.
+    /// Per-budget writers are ignored; drop summaries and stats reports are
+    /// still written to the layer's writer.
+    pub fn re_emit(mut self) -> Self {
+        self.config.re_emit = true;
+        self
+    }
+
+    /// Install a panic hook that writes all buffered events before the
+    /// previously installed hook runs.
+    ///
+    /// Without this, a panic that aborts the process loses whatever is still
+    /// held in the reservoirs and the smear queue.
+    pub fn flush_on_panic(mut self) -> Self {
+        self.config.flush_on_panic = true;
+        self
+    }
+
+    /// Write a summary line at each bucket rotation in which events were
+    /// dropped, e.g. `WARN tracing_log_sample: 342 events dropped (error=12, info=330)`.
+    pub fn drop_summary(mut self, level: Level) -> Self {
+        self.config.drop_summary = Some(level);
+        self
+    }
+
+    /// Render drop summary lines with a custom function instead of the
+    /// default format. Enables summaries at `WARN` unless
+    /// [`drop_summary`](Self::drop_summary) sets another level.
+    ///
+    /// The returned string is written as-is, so it should end in a newline.
+    pub fn drop_summary_format<F>(mut self, format: F) -> Self
+    where
+        F: Fn(&DropSummary) -> String + Send + Sync + 'static,
+    {
+        self.config.drop_summary_format = Some(Box::new(format));
+        self
+    }
+
+    /// Periodically write an `INFO` line with the cumulative [`Stats`]
+    /// counters, e.g.
+    /// `INFO tracing_log_sample: sampling stats received=10 sampled=8 dropped=2 lost=0`.
+    ///
+    /// Reports are written at bucket rotation, so the cadence is rounded up to
+    /// a whole number of buckets.
+    pub fn report_stats_every(mut self, interval: Duration) -> Self {
+        self.config.report_every = Some(interval);
+        self
+    }
+
+    /// Keep a copy of each written event in `recent`, so the most recent
+    /// sampled output can be inspected at runtime.
+    pub fn recent_events(mut self, recent: RecentEvents) -> Self {
+        self.config.recent = Some(recent);
+        self
+    }
+
+    /// When to flush the writers, so buffered writers such as a
+    /// [`BufWriter`](std::io::BufWriter) persist sampled events promptly.
+    /// Defaults to [`FlushPolicy::PerBucket`].
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.config.flush_policy = policy;
+        self
+    }
+
+    /// Call `f` with each error returned by a writer, e.g. a broken pipe or
+    /// a full disk. Errors are also counted in [`Stats::write_errors`].
+    ///
+    /// `f` runs on whichever thread is writing, which may be one emitting an
+    /// event.
+    pub fn on_write_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        self.config.on_write_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Write to `writer`, e.g. stderr, when the layer's writers fail, so
+    /// sampled events survive an outage of the primary sink.
+    ///
+    /// Failed writes are retried on the fallback. Once the primary has
+    /// failed several times in a row it is only retried periodically, and
+    /// used again as soon as a retry succeeds. See
+    /// [`fallback_policy`](Self::fallback_policy).
+    pub fn fallback_writer<W2>(mut self, writer: W2) -> Self
+    where
+        W2: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.config.fallback = Some(BoxMakeWriter::new(writer));
+        self
+    }
+
+    /// Switch to the [fallback writer](Self::fallback_writer) after
+    /// `failures` consecutive failed writes, and retry the primary every
+    /// `retry_after` while switched. Defaults to 3 failures and 5 seconds.
+    pub fn fallback_policy(mut self, failures: u32, retry_after: Duration) -> Self {
+        self.config.fallback_threshold = failures;
+        self.config.fallback_retry_after = retry_after;
+        self
+    }
+
+    /// Also write every event matched by a budget, before sampling, to
+    /// `writer`, so a complete archive is kept alongside the sampled output.
+    ///
+    /// Archived events are written on the thread that emits them, in the
+    /// layer's format. Events a budget drops before formatting, over its
+    /// [`hard_limit`](crate::Budget::hard_limit) or
+    /// [`per_root_span`](crate::Budget::per_root_span) limit, aren't
+    /// archived.
+    pub fn archive_writer<W2>(mut self, writer: W2) -> Self
+    where
+        W2: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.config.archive = Some(BoxMakeWriter::new(writer));
         self
     }
 
     /// Set the output writer. Defaults to stderr.
+    ///
+    /// Each event is written with [`MakeWriter::make_writer_for`] its
+    /// metadata, so writers such as
+    /// [`with_max_level`](tracing_subscriber::fmt::writer::MakeWriterExt::with_max_level)
+    /// can route by level or target. Consecutive events with the same level
+    /// and target share one write, so routing on any other metadata is not
+    /// supported.
     pub fn writer<W2>(self, writer: W2) -> SamplingLayerBuilder<S, N, E, W2> {
         SamplingLayerBuilder {
-            budgets: self.budgets,
-            bucket_duration: self.bucket_duration,
+            config: self.config,
             writer,
             fmt_layer: self.fmt_layer,
             _subscriber: PhantomData,
@@ -76,8 +769,7 @@ where
         E2: fmt::FormatEvent<S, N> + 'static,
     {
         SamplingLayerBuilder {
-            budgets: self.budgets,
-            bucket_duration: self.bucket_duration,
+            config: self.config,
             writer: self.writer,
             fmt_layer: self.fmt_layer.event_format(e),
             _subscriber: PhantomData,
@@ -90,8 +782,7 @@ where
         E2: fmt::FormatEvent<S, N> + 'static,
     {
         SamplingLayerBuilder {
-            budgets: self.budgets,
-            bucket_duration: self.bucket_duration,
+            config: self.config,
             writer: self.writer,
             fmt_layer: self.fmt_layer.map_event_format(f),
             _subscriber: PhantomData,
@@ -104,8 +795,7 @@ where
         N2: for<'writer> FormatFields<'writer> + 'static,
     {
         SamplingLayerBuilder {
-            budgets: self.budgets,
-            bucket_duration: self.bucket_duration,
+            config: self.config,
             writer: self.writer,
             fmt_layer: self.fmt_layer.fmt_fields(fmt_fields),
             _subscriber: PhantomData,
@@ -120,14 +810,51 @@ where
     /// Do not emit timestamps.
     pub fn without_time(self) -> SamplingLayerBuilder<S, N, Format<L, ()>, W> {
         SamplingLayerBuilder {
-            budgets: self.budgets,
-            bucket_duration: self.bucket_duration,
+            config: self.config,
             writer: self.writer,
             fmt_layer: self.fmt_layer.without_time(),
             _subscriber: PhantomData,
         }
     }
 
+    /// Use a custom timer for event timestamps, e.g. one from
+    /// [`tracing_subscriber::fmt::time`](mod@tracing_subscriber::fmt::time)
+    /// so sampled output matches the rest of the application's logs.
+    pub fn with_timer<T2>(self, timer: T2) -> SamplingLayerBuilder<S, N, Format<L, T2>, W> {
+        SamplingLayerBuilder {
+            config: self.config,
+            writer: self.writer,
+            fmt_layer: self.fmt_layer.with_timer(timer),
+            _subscriber: PhantomData,
+        }
+    }
+
+    /// Timestamp events with the wall-clock time in UTC, formatted as RFC
+    /// 3339 (e.g. `2024-01-01T00:00:00.000000Z`). This is the default.
+    pub fn with_utc_timestamps(self) -> SamplingLayerBuilder<S, N, Format<L, SystemTime>, W> {
+        self.with_timer(SystemTime)
+    }
+
+    /// Timestamp events with the time elapsed since the layer was built.
+    pub fn with_uptime(self) -> SamplingLayerBuilder<S, N, Format<L, Uptime>, W> {
+        self.with_timer(Uptime::default())
+    }
+
+    /// Also emit events when spans are created, entered, exited or closed,
+    /// like [`fmt::Layer::with_span_events`].
+    ///
+    /// Span events are sampled like any other event. They only carry the
+    /// span's metadata, so budgets are matched on the span's target and
+    /// level alone. Span events are not re-emitted in
+    /// [`re_emit`](Self::re_emit) mode.
+    pub fn with_span_events(mut self, kind: FmtSpan) -> Self {
+        self.config.span_events = kind != FmtSpan::NONE;
+        SamplingLayerBuilder {
+            fmt_layer: self.fmt_layer.with_span_events(kind),
+            ..self
+        }
+    }
+
     /// Sets whether or not an event's target is displayed.
     pub fn with_target(self, display_target: bool) -> Self {
         SamplingLayerBuilder {
@@ -136,6 +863,53 @@ where
         }
     }
 
+    /// Sets whether or not an event's source file is displayed.
+    pub fn with_file(self, display_filename: bool) -> Self {
+        SamplingLayerBuilder {
+            fmt_layer: self.fmt_layer.with_file(display_filename),
+            ..self
+        }
+    }
+
+    /// Sets whether or not an event's source line number is displayed.
+    pub fn with_line_number(self, display_line_number: bool) -> Self {
+        SamplingLayerBuilder {
+            fmt_layer: self.fmt_layer.with_line_number(display_line_number),
+            ..self
+        }
+    }
+
+    /// Sets whether or not the name of the thread that emitted an event is
+    /// displayed. Sampled events from many threads are interleaved and
+    /// written late, so this is often the only way to attribute them.
+    pub fn with_thread_names(self, display_thread_names: bool) -> Self {
+        SamplingLayerBuilder {
+            fmt_layer: self.fmt_layer.with_thread_names(display_thread_names),
+            ..self
+        }
+    }
+
+    /// Sets whether or not the id of the thread that emitted an event is
+    /// displayed.
+    pub fn with_thread_ids(self, display_thread_ids: bool) -> Self {
+        SamplingLayerBuilder {
+            fmt_layer: self.fmt_layer.with_thread_ids(display_thread_ids),
+            ..self
+        }
+    }
+
+    /// Sets whether or not ANSI colour codes are written. Disable this when
+    /// the writer is a file or a log shipper rather than a terminal.
+    ///
+    /// Defaults to enabled unless the `NO_COLOR` environment variable is
+    /// set.
+    pub fn with_ansi(self, ansi: bool) -> Self {
+        SamplingLayerBuilder {
+            fmt_layer: self.fmt_layer.with_ansi(ansi),
+            ..self
+        }
+    }
+
     /// Sets whether or not an event's level is displayed.
     pub fn with_level(self, display_level: bool) -> Self {
         SamplingLayerBuilder {
@@ -152,59 +926,496 @@ where
         N: for<'writer> FormatFields<'writer> + 'static,
     {
         SamplingLayerBuilder {
-            budgets: self.budgets,
-            bucket_duration: self.bucket_duration,
+            config: self.config,
             writer: self.writer,
             fmt_layer: self.fmt_layer.compact(),
             _subscriber: PhantomData,
         }
     }
+
+    /// Use the [`Json`] formatter, writing each event as a single-line JSON
+    /// object. The timestamp is reset to the default, RFC 3339 in UTC.
+    pub fn json(self) -> SamplingLayerBuilder<S, JsonFields, Json, W>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        SamplingLayerBuilder {
+            config: self.config,
+            writer: self.writer,
+            fmt_layer: self
+                .fmt_layer
+                .fmt_fields(JsonFields::default())
+                .event_format(Json::default()),
+            _subscriber: PhantomData,
+        }
+    }
+
+    /// Use the [`Logfmt`] formatter, writing each event as `key=value`
+    /// pairs. The timestamp is reset to the default, RFC 3339 in UTC.
+    pub fn logfmt(self) -> SamplingLayerBuilder<S, N, Logfmt, W>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        SamplingLayerBuilder {
+            config: self.config,
+            writer: self.writer,
+            fmt_layer: self.fmt_layer.event_format(Logfmt::default()),
+            _subscriber: PhantomData,
+        }
+    }
+
+    /// Use the [`Gelf`] formatter, reporting events as coming from `host`,
+    /// so sampled events can be shipped straight to Graylog.
+    pub fn gelf(self, host: impl Into<String>) -> SamplingLayerBuilder<S, N, Gelf, W>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        SamplingLayerBuilder {
+            config: self.config,
+            writer: self.writer,
+            fmt_layer: self.fmt_layer.event_format(Gelf::new(host)),
+            _subscriber: PhantomData,
+        }
+    }
+
+    /// Format events as RFC 5424 syslog messages from `facility` and send
+    /// them with `writer`. See [`Syslog`](crate::Syslog) to set the hostname
+    /// or app name.
+    #[cfg(feature = "syslog")]
+    pub fn syslog(
+        self,
+        facility: crate::Facility,
+        writer: crate::SyslogWriter,
+    ) -> SamplingLayerBuilder<S, N, crate::Syslog, crate::SyslogWriter>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        SamplingLayerBuilder {
+            config: self.config,
+            writer,
+            fmt_layer: self
+                .fmt_layer
+                .with_ansi(false)
+                .event_format(crate::Syslog::new(facility)),
+            _subscriber: PhantomData,
+        }
+    }
+
+    /// Write events to journald over its native protocol, keeping the level
+    /// as `PRIORITY` and fields as journal fields. See
+    /// [`Journald`](crate::Journald) for the fields written.
+    #[cfg(all(unix, feature = "journald"))]
+    pub fn journald(
+        self,
+        writer: crate::JournaldWriter,
+    ) -> SamplingLayerBuilder<S, N, crate::Journald, crate::JournaldWriter>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        SamplingLayerBuilder {
+            config: self.config,
+            writer,
+            fmt_layer: self
+                .fmt_layer
+                .with_ansi(false)
+                .event_format(crate::Journald::default()),
+            _subscriber: PhantomData,
+        }
+    }
+
+    /// Use the pretty formatter: multi-line output including the source
+    /// location of each event. Best suited to local development.
+    pub fn pretty(self) -> SamplingLayerBuilder<S, Pretty, Format<Pretty, T>, W> {
+        SamplingLayerBuilder {
+            config: self.config,
+            writer: self.writer,
+            fmt_layer: self.fmt_layer.pretty(),
+            _subscriber: PhantomData,
+        }
+    }
+}
+
+impl<S, T, W> SamplingLayerBuilder<S, JsonFields, Json<T>, W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    T: FormatTime + 'static,
+{
+    /// Write event fields at the top level of the JSON object instead of
+    /// nested under `"fields"`. See [`Json::flatten_event`].
+    pub fn flatten_event(self, flatten_event: bool) -> Self {
+        self.map_event_format(|json| json.flatten_event(flatten_event))
+    }
+
+    /// Sets whether or not the innermost span is included as `"span"`.
+    pub fn with_current_span(self, display_current_span: bool) -> Self {
+        self.map_event_format(|json| json.with_current_span(display_current_span))
+    }
+
+    /// Sets whether or not every span in scope is included as `"spans"`.
+    pub fn with_span_list(self, display_span_list: bool) -> Self {
+        self.map_event_format(|json| json.with_span_list(display_span_list))
+    }
+
+    /// Do not emit timestamps.
+    pub fn without_time(self) -> SamplingLayerBuilder<S, JsonFields, Json<()>, W> {
+        self.map_event_format(Json::without_time)
+    }
+}
+
+impl<S, N, T, W> SamplingLayerBuilder<S, N, Logfmt<T>, W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+    T: FormatTime + 'static,
+{
+    /// Do not emit timestamps.
+    pub fn without_time(self) -> SamplingLayerBuilder<S, N, Logfmt<()>, W> {
+        self.map_event_format(Logfmt::without_time)
+    }
 }
 
 impl<S, N, E, W> SamplingLayerBuilder<S, N, E, W>
 where
-    W: for<'a> MakeWriter<'a> + 'static,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
     S: Subscriber + for<'a> LookupSpan<'a>,
     N: for<'writer> FormatFields<'writer> + 'static,
     E: fmt::FormatEvent<S, N> + 'static,
 {
     /// Consume the builder and create a [`SamplingLayer`](crate::SamplingLayer)
     /// and a [`Stats`] handle for reading event counters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bucket duration is zero or more than 64 budgets were
+    /// added. Use [`try_build`](Self::try_build) to handle these, and other
+    /// likely mistakes, as errors.
     pub fn build(self) -> (SamplingLayer<S, N, E, W>, Stats) {
-        assert!(
-            !self.bucket_duration.is_zero(),
-            "bucket_duration must be > 0"
-        );
+        self.build_inner(false)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
 
-        let bucket_secs = self.bucket_duration.as_secs_f64();
+    /// Like [`build`](Self::build), but return an error instead of panicking
+    /// on an invalid configuration.
+    ///
+    /// This is stricter than `build`: it also rejects a builder with no
+    /// budgets, budgets whose limit rounds to zero events per bucket (which
+    /// `build` skips), and budgets needing more than 2^20 events per bucket.
+    pub fn try_build(self) -> Result<Built<S, N, E, W>, BuildError> {
+        self.build_inner(true)
+    }
+
+    fn build_inner(mut self, strict: bool) -> Result<Built<S, N, E, W>, BuildError> {
+        if let Some(max_delay) = self.config.max_delay {
+            self.config.bucket_duration = self.config.bucket_duration.min(max_delay);
+            self.config.flusher.get_or_insert(Flusher::Thread);
+        }
+        if self.config.bucket_duration.is_zero() {
+            return Err(BuildError::ZeroBucketDuration);
+        }
+        if strict && self.config.budgets.is_empty() {
+            return Err(BuildError::NoBudgets);
+        }
+
+        let now = Instant::now();
+        let bucket_secs = self.config.bucket_duration.as_secs_f64();
         let mut filters = Vec::new();
+        let mut budgets = Vec::new();
         let mut reservoirs = Vec::new();
-        for (filter, limit_per_second) in self.budgets {
-            let limit_per_bucket = (limit_per_second as f64 * bucket_secs).ceil() as usize;
-            if limit_per_bucket == 0 {
+        let mut names = Vec::new();
+        let mut budget_writers = Vec::new();
+        let mut emits = Vec::new();
+        let mut weightings = Vec::new();
+        let mut costs = Vec::new();
+        let mut sample_keys = Vec::new();
+        let mut heads = Vec::new();
+        let mut passthroughs = Vec::new();
+        let mut quotas = Vec::new();
+        let mut ramp_ups = Vec::new();
+        let mut hard_limits = Vec::new();
+        let mut bursts = Vec::new();
+        let mut parents = Vec::new();
+        let mut keyed_reservoirs = Vec::new();
+        let mut per_root_spans = Vec::new();
+        #[cfg(feature = "sentry")]
+        let mut sentry_budgets = 0;
+        for (index, budget) in self.config.budgets.into_iter().enumerate() {
+            #[cfg(feature = "sentry")]
+            let forward_to_sentry = budget.sentry;
+            let Budget {
+                name,
+                filter,
+                limit_per_second,
+                quota,
+                cascade,
+                writer,
+                emit,
+                weighting,
+                cost,
+                max_bytes,
+                fraction,
+                sample_key,
+                head,
+                passthrough,
+                ramp_up,
+                hard_limit,
+                burst,
+                parent,
+                keyed,
+                per_root_span,
+                ..
+            } = budget;
+            let limit_per_second = quota
+                .as_ref()
+                .map_or(limit_per_second, |quota| quota.limit());
+            let (limit_per_second, limit_per_bucket) = match fraction {
+                Some(_) => (0, 0),
+                None => (
+                    limit_per_second,
+                    (limit_per_second as f64 * bucket_secs).ceil() as usize,
+                ),
+            };
+            // A dynamic limit may only be zero for now.
+            if limit_per_bucket == 0 && fraction.is_none() && quota.is_none() {
+                if strict {
+                    return Err(BuildError::ZeroCapacity { budget: index });
+                }
                 continue;
             }
+            // Byte budgets don't allocate a slot per unit.
+            if strict && limit_per_bucket > MAX_CAPACITY && cost != Cost::Bytes {
+                return Err(BuildError::CapacityTooLarge {
+                    budget: index,
+                    capacity: limit_per_bucket,
+                });
+            }
+            // The first bucket ends one bucket duration after the build.
+            let capacity = (limit_per_bucket as f64
+                * adaptive::ramp(ramp_up, self.config.bucket_duration))
+            .ceil() as usize;
+            let capacity = capacity + burst.map_or(0, |(events, _)| events);
+            let reservoir = match fraction {
+                _ if self.config.unbuffered => Reservoir::unbuffered(capacity, fraction),
+                Some(fraction) => Reservoir::fraction(fraction),
+                None => Reservoir::for_budget(capacity, weighting, cost, max_bytes),
+            };
+            names.push(name.clone());
+            parents.push(parent.map(|parent| (index, parent)));
+            per_root_spans.push(per_root_span);
+            budgets.push(BudgetInfo {
+                name,
+                filter: filter.to_string(),
+                limit_per_second,
+                capacity: reservoir.capacity(),
+                cascade,
+            });
             filters.push(filter);
-            reservoirs.push(Reservoir::new(limit_per_bucket));
+            #[cfg(feature = "sentry")]
+            if forward_to_sentry {
+                sentry_budgets |= 1u64.checked_shl(budget_writers.len() as u32).unwrap_or(0);
+            }
+            budget_writers.push(writer);
+            emits.push(emit.unwrap_or(self.config.emit));
+            weightings.push(weighting);
+            costs.push(cost);
+            sample_keys.push(sample_key);
+            heads.push(head);
+            passthroughs.push(passthrough);
+            quotas.push(quota);
+            ramp_ups.push(ramp_up);
+            hard_limits.push(hard_limit.map(|per_second| {
+                let hard = HardLimit {
+                    per_second,
+                    capacity: AtomicUsize::new(0),
+                    seen: AtomicUsize::new(0),
+                };
+                hard.reset(self.config.bucket_duration);
+                hard
+            }));
+            bursts.push(burst.map(|(events, every)| Burst::new(events, every, now)));
+            keyed_reservoirs.push(
+                keyed
+                    .filter(|_| fraction.is_none() && !self.config.unbuffered)
+                    .map(|(field, max_keys)| KeyedReservoirs::new(field, max_keys, max_bytes)),
+            );
+            reservoirs.push(reservoir);
         }
+        if filters.len() > MAX_BUDGETS {
+            return Err(BuildError::TooManyBudgets {
+                count: filters.len(),
+            });
+        }
+        let parents = (parents.into_iter().enumerate())
+            .map(|(i, parent)| {
+                let Some((budget, parent)) = parent else {
+                    return Ok(None);
+                };
+                (i + 1..budgets.len())
+                    .find(|&p| budgets[p].name.as_deref() == Some(&*parent))
+                    .map(Some)
+                    .ok_or(BuildError::UnknownParent { budget, parent })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let no_cascade = (0..budgets.len())
+            .filter(|&i| !budgets[i].cascade)
+            .fold(0, |mask, i| mask | 1 << i);
 
-        let now = Instant::now();
-        let stats = Stats::new();
-        let layer = SamplingLayer {
-            filters,
+        let stats = Stats::new(
+            names
+                .into_iter()
+                .zip(reservoirs.iter().map(Reservoir::capacity)),
+        );
+        let bucket_start = if self.config.jitter_buckets {
+            let offset = self.config.bucket_duration.mul_f64(fastrand::f64());
+            now.checked_sub(offset).unwrap_or(now)
+        } else {
+            now
+        };
+        let re_emit = self.config.re_emit.then(|| Arc::new(OnceLock::new()));
+        let flush_policy = match self.config.flush_policy {
+            FlushPolicy::PerBucket => FlushPolicy::Every(self.config.bucket_duration),
+            policy => policy,
+        };
+        let writers = Writers {
+            default: self.writer,
+            budgets: budget_writers,
+            re_emit: re_emit.clone().map(|dispatch| ReEmitter { dispatch }),
+            #[cfg(feature = "sentry")]
+            sentry: sentry_budgets,
+            flush_policy,
+            last_flush: Mutex::new(now),
+            on_error: self.config.on_write_error.clone(),
+            fallback: self.config.fallback.map(|writer| Fallback {
+                writer,
+                threshold: self.config.fallback_threshold.max(1),
+                retry_after: self.config.fallback_retry_after,
+                state: Mutex::default(),
+            }),
+        };
+        // Dispatching from inside another event's callbacks would clobber
+        // the per-layer filter state of that event, so re-emit from the
+        // worker thread.
+        let io_queue = match self.config.io_queue {
+            None if re_emit.is_some() => Some(RE_EMIT_QUEUE),
+            io_queue => io_queue,
+        };
+        #[cfg(feature = "tokio")]
+        let async_sink = (self.config.async_writer)
+            .filter(|_| re_emit.is_none())
+            .map(|config| {
+                #[allow(unused_mut)]
+                let mut worker = AsyncWorker::spawn(
+                    config,
+                    flush_policy,
+                    self.config.on_write_error,
+                    stats.clone(),
+                );
+                #[cfg(feature = "sentry")]
+                {
+                    worker.sentry = sentry_budgets;
+                }
+                Sink::Async(worker)
+            });
+        #[cfg(not(feature = "tokio"))]
+        let async_sink = None;
+        let sink = match (async_sink, io_queue) {
+            (Some(sink), _) => sink,
+            (None, Some(capacity)) => Sink::Worker(Worker::spawn(writers, capacity, stats.clone())),
+            (None, None) => Sink::Direct(writers),
+        };
+        let summary = match (self.config.drop_summary, self.config.drop_summary_format) {
+            (None, None) => None,
+            (level, format) => Some(SummaryConfig {
+                level: level.unwrap_or(Level::WARN),
+                format,
+            }),
+        };
+        let shared = Arc::new(Shared {
             state: Mutex::new(State {
-                bucket_start: now,
+                bucket_start,
+                bucket_duration: self.config.bucket_duration,
                 seq: 0,
                 reservoirs,
                 pending: Vec::new().into_iter(),
+                pending_carried: 0,
                 last_release: now,
+                bucket_dropped: [0; 5],
+                bucket_received: 0,
+                head_written: vec![0; heads.len()],
+                adaptive: (self.config.target_rate)
+                    .map(|target| Adaptive::new(target, heads.len())),
+                backpressure: self.config.backpressure.map(Backpressure::new),
+                bursts,
+                keyed: keyed_reservoirs,
+                head_limit: heads
+                    .iter()
+                    .zip(&passthroughs)
+                    .zip(&budgets)
+                    .map(|((head, passthrough), budget)| {
+                        head + if *passthrough { budget.capacity } else { 0 }
+                    })
+                    .collect(),
+                last_report: now,
             }),
-            bucket_duration: self.bucket_duration,
-            writer: self.writer,
-            fmt_layer: self.fmt_layer,
+            max_release: self.config.max_release,
+            max_release_time: self.config.max_release_time,
+            max_delay: self.config.max_delay,
+            budgets,
+            emit: emits,
+            weighting: weightings,
+            cost: costs,
+            sample_keys,
+            head: heads,
+            passthrough: passthroughs,
+            quotas,
+            ramp_up: ramp_ups,
+            started: now,
+            global_limit: self.config.global_limit,
+            parents,
+            hard: hard_limits,
+            per_root_span: per_root_spans,
+            enabled: AtomicBool::new(true),
+            sink,
             stats: stats.clone(),
+            summary,
+            report_every: self.config.report_every,
+            recent: self.config.recent,
+        });
+        if let Some(flusher) = self.config.flusher {
+            flusher.spawn(&shared);
+        }
+        let weak: Weak<dyn Control> = Arc::downgrade(&shared) as Weak<Shared<W>>;
+        let handle = Handle { inner: weak };
+        if self.config.flush_on_panic {
+            handle::flush_on_panic(handle.clone());
+        }
+        let layer = SamplingLayer {
+            filters,
+            no_cascade,
+            max_cascade_depth: self.config.max_cascade_depth,
+            first_match_only: self.config.first_match_only,
+            bypass_sampling: self.config.bypass_sampling,
+            priority: self.config.priority,
+            global_filter: self.config.global_filter,
+            per_layer: false,
+            handle,
+            shared,
+            fmt_layer: self.fmt_layer,
+            matched: ThreadLocal::new(),
+            span_events: self.config.span_events,
+            archive: self.config.archive,
+            re_emit,
             _subscriber: PhantomData,
         };
-        (layer, stats)
+        Ok((layer, stats))
+    }
+
+    /// Like [`build`](Self::build), but also returns a [`SamplingGuard`] that
+    /// flushes the layer when dropped.
+    pub fn build_with_guard(self) -> (SamplingLayer<S, N, E, W>, Stats, SamplingGuard) {
+        let (layer, stats) = self.build();
+        let guard = SamplingGuard {
+            handle: layer.handle(),
+        };
+        (layer, stats, guard)
     }
 }
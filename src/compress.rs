@@ -0,0 +1,155 @@
+use std::io::{self, Write};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Encoder<W: Write> {
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder,
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder,
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+struct State<W: Write> {
+    encoder: Option<Encoder<W>>,
+    flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl<W: Write> State<W> {
+    fn encoder(&mut self) -> io::Result<&mut dyn Write> {
+        match &mut self.encoder {
+            Some(encoder) => Ok(encoder.writer()),
+            None => Err(io::Error::other("compressed stream already finished")),
+        }
+    }
+}
+
+/// A [`MakeWriter`] that compresses sampled output into a single gzip or
+/// zstd stream.
+///
+/// The encoder is flushed once `flush_interval` has passed since the last
+/// flush, so everything written up to that point can be decompressed even
+/// while the stream is still open. The flush happens on the next write; call
+/// [`flush`](Self::flush) to force one, e.g. at shutdown. Dropping the writer
+/// finishes the stream.
+pub struct Compressed<W: Write> {
+    state: Mutex<State<W>>,
+}
+
+impl<W: Write> Compressed<W> {
+    fn new(encoder: Encoder<W>) -> Self {
+        Self {
+            state: Mutex::new(State {
+                encoder: Some(encoder),
+                flush_interval: DEFAULT_FLUSH_INTERVAL,
+                last_flush: Instant::now(),
+            }),
+        }
+    }
+
+    /// Compress into `writer` with gzip at the default compression level.
+    #[cfg(feature = "gzip")]
+    pub fn gzip(writer: W) -> Self {
+        Self::new(Encoder::Gzip(flate2::write::GzEncoder::new(
+            writer,
+            flate2::Compression::default(),
+        )))
+    }
+
+    /// Compress into `writer` with zstd at `level`, where 0 picks zstd's
+    /// default.
+    #[cfg(feature = "zstd")]
+    pub fn zstd(writer: W, level: i32) -> io::Result<Self> {
+        Ok(Self::new(Encoder::Zstd(zstd::stream::write::Encoder::new(
+            writer, level,
+        )?)))
+    }
+
+    /// How often the encoder is flushed through to the underlying writer.
+    /// Shorter intervals lose less on a crash but compress worse. Defaults
+    /// to one second.
+    pub fn flush_interval(self, interval: Duration) -> Self {
+        self.lock().flush_interval = interval;
+        self
+    }
+
+    /// Flush everything written so far through to the underlying writer.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        state.last_flush = Instant::now();
+        state.encoder()?.flush()
+    }
+
+    /// Finish the stream and return the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.lock().encoder.take() {
+            Some(encoder) => encoder.finish(),
+            None => Err(io::Error::other("compressed stream already finished")),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<W>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write> Drop for Compressed<W> {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Some(encoder) = state.encoder.take() {
+            let _ = encoder.finish();
+        }
+    }
+}
+
+/// Writer returned by [`Compressed`].
+pub struct CompressedWriter<'a, W: Write> {
+    state: MutexGuard<'a, State<W>>,
+}
+
+impl<W: Write> Write for CompressedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.state.encoder()?.write(buf)?;
+        if self.state.last_flush.elapsed() >= self.state.flush_interval {
+            self.flush()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.last_flush = Instant::now();
+        self.state.encoder()?.flush()
+    }
+}
+
+impl<'a, W: Write + 'a> MakeWriter<'a> for Compressed<W> {
+    type Writer = CompressedWriter<'a, W>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        CompressedWriter { state: self.lock() }
+    }
+}
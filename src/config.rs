@@ -0,0 +1,272 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::{
+    Compact, DefaultFields, Format, FormatEvent, FormatFields, Full, Writer,
+};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::format::{Json, JsonFields, Logfmt};
+use crate::quota::Schedule;
+
+/// Sampling configuration that can be deserialized, e.g. from a TOML file,
+/// so deployments can tune sampling without code changes. Turned into a
+/// builder with
+/// [`SamplingLayerBuilder::from_config`](crate::SamplingLayerBuilder::from_config).
+///
+/// ```toml
+/// bucket_duration = "50ms"
+/// format = "json"
+///
+/// [[budgets]]
+/// filter = "error"
+/// limit = 1000
+///
+/// [[budgets]]
+/// name = "info"
+/// filter = "info,hyper=warn"
+/// limit = 5000
+///
+/// [[budgets]]
+/// name = "debug"
+/// filter = "debug"
+/// limit = 100
+/// schedule.utc_offset_minutes = 60
+/// schedule.windows = [{ from = "09:00", to = "17:00", limit = 1000 }]
+/// ```
+///
+/// Every key is optional; unknown keys are rejected.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct SamplingConfig {
+    /// The bucket duration, with a unit: `ns`, `us`, `ms`, `s`, `m` or `h`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub bucket_duration: Option<Duration>,
+    /// Budgets, in the order they are added.
+    pub budgets: Vec<BudgetConfig>,
+    /// How events are formatted.
+    pub format: FormatConfig,
+    /// Whether to use ANSI colours. Defaults to the builder's default.
+    pub ansi: Option<bool>,
+    /// Whether to include timestamps. Defaults to true.
+    pub time: bool,
+    /// Whether to include event targets, for the `full` and `compact`
+    /// formats. Defaults to true.
+    pub target: bool,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            bucket_duration: None,
+            budgets: Vec::new(),
+            format: FormatConfig::Full,
+            ansi: None,
+            time: true,
+            target: true,
+        }
+    }
+}
+
+/// A budget in a [`SamplingConfig`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct BudgetConfig {
+    /// See [`Budget::named`](crate::Budget::named).
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Filter directives in [`EnvFilter`](tracing_subscriber::EnvFilter)
+    /// syntax.
+    pub filter: String,
+    /// The per-second event limit.
+    pub limit: u64,
+    /// See [`Budget::no_cascade`](crate::Budget::no_cascade). Defaults to
+    /// true.
+    #[serde(default = "yes")]
+    pub cascade: bool,
+    /// Limits replacing `limit` at certain times of day. See
+    /// [`Schedule`].
+    #[serde(default)]
+    pub schedule: Option<ScheduleConfig>,
+}
+
+/// A budget's [`Schedule`] in a [`SamplingConfig`].
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct ScheduleConfig {
+    /// See [`Schedule::utc_offset_minutes`].
+    pub utc_offset_minutes: i32,
+    /// See [`Schedule::window`].
+    pub windows: Vec<WindowConfig>,
+}
+
+/// A window in a [`ScheduleConfig`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[non_exhaustive]
+pub struct WindowConfig {
+    /// The start, as `HH:MM`.
+    #[serde(deserialize_with = "deserialize_time")]
+    pub from: (u32, u32),
+    /// The end, as `HH:MM`.
+    #[serde(deserialize_with = "deserialize_time")]
+    pub to: (u32, u32),
+    /// The per-second event limit within the window.
+    pub limit: u64,
+}
+
+impl ScheduleConfig {
+    pub(crate) fn schedule(&self, limit: u64) -> Schedule {
+        let schedule = Schedule::new(limit).utc_offset_minutes(self.utc_offset_minutes);
+        (self.windows.iter()).fold(schedule, |schedule, window| {
+            schedule.window(window.from, window.to, window.limit)
+        })
+    }
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// The event format chosen by a [`SamplingConfig`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum FormatConfig {
+    /// `tracing-subscriber`'s default format.
+    #[default]
+    Full,
+    /// `tracing-subscriber`'s compact format.
+    Compact,
+    /// See [`Json`].
+    Json,
+    /// See [`Logfmt`].
+    Logfmt,
+}
+
+fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(d)?;
+    crate::env::parse_duration(&s)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format_args!("invalid duration {s:?}")))
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(d: D) -> Result<(u32, u32), D::Error> {
+    let s = String::deserialize(d)?;
+    (s.split_once(':'))
+        .and_then(|(hour, minute)| Some((hour.parse().ok()?, minute.parse().ok()?)))
+        .filter(|&(hour, minute)| hour < 24 && minute < 60)
+        .ok_or_else(|| serde::de::Error::custom(format_args!("invalid time of day {s:?}")))
+}
+
+/// The event formatter chosen by a [`SamplingConfig`].
+pub struct ConfigFormat(Formatter);
+
+enum Formatter {
+    Full(Format<Full, Timer>),
+    Compact(Format<Compact, Timer>),
+    Json(Json<Timer>),
+    Logfmt(Logfmt<Timer>),
+}
+
+impl ConfigFormat {
+    pub(crate) fn new(config: &SamplingConfig) -> Self {
+        let timer = Timer(config.time);
+        let format = Format::default().with_target(config.target);
+        let format = if config.time {
+            format.with_timer(timer)
+        } else {
+            // Leaves out the space that would follow the timestamp.
+            format.without_time().with_timer(timer)
+        };
+        ConfigFormat(match config.format {
+            FormatConfig::Full => Formatter::Full(format),
+            FormatConfig::Compact => Formatter::Compact(format.compact()),
+            FormatConfig::Json => Formatter::Json(Json::default().with_timer(timer)),
+            FormatConfig::Logfmt => {
+                Formatter::Logfmt(Logfmt::<SystemTime>::default().with_timer(timer))
+            }
+        })
+    }
+
+    pub(crate) fn fields(&self) -> ConfigFields {
+        ConfigFields {
+            json: matches!(self.0, Formatter::Json(_)),
+        }
+    }
+}
+
+impl<S> FormatEvent<S, ConfigFields> for ConfigFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, ConfigFields>,
+        writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        match &self.0 {
+            Formatter::Full(format) => format.format_event(ctx, writer, event),
+            Formatter::Compact(format) => format.format_event(ctx, writer, event),
+            Formatter::Json(format) => format.format_event(ctx, writer, event),
+            Formatter::Logfmt(format) => format.format_event(ctx, writer, event),
+        }
+    }
+}
+
+/// Formats span fields for a [`ConfigFormat`]: as JSON for the `json`
+/// format, and as `tracing-subscriber` does by default otherwise.
+pub struct ConfigFields {
+    json: bool,
+}
+
+impl<'writer> FormatFields<'writer> for ConfigFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        if self.json {
+            JsonFields::default().format_fields(writer, fields)
+        } else {
+            DefaultFields::new().format_fields(writer, fields)
+        }
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        if !self.json {
+            if !current.fields.is_empty() {
+                current.fields.push(' ');
+            }
+            return self.format_fields(current.as_writer(), fields);
+        }
+        let first = current.fields.is_empty();
+        let mut writer = current.as_writer();
+        let mut visitor = crate::format::JsonVisitor::new(&mut writer, first);
+        fields.record(&mut visitor);
+        visitor.finish()
+    }
+}
+
+/// The system time, or nothing if timestamps are disabled.
+#[derive(Clone, Copy)]
+struct Timer(bool);
+
+impl FormatTime for Timer {
+    fn format_time(&self, w: &mut Writer<'_>) -> fmt::Result {
+        if self.0 {
+            SystemTime.format_time(w)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt::{self, Write};
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Metadata};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// What a budget hashes to decide on an event consistently. See
+/// [`Budget::consistent`](crate::Budget::consistent).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SampleKey {
+    /// The event's callsite: its target and the file and line of the macro
+    /// invocation, standing in for its message template.
+    Callsite,
+    /// The value of a field, such as a request or trace id. Events without
+    /// the field are decided at random.
+    Field(&'static str),
+    /// A value drawn once for each root span, such as the span of one
+    /// request, and kept in its extensions, so that all of a request's
+    /// events are kept or none are, rather than a confusing few. Events
+    /// outside any span are decided at random.
+    RootSpan,
+    /// The trace id from a `trace_id` field of the event or one of its
+    /// spans, or with the `opentelemetry` feature from the current
+    /// OpenTelemetry context, so that logs are kept for the same traces as
+    /// are sampled elsewhere.
+    ///
+    /// An id of 32 hex digits is decided as OpenTelemetry's
+    /// `TraceIdRatioBased` sampler decides on its trace, so a budget keeping
+    /// a [`fraction`](crate::Budget::fraction) of events keeps those of the
+    /// traces sampled at that ratio. Other ids are hashed as for
+    /// [`Field`](Self::Field), and events without one are decided at
+    /// random.
+    TraceId,
+}
+
+impl SampleKey {
+    /// A hash of the key for an event, scaled to `[0, 1)`, or `None` if the
+    /// event doesn't have the key.
+    pub(crate) fn hash(&self, meta: &Metadata<'_>, event: Option<&Event<'_>>) -> Option<f64> {
+        let mut hasher = Fnv::default();
+        match self {
+            SampleKey::Callsite => {
+                hasher.write(meta.target().as_bytes());
+                hasher.write(meta.name().as_bytes());
+            }
+            // Decided by the layer, which has the event's spans.
+            SampleKey::RootSpan | SampleKey::TraceId => return None,
+            SampleKey::Field(field) => {
+                let mut visitor = KeyVisitor {
+                    field,
+                    hasher: &mut hasher,
+                    found: false,
+                };
+                event?.record(&mut visitor);
+                if !visitor.found {
+                    return None;
+                }
+            }
+        }
+        Some(hasher.fraction())
+    }
+}
+
+/// What an event is decided by for the keys that depend on its spans or
+/// context, which [`SampleKey::hash`] doesn't have.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct SpanHashes {
+    pub(crate) root_span: Option<f64>,
+    pub(crate) trace_id: Option<f64>,
+}
+
+impl SpanHashes {
+    pub(crate) fn hash(
+        &self,
+        key: SampleKey,
+        meta: &Metadata<'_>,
+        event: Option<&Event<'_>>,
+    ) -> Option<f64> {
+        match key {
+            SampleKey::RootSpan => self.root_span,
+            SampleKey::TraceId => self.trace_id,
+            key => key.hash(meta, event),
+        }
+    }
+}
+
+/// The value `root`'s events are decided by for [`SampleKey::RootSpan`],
+/// drawn the first time it's needed.
+pub(crate) fn root_span_hash<S>(root: &SpanRef<'_, S>) -> f64
+where
+    S: for<'a> LookupSpan<'a>,
+{
+    let mut extensions = root.extensions_mut();
+    if let Some(RootSpanHash(hash)) = extensions.get_mut::<RootSpanHash>() {
+        return *hash;
+    }
+    let hash = fastrand::f64();
+    extensions.insert(RootSpanHash(hash));
+    hash
+}
+
+/// Shared by every layer, so they decide on a root span's events alike.
+struct RootSpanHash(f64);
+
+/// The trace id of a span, as [`trace_id_hash`] hashes it.
+struct SpanTraceId(f64);
+
+/// Remember the `trace_id` among a span's `values`, if any, for
+/// [`SampleKey::TraceId`].
+pub(crate) fn record_span_trace_id<S>(span: &SpanRef<'_, S>, values: impl FnOnce(&mut dyn Visit))
+where
+    S: for<'a> LookupSpan<'a>,
+{
+    let mut visitor = TraceIdVisitor(None);
+    values(&mut visitor);
+    if let Some(hash) = visitor.0 {
+        span.extensions_mut().replace(SpanTraceId(hash));
+    }
+}
+
+/// The trace id an event is decided by for [`SampleKey::TraceId`]: its
+/// own `trace_id` field, that of the nearest of its spans to have one, or
+/// the current OpenTelemetry context's.
+pub(crate) fn trace_id_hash<S>(
+    event: Option<&Event<'_>>,
+    leaf: Option<&SpanRef<'_, S>>,
+) -> Option<f64>
+where
+    S: for<'a> LookupSpan<'a>,
+{
+    let mut visitor = TraceIdVisitor(None);
+    if let Some(event) = event {
+        event.record(&mut visitor);
+    }
+    visitor
+        .0
+        .or_else(|| {
+            leaf?
+                .scope()
+                .find_map(|span| span.extensions().get::<SpanTraceId>().map(|id| id.0))
+        })
+        .or_else(context_trace_id)
+}
+
+#[cfg(feature = "opentelemetry")]
+fn context_trace_id() -> Option<f64> {
+    use opentelemetry::trace::TraceContextExt;
+
+    let context = opentelemetry::Context::current();
+    let span = context.span();
+    let span_context = span.span_context();
+    span_context
+        .is_valid()
+        .then(|| ratio(u128::from_be_bytes(span_context.trace_id().to_bytes())))
+}
+
+#[cfg(not(feature = "opentelemetry"))]
+fn context_trace_id() -> Option<f64> {
+    None
+}
+
+/// Where a trace falls in `[0, 1)` for OpenTelemetry's `TraceIdRatioBased`
+/// sampler, which keeps it if this is below its ratio: the low 64 bits of
+/// the id without the lowest, as a fraction of `2^63`.
+fn ratio(trace_id: u128) -> f64 {
+    ((trace_id as u64) >> 1) as f64 / (1u64 << 63) as f64
+}
+
+/// Hashes a `trace_id` field with [`ratio`] if it's 32 hex digits, or as
+/// [`SampleKey::Field`] does otherwise.
+struct TraceIdVisitor(Option<f64>);
+
+impl TraceIdVisitor {
+    fn record_id(&mut self, id: &str) {
+        let hex = id.len() == 32 && id.bytes().all(|byte| byte.is_ascii_hexdigit());
+        self.0 = Some(match u128::from_str_radix(id, 16) {
+            Ok(trace_id) if hex => ratio(trace_id),
+            _ => {
+                let mut hasher = Fnv::default();
+                hasher.write(id.as_bytes());
+                hasher.fraction()
+            }
+        });
+    }
+}
+
+impl Visit for TraceIdVisitor {
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        if field.name() == "trace_id" {
+            self.0 = Some(ratio(value));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "trace_id" {
+            self.record_id(value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "trace_id" {
+            self.record_id(&format!("{value:?}"));
+        }
+    }
+}
+
+/// FNV-1a with a final mix, which unlike `std`'s hasher is stable across
+/// Rust versions, and so across replicas built differently.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// The `fmix64` finalizer from MurmurHash3, so every bit of the hash
+    /// depends on every input bit.
+    fn finish(&self) -> u64 {
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+
+    /// The hash scaled to `[0, 1)`.
+    fn fraction(&self) -> f64 {
+        (self.finish() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl fmt::Write for Fnv {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Hashes a field's value as it would be displayed, so `42` and `"42"`
+/// are the same key.
+struct KeyVisitor<'a> {
+    field: &'a str,
+    hasher: &'a mut Fnv,
+    found: bool,
+}
+
+impl Visit for KeyVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.field {
+            self.hasher.write(value.as_bytes());
+            self.found = true;
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == self.field {
+            let _ = write!(self.hasher, "{value:?}");
+            self.found = true;
+        }
+    }
+}
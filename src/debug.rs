@@ -0,0 +1,92 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::handle::Handle;
+use crate::layer::Shared;
+use crate::stats::StatsSnapshot;
+
+#[derive(Serialize)]
+pub(crate) struct DebugState {
+    bucket_duration_ms: f64,
+    pending: usize,
+    budgets: Vec<BudgetState>,
+    stats: StatsSnapshot,
+}
+
+#[derive(Serialize)]
+struct BudgetState {
+    name: Option<String>,
+    filter: String,
+    limit_per_second: u64,
+    capacity: usize,
+    fill: usize,
+}
+
+impl<W> Shared<W> {
+    pub(crate) fn debug_state(&self) -> DebugState {
+        let state = self.state.lock().unwrap();
+        let budgets = self
+            .budgets
+            .iter()
+            .zip(&state.reservoirs)
+            .map(|(info, reservoir)| BudgetState {
+                name: info.name.clone(),
+                filter: info.filter.clone(),
+                limit_per_second: info.limit_per_second,
+                capacity: reservoir.capacity(),
+                fill: reservoir.len(),
+            })
+            .collect();
+        DebugState {
+            bucket_duration_ms: state.bucket_duration.as_secs_f64() * 1000.0,
+            pending: state.pending.len(),
+            budgets,
+            stats: self.stats.snapshot(),
+        }
+    }
+}
+
+impl Handle {
+    /// Serve [`debug_json`](Self::debug_json) over HTTP on `addr` from a
+    /// background thread, returning the bound address.
+    ///
+    /// Every request is answered with the current JSON state regardless of
+    /// method or path. Intended for local debugging only: there is no
+    /// authentication and connections are handled one at a time. The thread
+    /// exits on the first request after the layer has been dropped.
+    pub fn serve_debug(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local = listener.local_addr()?;
+        let handle = self.clone();
+        std::thread::Builder::new()
+            .name("tracing-log-sample-debug".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else { continue };
+                    let Some(body) = handle.debug_json() else {
+                        break;
+                    };
+                    let _ = respond(stream, &body);
+                }
+            })?;
+        Ok(local)
+    }
+}
+
+fn respond(mut stream: TcpStream, body: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // Read and discard the request head.
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
@@ -0,0 +1,120 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::EnvError;
+
+/// The variable read by
+/// [`SamplingLayerBuilder::from_env`](crate::SamplingLayerBuilder::from_env).
+pub(crate) const DEFAULT_VAR: &str = "TRACING_SAMPLE";
+
+/// A full configuration parsed from a spec like
+/// `error@1000,info@5000;bucket=50ms`.
+#[derive(Debug, Default)]
+pub(crate) struct Spec {
+    /// Filter directives and per-second limit of each budget, in order.
+    pub(crate) budgets: Vec<(String, u64)>,
+    pub(crate) bucket_duration: Option<Duration>,
+}
+
+impl Spec {
+    pub(crate) fn parse(spec: &str) -> Result<Self, EnvError> {
+        let mut parsed = Spec::default();
+        let mut options = spec.split(';');
+        // Directives may themselves contain commas, so a budget runs until
+        // the next `@limit`.
+        let mut directives = String::new();
+        for part in options.next().unwrap_or_default().split(',') {
+            if !directives.is_empty() {
+                directives.push(',');
+            }
+            directives.push_str(part.trim());
+            if part.contains('@') {
+                let (filter, limit) = split_budget(&directives)?;
+                parsed.budgets.push((filter.to_owned(), limit));
+                directives.clear();
+            }
+        }
+        if !directives.is_empty() {
+            return Err(EnvError::MissingLimit { budget: directives });
+        }
+        for option in options.map(str::trim).filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("bucket", duration)) => {
+                    let invalid = || EnvError::InvalidDuration {
+                        duration: duration.to_owned(),
+                    };
+                    parsed.bucket_duration = Some(parse_duration(duration).ok_or_else(invalid)?);
+                }
+                _ => {
+                    return Err(EnvError::UnknownOption {
+                        option: option.to_owned(),
+                    });
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Read `var`, which must be set and valid unicode.
+pub(crate) fn read(var: &str) -> Result<String, EnvError> {
+    std::env::var(var).map_err(|_| EnvError::NotPresent {
+        var: var.to_owned(),
+    })
+}
+
+/// Split a budget written as `directives@limit`, e.g. `error@1000`, into its
+/// filter directives and per-second limit.
+pub(crate) fn split_budget(spec: &str) -> Result<(&str, u64), EnvError> {
+    let Some((directives, limit)) = spec.rsplit_once('@') else {
+        return Err(EnvError::MissingLimit {
+            budget: spec.to_owned(),
+        });
+    };
+    let limit = limit.trim();
+    match limit.parse() {
+        Ok(limit) => Ok((directives.trim(), limit)),
+        Err(_) => Err(EnvError::InvalidLimit {
+            limit: limit.to_owned(),
+        }),
+    }
+}
+
+/// A limit re-read from `var` at most once per `every`, keeping the last
+/// good value while the variable is unset or invalid.
+pub(crate) fn reread_limit(
+    var: String,
+    every: Duration,
+    limit: u64,
+) -> impl Fn() -> u64 + Send + Sync + 'static {
+    let last = Mutex::new((Instant::now(), limit));
+    move || {
+        let mut last = last.lock().unwrap();
+        if last.0.elapsed() >= every {
+            last.0 = Instant::now();
+            if let Ok(spec) = read(&var)
+                && let Ok((_, limit)) = split_budget(&spec)
+            {
+                last.1 = limit;
+            }
+        }
+        last.1
+    }
+}
+
+/// Parse a duration written with a unit, e.g. `50ms` or `1.5s`.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (value, unit) = s.split_at(s.find(|c: char| c.is_ascii_alphabetic())?);
+    let scale = match unit {
+        "ns" => 1e-9,
+        "us" => 1e-6,
+        "ms" => 1e-3,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return None,
+    };
+    let value: f64 = value.trim().parse().ok()?;
+    Duration::try_from_secs_f64(value * scale).ok()
+}
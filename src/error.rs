@@ -0,0 +1,147 @@
+use std::fmt;
+
+use tracing_subscriber::filter::ParseError;
+
+/// Reservoirs larger than this are rejected by
+/// [`try_build`](crate::SamplingLayerBuilder::try_build), and capacities that
+/// change as buckets end are capped to it, since every slot is allocated up
+/// front.
+pub(crate) const MAX_CAPACITY: usize = 1 << 20;
+
+/// The most budgets a layer can have; matches are tracked in a `u64` bitset.
+pub(crate) const MAX_BUDGETS: usize = u64::BITS as usize;
+
+/// An invalid builder configuration.
+///
+/// Returned by [`try_build`](crate::SamplingLayerBuilder::try_build).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The bucket duration was zero.
+    ZeroBucketDuration,
+    /// No budgets were added, so every event would be discarded.
+    NoBudgets,
+    /// More than 64 budgets were added.
+    TooManyBudgets {
+        /// The number of budgets added.
+        count: usize,
+    },
+    /// A budget's limit rounds to zero events per bucket.
+    ZeroCapacity {
+        /// The budget's index, in the order budgets were added.
+        budget: usize,
+    },
+    /// A budget's limit would need a reservoir of more than 2^20 events per
+    /// bucket.
+    CapacityTooLarge {
+        /// The budget's index, in the order budgets were added.
+        budget: usize,
+        /// The reservoir capacity the limit works out to.
+        capacity: usize,
+    },
+    /// A budget's [`parent`](crate::Budget::parent) doesn't name a budget
+    /// added after it.
+    UnknownParent {
+        /// The budget's index, in the order budgets were added.
+        budget: usize,
+        /// The parent's name.
+        parent: String,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ZeroBucketDuration => f.write_str("bucket_duration must be > 0"),
+            BuildError::NoBudgets => f.write_str("no budgets were configured"),
+            BuildError::TooManyBudgets { count } => {
+                write!(
+                    f,
+                    "{count} budgets configured, at most {MAX_BUDGETS} are supported"
+                )
+            }
+            BuildError::ZeroCapacity { budget } => {
+                write!(f, "budget {budget} allows zero events per bucket")
+            }
+            BuildError::CapacityTooLarge { budget, capacity } => write!(
+                f,
+                "budget {budget} needs {capacity} events per bucket, at most {MAX_CAPACITY} are supported"
+            ),
+            BuildError::UnknownParent { budget, parent } => write!(
+                f,
+                "budget {budget} has parent {parent:?}, which isn't a budget added after it"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// An invalid budget or configuration read from an environment variable.
+///
+/// Returned by [`Budget::from_env`](crate::Budget::from_env) and
+/// [`SamplingLayerBuilder::from_env`](crate::SamplingLayerBuilder::from_env).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EnvError {
+    /// The variable isn't set or isn't valid unicode.
+    NotPresent {
+        /// The variable's name.
+        var: String,
+    },
+    /// A budget wasn't written as `directives@limit`.
+    MissingLimit {
+        /// The budget as written.
+        budget: String,
+    },
+    /// A budget's limit wasn't a whole number.
+    InvalidLimit {
+        /// The limit as written.
+        limit: String,
+    },
+    /// A budget's filter directives were invalid.
+    Filter(ParseError),
+    /// An option wasn't one of those supported.
+    UnknownOption {
+        /// The option as written.
+        option: String,
+    },
+    /// A duration wasn't a number followed by a unit.
+    InvalidDuration {
+        /// The duration as written.
+        duration: String,
+    },
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvError::NotPresent { var } => write!(f, "environment variable {var} is not set"),
+            EnvError::MissingLimit { budget } => {
+                write!(
+                    f,
+                    "budget {budget:?} has no limit, expected directives@limit"
+                )
+            }
+            EnvError::InvalidLimit { limit } => write!(f, "invalid limit {limit:?}"),
+            EnvError::Filter(err) => write!(f, "invalid filter directives: {err}"),
+            EnvError::UnknownOption { option } => write!(f, "unknown option {option:?}"),
+            EnvError::InvalidDuration { duration } => write!(f, "invalid duration {duration:?}"),
+        }
+    }
+}
+
+impl std::error::Error for EnvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EnvError::Filter(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for EnvError {
+    fn from(err: ParseError) -> Self {
+        EnvError::Filter(err)
+    }
+}
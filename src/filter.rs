@@ -0,0 +1,368 @@
+use std::fmt;
+
+use tracing::field::{Field, Visit};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter, Targets};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Decides which events a budget accepts.
+///
+/// Implemented for [`EnvFilter`], [`Targets`] and [`LevelFilter`]. `Targets`
+/// and `LevelFilter` are cheaper to evaluate than `EnvFilter` when span-based
+/// directives aren't needed.
+///
+/// The [`Display`](fmt::Display) output is reported as the budget's filter by
+/// [`SamplingLayer::budgets`](crate::SamplingLayer::budgets).
+pub trait BudgetFilter<S>: fmt::Display + Send + Sync + 'static {
+    /// Whether events from this callsite can ever be accepted.
+    ///
+    /// Returning [`Interest::never`] lets the layer skip the callsite
+    /// entirely when no other budget is interested. Defaults to
+    /// [`Interest::sometimes`], so [`enabled`](Self::enabled) is always
+    /// consulted.
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let _ = meta;
+        Interest::sometimes()
+    }
+
+    /// Whether this budget accepts an event with the given metadata.
+    fn enabled(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> bool;
+
+    /// Whether this budget accepts a specific event, whose metadata has
+    /// already passed [`enabled`](Self::enabled). Override this to match on
+    /// field values. Defaults to true.
+    fn event_enabled(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> bool {
+        let _ = (event, ctx);
+        true
+    }
+
+    /// The most verbose level this budget can accept, if known, letting
+    /// `tracing` skip more verbose events before reaching the layer.
+    /// Defaults to `None`.
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        None
+    }
+}
+
+impl<S: Subscriber> BudgetFilter<S> for EnvFilter {
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        Layer::<S>::register_callsite(self, meta)
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> bool {
+        EnvFilter::enabled(self, meta, ctx.clone())
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        EnvFilter::max_level_hint(self)
+    }
+}
+
+impl<S> BudgetFilter<S> for Targets {
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        static_interest(self.would_enable(meta.target(), meta.level()))
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, _: &Context<'_, S>) -> bool {
+        self.would_enable(meta.target(), meta.level())
+    }
+}
+
+impl<S> BudgetFilter<S> for LevelFilter {
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        static_interest(meta.level() <= self)
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, _: &Context<'_, S>) -> bool {
+        meta.level() <= self
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(*self)
+    }
+}
+
+fn static_interest(enabled: bool) -> Interest {
+    if enabled {
+        Interest::always()
+    } else {
+        Interest::never()
+    }
+}
+
+/// A closure deciding which events a budget accepts.
+pub(crate) struct FnFilter<F>(pub(crate) F);
+
+impl<S, F> BudgetFilter<S> for FnFilter<F>
+where
+    F: Fn(&Metadata<'_>, &Context<'_, S>) -> bool + Send + Sync + 'static,
+{
+    fn enabled(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> bool {
+        (self.0)(meta, ctx)
+    }
+}
+
+impl<F> fmt::Display for FnFilter<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<fn>")
+    }
+}
+
+/// Events between two levels, inclusive.
+pub(crate) struct LevelRange {
+    /// The most severe level accepted.
+    severe: Level,
+    /// The most verbose level accepted, so `severe <= verbose` in `tracing`'s
+    /// ordering.
+    verbose: Level,
+}
+
+impl LevelRange {
+    pub(crate) fn new(a: Level, b: Level) -> Self {
+        Self {
+            severe: a.min(b),
+            verbose: a.max(b),
+        }
+    }
+}
+
+impl<S> BudgetFilter<S> for LevelRange {
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        static_interest((self.severe..=self.verbose).contains(meta.level()))
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, _: &Context<'_, S>) -> bool {
+        (self.severe..=self.verbose).contains(meta.level())
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.verbose))
+    }
+}
+
+impl fmt::Display for LevelRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verbose = self.verbose.as_str().to_lowercase();
+        // Equivalent to an `EnvFilter` directive naming just the level.
+        if self.severe == Level::ERROR {
+            return f.write_str(&verbose);
+        }
+        write!(f, "{verbose}..={}", self.severe.as_str().to_lowercase())
+    }
+}
+
+/// Events accepted by `include` but not by `exclude`.
+pub(crate) struct Except<S> {
+    pub(crate) include: Box<dyn BudgetFilter<S>>,
+    pub(crate) exclude: Box<dyn BudgetFilter<S>>,
+}
+
+impl<S: 'static> BudgetFilter<S> for Except<S> {
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let include = self.include.callsite_enabled(meta);
+        let exclude = self.exclude.callsite_enabled(meta);
+        if include.is_never() || exclude.is_always() {
+            Interest::never()
+        } else if include.is_always() && exclude.is_never() {
+            Interest::always()
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> bool {
+        self.include.enabled(meta, ctx) && !self.exclude.enabled(meta, ctx)
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> bool {
+        self.include.event_enabled(event, ctx)
+            && !(self.exclude.enabled(event.metadata(), ctx)
+                && self.exclude.event_enabled(event, ctx))
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.include.max_level_hint()
+    }
+}
+
+impl<S> fmt::Display for Except<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} except {}", self.include, self.exclude)
+    }
+}
+
+/// Events accepted by `inner` inside a span named `span`.
+pub(crate) struct SpanFilter<S> {
+    pub(crate) inner: Box<dyn BudgetFilter<S>>,
+    pub(crate) span: &'static str,
+}
+
+impl<S> BudgetFilter<S> for SpanFilter<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        match self.inner.callsite_enabled(meta) {
+            interest if interest.is_never() => interest,
+            _ => Interest::sometimes(),
+        }
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> bool {
+        self.inner.enabled(meta, ctx)
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> bool {
+        self.inner.event_enabled(event, ctx)
+            && ctx
+                .event_scope(event)
+                .is_some_and(|mut scope| scope.any(|span| span.name() == self.span))
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.inner.max_level_hint()
+    }
+}
+
+impl<S> fmt::Display for SpanFilter<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in span {}", self.inner, self.span)
+    }
+}
+
+/// The value of an event field, as passed to
+/// [`Budget::field_matches`](crate::Budget::field_matches).
+///
+/// Values recorded with `Debug` or `Display`, and integers wider than 64 bits,
+/// are formatted and passed as [`Str`](Self::Str).
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum FieldValue<'a> {
+    /// A signed integer.
+    I64(i64),
+    /// An unsigned integer.
+    U64(u64),
+    /// A floating point number.
+    F64(f64),
+    /// A boolean.
+    Bool(bool),
+    /// A string, or a value formatted as one.
+    Str(&'a str),
+}
+
+impl FieldValue<'_> {
+    /// The value as an `i64`, if it is an integer that fits.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            FieldValue::I64(v) => Some(v),
+            FieldValue::U64(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a `u64`, if it is an integer that fits.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            FieldValue::I64(v) => v.try_into().ok(),
+            FieldValue::U64(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, if it is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            FieldValue::Str(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Events accepted by `inner` whose `field` satisfies `predicate`.
+pub(crate) struct FieldFilter<S> {
+    pub(crate) inner: Box<dyn BudgetFilter<S>>,
+    pub(crate) field: &'static str,
+    pub(crate) predicate: Box<dyn Fn(FieldValue<'_>) -> bool + Send + Sync>,
+}
+
+impl<S: 'static> BudgetFilter<S> for FieldFilter<S> {
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        if meta.fields().field(self.field).is_none() {
+            return Interest::never();
+        }
+        match self.inner.callsite_enabled(meta) {
+            interest if interest.is_never() => interest,
+            _ => Interest::sometimes(),
+        }
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> bool {
+        meta.fields().field(self.field).is_some() && self.inner.enabled(meta, ctx)
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> bool {
+        if !self.inner.event_enabled(event, ctx) {
+            return false;
+        }
+        let mut visitor = FieldVisitor {
+            filter: self,
+            matched: false,
+        };
+        event.record(&mut visitor);
+        visitor.matched
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.inner.max_level_hint()
+    }
+}
+
+impl<S> fmt::Display for FieldFilter<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} where {}", self.inner, self.field)
+    }
+}
+
+struct FieldVisitor<'a, S> {
+    filter: &'a FieldFilter<S>,
+    matched: bool,
+}
+
+impl<S> FieldVisitor<'_, S> {
+    fn check(&mut self, field: &Field, value: FieldValue<'_>) {
+        if field.name() == self.filter.field {
+            self.matched = (self.filter.predicate)(value);
+        }
+    }
+}
+
+impl<S> Visit for FieldVisitor<'_, S> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.check(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.check(field, FieldValue::U64(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.check(field, FieldValue::F64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.check(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.check(field, FieldValue::Str(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == self.filter.field {
+            self.check(field, FieldValue::Str(&format!("{value:?}")));
+        }
+    }
+}
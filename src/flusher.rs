@@ -0,0 +1,70 @@
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::layer::Shared;
+
+/// Lower bound on how long a flusher sleeps between ticks, so dense smear
+/// schedules are released in small batches rather than spinning.
+const MIN_TICK: Duration = Duration::from_millis(1);
+
+/// How bucket rotation and smearing are driven when no events arrive.
+pub(crate) enum Flusher {
+    Thread,
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::runtime::Handle),
+}
+
+impl Flusher {
+    pub(crate) fn spawn<W>(self, shared: &Arc<Shared<W>>)
+    where
+        W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        match self {
+            Flusher::Thread => spawn_thread(shared),
+            #[cfg(feature = "tokio")]
+            Flusher::Tokio(handle) => spawn_tokio(shared, &handle),
+        }
+    }
+}
+
+fn sleep_for(next: Instant) -> Duration {
+    next.saturating_duration_since(Instant::now()).max(MIN_TICK)
+}
+
+/// Spawn an OS thread that rotates buckets and releases smeared events on a
+/// timer. The thread exits once the layer has been dropped.
+fn spawn_thread<W>(shared: &Arc<Shared<W>>)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let weak: Weak<Shared<W>> = Arc::downgrade(shared);
+    std::thread::Builder::new()
+        .name("tracing-log-sample-flush".into())
+        .spawn(move || {
+            while let Some(shared) = weak.upgrade() {
+                let next = shared.tick_smear();
+                drop(shared);
+                std::thread::sleep(sleep_for(next));
+            }
+        })
+        .expect("failed to spawn flush thread");
+}
+
+/// Spawn a tokio task that rotates buckets and releases smeared events on a
+/// timer. The task exits once the layer has been dropped.
+#[cfg(feature = "tokio")]
+fn spawn_tokio<W>(shared: &Arc<Shared<W>>, handle: &tokio::runtime::Handle)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let weak: Weak<Shared<W>> = Arc::downgrade(shared);
+    handle.spawn(async move {
+        while let Some(shared) = weak.upgrade() {
+            let next = shared.tick_smear();
+            drop(shared);
+            tokio::time::sleep(sleep_for(next)).await;
+        }
+    });
+}
@@ -0,0 +1,554 @@
+use std::fmt::{self, Write};
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// Escapes everything written through it as the inside of a JSON string.
+struct JsonEscape<'a, W: ?Sized>(&'a mut W);
+
+impl<W: Write + ?Sized> Write for JsonEscape<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+        for (i, b) in s.bytes().enumerate() {
+            let escape = match b {
+                b'"' => "\\\"",
+                b'\\' => "\\\\",
+                b'\n' => "\\n",
+                b'\r' => "\\r",
+                b'\t' => "\\t",
+                0..0x20 => "",
+                _ => continue,
+            };
+            self.0.write_str(&s[start..i])?;
+            if escape.is_empty() {
+                write!(self.0, "\\u{b:04x}")?;
+            } else {
+                self.0.write_str(escape)?;
+            }
+            start = i + 1;
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+/// Write `value`'s `Display` output as a JSON string literal.
+pub(crate) fn write_json_str(
+    w: &mut (impl Write + ?Sized),
+    value: impl fmt::Display,
+) -> fmt::Result {
+    w.write_char('"')?;
+    write!(JsonEscape(w), "{value}")?;
+    w.write_char('"')
+}
+
+/// Records fields as the members of a JSON object, without the braces.
+pub(crate) struct JsonVisitor<'a, W: ?Sized> {
+    writer: &'a mut W,
+    /// Splits a field name into a prefix and name for its key.
+    key: fn(&'static str) -> (&'static str, &'static str),
+    first: bool,
+    result: fmt::Result,
+}
+
+impl<'a, W: Write + ?Sized> JsonVisitor<'a, W> {
+    /// `first` is whether no members have been written to the object yet.
+    pub(crate) fn new(writer: &'a mut W, first: bool) -> Self {
+        Self {
+            writer,
+            key: |name| ("", name),
+            first,
+            result: Ok(()),
+        }
+    }
+
+    fn with_key(self, key: fn(&'static str) -> (&'static str, &'static str)) -> Self {
+        Self { key, ..self }
+    }
+
+    pub(crate) fn finish(self) -> fmt::Result {
+        self.result
+    }
+
+    fn member(&mut self, field: &Field, value: impl FnOnce(&mut W) -> fmt::Result) {
+        if self.result.is_err() {
+            return;
+        }
+        self.result = (|| {
+            if !self.first {
+                self.writer.write_char(',')?;
+            }
+            self.first = false;
+            let (prefix, name) = (self.key)(field.name());
+            write_json_str(self.writer, format_args!("{prefix}{name}"))?;
+            self.writer.write_char(':')?;
+            value(self.writer)
+        })();
+    }
+}
+
+impl<W: Write + ?Sized> Visit for JsonVisitor<'_, W> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.member(field, |w| write!(w, "{value}"));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.member(field, |w| write!(w, "{value}"));
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.member(field, |w| write!(w, "{value}"));
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.member(field, |w| write!(w, "{value}"));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if value.is_finite() {
+            self.member(field, |w| write!(w, "{value}"));
+        } else {
+            self.member(field, |w| write_json_str(w, value));
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.member(field, |w| write!(w, "{value}"));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.member(field, |w| write_json_str(w, value));
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.member(field, |w| write_json_str(w, value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.member(field, |w| write_json_str(w, format_args!("{value:?}")));
+    }
+}
+
+/// Formats span fields as JSON object members, for use with [`Json`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonFields {
+    _private: (),
+}
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut visitor = JsonVisitor::new(&mut writer, true);
+        fields.record(&mut visitor);
+        visitor.finish()
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let first = current.fields.is_empty();
+        let mut writer = current.as_writer();
+        let mut visitor = JsonVisitor::new(&mut writer, first);
+        fields.record(&mut visitor);
+        visitor.finish()
+    }
+}
+
+/// Formats each event as a single-line JSON object:
+///
+/// ```json
+/// {"timestamp":"2024-01-01T00:00:00.000000Z","level":"INFO","fields":{"message":"hello","n":1},"target":"app","span":{"name":"request","id":7}}
+/// ```
+///
+/// Select it with [`SamplingLayerBuilder::json`](crate::SamplingLayerBuilder::json).
+/// Span fields are only included when spans are formatted with
+/// [`JsonFields`].
+#[derive(Clone, Debug)]
+pub struct Json<T = SystemTime> {
+    timer: T,
+    flatten_event: bool,
+    current_span: bool,
+    span_list: bool,
+}
+
+impl Default for Json {
+    fn default() -> Self {
+        Self {
+            timer: SystemTime,
+            flatten_event: false,
+            current_span: true,
+            span_list: false,
+        }
+    }
+}
+
+impl<T> Json<T> {
+    /// Write the event's fields at the top level of the object instead of
+    /// nested under `"fields"`.
+    pub fn flatten_event(self, flatten_event: bool) -> Self {
+        Self {
+            flatten_event,
+            ..self
+        }
+    }
+
+    /// Include the innermost span as `"span"`. Enabled by default.
+    pub fn with_current_span(self, current_span: bool) -> Self {
+        Self {
+            current_span,
+            ..self
+        }
+    }
+
+    /// Include every span in scope, outermost first, as `"spans"`.
+    pub fn with_span_list(self, span_list: bool) -> Self {
+        Self { span_list, ..self }
+    }
+
+    /// Use a custom timer for the `"timestamp"` member.
+    pub fn with_timer<T2>(self, timer: T2) -> Json<T2> {
+        Json {
+            timer,
+            flatten_event: self.flatten_event,
+            current_span: self.current_span,
+            span_list: self.span_list,
+        }
+    }
+
+    /// Leave out the `"timestamp"` member.
+    pub fn without_time(self) -> Json<()> {
+        self.with_timer(())
+    }
+}
+
+fn write_span<S, N>(w: &mut Writer<'_>, span: &SpanRef<'_, S>) -> fmt::Result
+where
+    S: for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    w.write_str("{\"name\":")?;
+    write_json_str(w, span.name())?;
+    let extensions = span.extensions();
+    if let Some(fields) = extensions.get::<FormattedFields<N>>()
+        && !fields.is_empty()
+    {
+        write!(w, ",{fields}")?;
+    }
+    w.write_char('}')
+}
+
+impl<S, N, T> FormatEvent<S, N> for Json<T>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    T: FormatTime,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        writer.write_char('{')?;
+        let mut timestamp = String::new();
+        if self
+            .timer
+            .format_time(&mut Writer::new(&mut timestamp))
+            .is_ok()
+            && !timestamp.is_empty()
+        {
+            writer.write_str("\"timestamp\":")?;
+            write_json_str(&mut writer, &timestamp)?;
+            writer.write_char(',')?;
+        }
+        writer.write_str("\"level\":")?;
+        write_json_str(&mut writer, meta.level())?;
+
+        if !self.flatten_event {
+            writer.write_str(",\"fields\":{")?;
+        }
+        let mut visitor = JsonVisitor::new(&mut writer, !self.flatten_event);
+        event.record(&mut visitor);
+        visitor.finish()?;
+        if !self.flatten_event {
+            writer.write_char('}')?;
+        }
+
+        writer.write_str(",\"target\":")?;
+        write_json_str(&mut writer, meta.target())?;
+
+        if self.current_span
+            && let Some(span) = ctx.event_scope().and_then(|mut scope| scope.next())
+        {
+            writer.write_str(",\"span\":")?;
+            write_span::<S, N>(&mut writer, &span)?;
+        }
+        if self.span_list
+            && let Some(scope) = ctx.event_scope()
+        {
+            writer.write_str(",\"spans\":[")?;
+            for (i, span) in scope.from_root().enumerate() {
+                if i > 0 {
+                    writer.write_char(',')?;
+                }
+                write_span::<S, N>(&mut writer, &span)?;
+            }
+            writer.write_char(']')?;
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+/// Records fields as logfmt `key=value` pairs, each preceded by a space.
+struct LogfmtVisitor<'a, 'w> {
+    writer: &'a mut Writer<'w>,
+    /// Reused to check whether a value needs quoting.
+    buf: String,
+    result: fmt::Result,
+}
+
+impl LogfmtVisitor<'_, '_> {
+    fn pair(&mut self, field: &Field, value: impl fmt::Display) {
+        if self.result.is_err() {
+            return;
+        }
+        let key = match field.name() {
+            "message" => "msg",
+            name => name,
+        };
+        self.buf.clear();
+        let _ = write!(self.buf, "{value}");
+        self.result = write!(self.writer, " {key}=")
+            .and_then(|()| write_logfmt_value(self.writer, &self.buf));
+    }
+}
+
+/// Write a logfmt value, quoting it if it is empty or contains spaces,
+/// quotes, `=` or control characters.
+fn write_logfmt_value(w: &mut impl Write, value: &str) -> fmt::Result {
+    let bare = !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c == ' ' || c == '"' || c == '=' || c.is_control());
+    if bare {
+        w.write_str(value)
+    } else {
+        write_json_str(w, value)
+    }
+}
+
+impl Visit for LogfmtVisitor<'_, '_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.pair(field, value);
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.pair(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.pair(field, format_args!("{value:?}"));
+    }
+}
+
+/// Formats each event as a line of logfmt `key=value` pairs, as expected by
+/// e.g. Heroku and Loki:
+///
+/// ```text
+/// time=2024-01-01T00:00:00.000000Z level=info target=app span=request msg="hello world" n=1
+/// ```
+///
+/// `span` lists the names of the spans in scope, outermost first, separated
+/// by `:`. Select it with
+/// [`SamplingLayerBuilder::logfmt`](crate::SamplingLayerBuilder::logfmt).
+#[derive(Clone, Debug, Default)]
+pub struct Logfmt<T = SystemTime> {
+    timer: T,
+}
+
+impl<T> Logfmt<T> {
+    /// Use a custom timer for the `time` key.
+    pub fn with_timer<T2>(self, timer: T2) -> Logfmt<T2> {
+        Logfmt { timer }
+    }
+
+    /// Leave out the `time` key.
+    pub fn without_time(self) -> Logfmt<()> {
+        self.with_timer(())
+    }
+}
+
+impl<S, N, T> FormatEvent<S, N> for Logfmt<T>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    T: FormatTime,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let mut timestamp = String::new();
+        if self
+            .timer
+            .format_time(&mut Writer::new(&mut timestamp))
+            .is_ok()
+            && !timestamp.is_empty()
+        {
+            writer.write_str("time=")?;
+            write_logfmt_value(&mut writer, &timestamp)?;
+            writer.write_char(' ')?;
+        }
+        write!(
+            writer,
+            "level={}",
+            meta.level().as_str().to_ascii_lowercase()
+        )?;
+        writer.write_str(" target=")?;
+        write_logfmt_value(&mut writer, meta.target())?;
+        if let Some(scope) = ctx.event_scope() {
+            let mut spans = String::new();
+            for span in scope.from_root() {
+                if !spans.is_empty() {
+                    spans.push(':');
+                }
+                spans.push_str(span.name());
+            }
+            writer.write_str(" span=")?;
+            write_logfmt_value(&mut writer, &spans)?;
+        }
+        let mut visitor = LogfmtVisitor {
+            writer: &mut writer,
+            buf: String::new(),
+            result: Ok(()),
+        };
+        event.record(&mut visitor);
+        visitor.result?;
+        writeln!(writer)
+    }
+}
+
+/// Formats each event as a [GELF] 1.1 message, for shipping straight to
+/// Graylog:
+///
+/// ```json
+/// {"version":"1.1","host":"web-1","short_message":"hello","timestamp":1704067200.000,"level":6,"_target":"app","_n":1}
+/// ```
+///
+/// The event's `message` becomes `short_message`, other fields are prefixed
+/// with `_`, and an `id` field, which GELF reserves, is written as `__id`.
+/// Levels map to syslog severities: error 3, warn 4, info 6, debug and
+/// trace 7.
+///
+/// Messages are terminated with a null byte, as GELF over TCP requires; use
+/// [`without_delimiter`](Self::without_delimiter) when each write is sent
+/// as its own UDP datagram. Select it with
+/// [`SamplingLayerBuilder::gelf`](crate::SamplingLayerBuilder::gelf).
+///
+/// [GELF]: https://go2docs.graylog.org/current/getting_in_log_data/gelf.html
+#[derive(Clone, Debug)]
+pub struct Gelf {
+    host: String,
+    delimiter: Option<char>,
+}
+
+impl Gelf {
+    /// Create a formatter reporting events as coming from `host`.
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            delimiter: Some('\0'),
+        }
+    }
+
+    /// Don't terminate messages with a null byte.
+    pub fn without_delimiter(self) -> Self {
+        Self {
+            delimiter: None,
+            ..self
+        }
+    }
+}
+
+/// The syslog severity for a level, as used by GELF and syslog itself.
+pub(crate) fn syslog_severity(level: &tracing::Level) -> u8 {
+    match *level {
+        tracing::Level::ERROR => 3,
+        tracing::Level::WARN => 4,
+        tracing::Level::INFO => 6,
+        tracing::Level::DEBUG | tracing::Level::TRACE => 7,
+    }
+}
+
+fn gelf_key(name: &'static str) -> (&'static str, &'static str) {
+    match name {
+        "message" => ("", "short_message"),
+        "id" => ("__", name),
+        _ => ("_", name),
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Gelf
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        writer.write_str("{\"version\":\"1.1\",\"host\":")?;
+        write_json_str(&mut writer, &self.host)?;
+        write!(
+            writer,
+            ",\"timestamp\":{}.{:03},\"level\":{}",
+            now.as_secs(),
+            now.subsec_millis(),
+            syslog_severity(meta.level()),
+        )?;
+        let mut has_message = false;
+        event.record(&mut |field: &Field, _: &dyn fmt::Debug| {
+            has_message |= field.name() == "message";
+        });
+        if !has_message {
+            // `short_message` is required.
+            writer.write_str(",\"short_message\":\"\"")?;
+        }
+        writer.write_str(",\"_target\":")?;
+        write_json_str(&mut writer, meta.target())?;
+        if let Some(file) = meta.file() {
+            writer.write_str(",\"_file\":")?;
+            write_json_str(&mut writer, file)?;
+        }
+        if let Some(line) = meta.line() {
+            write!(writer, ",\"_line\":{line}")?;
+        }
+        let mut visitor = JsonVisitor::new(&mut writer, false).with_key(gelf_key);
+        event.record(&mut visitor);
+        visitor.finish()?;
+        writer.write_char('}')?;
+        match self.delimiter {
+            Some(delimiter) => writer.write_char(delimiter),
+            None => Ok(()),
+        }
+    }
+}
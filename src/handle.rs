@@ -0,0 +1,149 @@
+use std::sync::Weak;
+use std::time::Duration;
+
+use crate::stats::{BudgetStats, Stats};
+
+/// Operations on a running layer that don't depend on its type parameters.
+pub(crate) trait Control: Send + Sync {
+    fn flush(&self);
+    fn try_flush(&self);
+    fn set_enabled(&self, enabled: bool);
+    fn set_bucket_duration(&self, duration: Duration);
+    fn stats(&self) -> &Stats;
+    #[cfg(feature = "debug-server")]
+    fn debug_json(&self) -> String;
+}
+
+/// A type-erased handle to a [`SamplingLayer`](crate::SamplingLayer).
+///
+/// Obtained from [`SamplingLayer::handle`](crate::SamplingLayer::handle), or
+/// from the active dispatcher without knowing the layer's type parameters:
+///
+/// ```
+/// use tracing_log_sample::Handle;
+///
+/// tracing::dispatcher::get_default(|dispatch| {
+///     if let Some(handle) = dispatch.downcast_ref::<Handle>() {
+///         handle.flush();
+///     }
+/// });
+/// ```
+///
+/// The handle does not keep the layer alive; once the layer is dropped, its
+/// methods do nothing.
+#[derive(Clone)]
+pub struct Handle {
+    pub(crate) inner: Weak<dyn Control>,
+}
+
+impl Handle {
+    /// Drain all reservoirs and write their contents immediately, starting a
+    /// new bucket.
+    pub fn flush(&self) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.flush();
+        }
+    }
+
+    /// Pause sampling while `enabled` is false, writing every matching event
+    /// immediately as an ordinary fmt layer would, e.g. while debugging an
+    /// incident. Pausing first flushes whatever is buffered.
+    pub fn set_enabled(&self, enabled: bool) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.set_enabled(enabled);
+        }
+    }
+
+    /// Change the [bucket duration](crate::SamplingLayerBuilder::bucket_duration),
+    /// trading smoothing against latency, and resize every budget's
+    /// reservoir for its per-second limit. Whatever is buffered is written
+    /// first and a new bucket starts.
+    ///
+    /// The duration is capped by
+    /// [`max_delay`](crate::SamplingLayerBuilder::max_delay) and a zero
+    /// duration is ignored. Each reservoir is capped at 2^20 events, as
+    /// [`try_build`](crate::SamplingLayerBuilder::try_build) requires, so a
+    /// long bucket may keep less than its limit. [`BudgetInfo`](crate::BudgetInfo) keeps reporting
+    /// the capacity the layer was built with, while
+    /// [`BudgetStats`] reports the current one.
+    pub fn set_bucket_duration(&self, duration: Duration) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.set_bucket_duration(duration);
+        }
+    }
+
+    /// Counters for the budget added with
+    /// [`budget_named`](crate::SamplingLayerBuilder::budget_named) under
+    /// `name`.
+    ///
+    /// Returns `None` if there is no such budget or the layer has been
+    /// dropped.
+    pub fn budget(&self, name: &str) -> Option<BudgetStats> {
+        self.inner.upgrade()?.stats().budget_named(name)
+    }
+
+    /// The layer's current configuration, reservoir fill levels and counters
+    /// as a JSON object, for serving from a debug endpoint.
+    ///
+    /// Returns `None` once the layer has been dropped.
+    #[cfg(feature = "debug-server")]
+    pub fn debug_json(&self) -> Option<String> {
+        Some(self.inner.upgrade()?.debug_json())
+    }
+
+    /// Flush whenever the process receives one of `signals`, e.g. `SIGUSR1`,
+    /// so operators can dump the currently buffered samples on demand.
+    ///
+    /// Signals are handled on a dedicated thread, which exits on the first
+    /// signal received after the layer has been dropped.
+    #[cfg(all(unix, feature = "signal"))]
+    pub fn flush_on_signal<I>(&self, signals: I) -> std::io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: std::borrow::Borrow<std::ffi::c_int>,
+    {
+        let mut signals = signal_hook::iterator::Signals::new(signals)?;
+        let handle = self.clone();
+        std::thread::Builder::new()
+            .name("tracing-log-sample-signal".into())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    let Some(inner) = handle.inner.upgrade() else {
+                        break;
+                    };
+                    inner.flush();
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// Flushes the layer when dropped.
+///
+/// Returned by
+/// [`SamplingLayerBuilder::build_with_guard`](crate::SamplingLayerBuilder::build_with_guard).
+/// A layer installed with `set_global_default` is never dropped, so keep the
+/// guard alive until the end of `main` to write out whatever is still buffered
+/// on exit.
+#[must_use = "dropping the guard immediately flushes the layer"]
+pub struct SamplingGuard {
+    pub(crate) handle: Handle,
+}
+
+impl Drop for SamplingGuard {
+    fn drop(&mut self) {
+        self.handle.flush();
+    }
+}
+
+/// Chain a panic hook that flushes the layer before running the previously
+/// installed hook.
+pub(crate) fn flush_on_panic(handle: Handle) {
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if let Some(inner) = handle.inner.upgrade() {
+            inner.try_flush();
+        }
+        prev(info);
+    }));
+}
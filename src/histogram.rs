@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// One bucket per power of two nanoseconds.
+const BUCKETS: usize = 64;
+
+fn bucket(nanos: u64) -> usize {
+    (u64::BITS - nanos.leading_zeros()) as usize % BUCKETS
+}
+
+/// A lock-free log-scale latency histogram.
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of how long writes to the output writer have taken.
+///
+/// Returned by [`Stats::write_latency`](crate::Stats::write_latency). Each
+/// sample is one batch of events written with a single writer. Samples are
+/// grouped into power-of-two buckets, so percentiles are accurate to within a
+/// factor of two.
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKETS],
+    sum: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    /// Number of writes recorded.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The longest write recorded.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// The mean write duration, or zero if nothing was recorded.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos(self.sum / count),
+        }
+    }
+
+    /// An upper bound on the write duration at quantile `q` (between 0 and 1),
+    /// e.g. `0.99` for the 99th percentile.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let upper = if i == 0 { 0 } else { (1u64 << i) - 1 };
+                return Duration::from_nanos(upper.min(self.max));
+            }
+        }
+        self.max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantiles_bound_samples() {
+        let histogram = Histogram::default();
+        for micros in 1..=100 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 100);
+        assert_eq!(snapshot.max(), Duration::from_micros(100));
+        let p50 = snapshot.quantile(0.5);
+        assert!(p50 >= Duration::from_micros(50) && p50 < Duration::from_micros(100));
+        assert_eq!(snapshot.quantile(1.0), Duration::from_micros(100));
+        assert_eq!(
+            LatencyHistogram {
+                buckets: [0; BUCKETS],
+                sum: 0,
+                max: 0
+            }
+            .quantile(0.5),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use std::fmt::{self, Write as _};
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::format::syslog_severity;
+
+/// Where journald listens for native protocol entries.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Formats each event as a journal entry for [`JournaldWriter`]:
+/// `PRIORITY` is derived from the level as for syslog, the event's message
+/// becomes `MESSAGE`, and other fields become journal fields with their
+/// names upper-cased. `TARGET`, `CODE_FILE`, `CODE_LINE` and
+/// `SYSLOG_IDENTIFIER` are also set.
+///
+/// The output is an intermediate encoding only [`JournaldWriter`]
+/// understands; select both with
+/// [`SamplingLayerBuilder::journald`](crate::SamplingLayerBuilder::journald).
+#[derive(Clone, Debug)]
+pub struct Journald {
+    identifier: Option<String>,
+}
+
+impl Default for Journald {
+    fn default() -> Self {
+        let identifier = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_name()?.to_str()?.to_owned()));
+        Self { identifier }
+    }
+}
+
+impl Journald {
+    /// Set `SYSLOG_IDENTIFIER`. Defaults to the executable's file name.
+    pub fn identifier(mut self, identifier: impl Into<String>) -> Self {
+        self.identifier = Some(identifier.into());
+        self
+    }
+}
+
+/// Write one `KEY=value` line, escaping backslashes and newlines in the
+/// value so entries can be split apart again by [`JournaldWriter`].
+fn write_field(w: &mut impl fmt::Write, key: &str, value: impl fmt::Display) -> fmt::Result {
+    write!(w, "{key}=")?;
+    write!(Escape(w), "{value}")?;
+    w.write_char('\n')
+}
+
+struct Escape<'a, W>(&'a mut W);
+
+impl<W: fmt::Write> fmt::Write for Escape<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_str("\\n")?;
+            }
+            for (j, chunk) in part.split('\\').enumerate() {
+                if j > 0 {
+                    self.0.write_str("\\\\")?;
+                }
+                self.0.write_str(chunk)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Journal field names are upper-case ASCII letters, digits and
+/// underscores, and can't start with an underscore or a digit.
+fn write_field_name(w: &mut String, name: &str) {
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        w.push_str("F_");
+    }
+    w.extend(name.chars().map(|c| match c {
+        'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+        _ => '_',
+    }));
+}
+
+struct JournaldVisitor<'a, 'w> {
+    writer: &'a mut Writer<'w>,
+    name: String,
+    result: fmt::Result,
+}
+
+impl Visit for JournaldVisitor<'_, '_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.result.is_err() {
+            return;
+        }
+        self.name.clear();
+        match field.name() {
+            "message" => self.name.push_str("MESSAGE"),
+            name => write_field_name(&mut self.name, name),
+        }
+        self.result = write_field(self.writer, &self.name, format_args!("{value:?}"));
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for Journald
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        write_field(&mut writer, "PRIORITY", syslog_severity(meta.level()))?;
+        if let Some(identifier) = &self.identifier {
+            write_field(&mut writer, "SYSLOG_IDENTIFIER", identifier)?;
+        }
+        write_field(&mut writer, "TARGET", meta.target())?;
+        if let Some(file) = meta.file() {
+            write_field(&mut writer, "CODE_FILE", file)?;
+        }
+        if let Some(line) = meta.line() {
+            write_field(&mut writer, "CODE_LINE", line)?;
+        }
+        let mut visitor = JournaldVisitor {
+            writer: &mut writer,
+            name: String::new(),
+            result: Ok(()),
+        };
+        event.record(&mut visitor);
+        visitor.result?;
+        // A blank line ends the entry.
+        writer.write_char('\n')
+    }
+}
+
+/// A [`MakeWriter`] sending entries formatted by [`Journald`] to journald
+/// over its native protocol, one datagram per entry.
+///
+/// Values containing newlines are sent in the protocol's binary form, so
+/// multi-line messages survive intact. Entries too large for a single
+/// datagram are dropped, as are send errors.
+pub struct JournaldWriter {
+    socket: UnixDatagram,
+}
+
+impl JournaldWriter {
+    /// Connect to the system journal.
+    pub fn new() -> io::Result<Self> {
+        Self::with_path(JOURNALD_SOCKET)
+    }
+
+    /// Connect to a journal socket at `path`.
+    pub fn with_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self { socket })
+    }
+
+    /// Re-encode one entry from [`Journald`]'s escaped lines into the native
+    /// protocol and send it.
+    fn send_entry(&self, entry: &[u8], datagram: &mut Vec<u8>) {
+        datagram.clear();
+        let mut value = Vec::new();
+        for line in entry.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            let Some(eq) = line.iter().position(|&b| b == b'=') else {
+                continue;
+            };
+            let (key, escaped) = (&line[..eq], &line[eq + 1..]);
+            value.clear();
+            let mut bytes = escaped.iter();
+            while let Some(&b) = bytes.next() {
+                match (b, bytes.clone().next()) {
+                    (b'\\', Some(b'n')) => {
+                        value.push(b'\n');
+                        bytes.next();
+                    }
+                    (b'\\', Some(b'\\')) => {
+                        value.push(b'\\');
+                        bytes.next();
+                    }
+                    _ => value.push(b),
+                }
+            }
+            datagram.extend_from_slice(key);
+            if value.contains(&b'\n') {
+                datagram.push(b'\n');
+                datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                datagram.push(b'=');
+            }
+            datagram.extend_from_slice(&value);
+            datagram.push(b'\n');
+        }
+        if !datagram.is_empty() {
+            let _ = self.socket.send(datagram);
+        }
+    }
+}
+
+/// Writer returned by [`JournaldWriter`].
+pub struct JournaldEntries<'a> {
+    writer: &'a JournaldWriter,
+    /// Bytes of an entry not yet ended by a blank line.
+    partial: Vec<u8>,
+    datagram: Vec<u8>,
+}
+
+impl io::Write for JournaldEntries<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        let mut start = 0;
+        while let Some(end) = self.partial[start..]
+            .windows(2)
+            .position(|pair| pair == b"\n\n")
+        {
+            let end = start + end + 2;
+            self.writer
+                .send_entry(&self.partial[start..end], &mut self.datagram);
+            start = end;
+        }
+        self.partial.drain(..start);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for JournaldEntries<'_> {
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            self.writer.send_entry(&self.partial, &mut self.datagram);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for JournaldWriter {
+    type Writer = JournaldEntries<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        JournaldEntries {
+            writer: self,
+            partial: Vec::new(),
+            datagram: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use tracing::{Event, Level, Metadata};
+
+use crate::consistent::SampleKey;
+use crate::reservoir::{Cost, Reservoir, UNIT, Weighting};
+use crate::sink::Buffered;
+
+/// Reservoirs for each value of a field, for a
+/// [`keyed`](crate::Budget::keyed) budget. Events without the field go to
+/// the budget's own reservoir.
+pub(crate) struct KeyedReservoirs {
+    field: SampleKey,
+    max_keys: usize,
+    max_bytes: Option<usize>,
+    /// By a hash of the field's value, with the tick each was last used.
+    reservoirs: HashMap<u64, (Reservoir<Buffered>, u64)>,
+    tick: u64,
+}
+
+impl KeyedReservoirs {
+    pub(crate) fn new(field: &'static str, max_keys: usize, max_bytes: Option<usize>) -> Self {
+        Self {
+            field: SampleKey::Field(field),
+            max_keys: max_keys.max(1),
+            max_bytes,
+            reservoirs: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// The reservoir for the event's key, or `own` if it has none.
+    ///
+    /// A new key past `max_keys` evicts the least recently used one, whose
+    /// held events are pushed to `evicted`.
+    pub(crate) fn reservoir<'a>(
+        &'a mut self,
+        meta: &Metadata<'_>,
+        event: Option<&Event<'_>>,
+        own: &'a mut Reservoir<Buffered>,
+        weighting: Weighting,
+        cost: Cost,
+        evicted: &mut Vec<Buffered>,
+    ) -> &'a mut Reservoir<Buffered> {
+        let Some(hash) = self.field.hash(meta, event) else {
+            return own;
+        };
+        let key = hash.to_bits();
+        self.tick += 1;
+        if !self.reservoirs.contains_key(&key) && self.reservoirs.len() >= self.max_keys {
+            let lru = (self.reservoirs.iter())
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(&key, _)| key);
+            if let Some((mut reservoir, _)) = lru.and_then(|lru| self.reservoirs.remove(&lru)) {
+                evicted.extend(reservoir.drain());
+            }
+        }
+        let capacity = own.capacity();
+        let (reservoir, used) = self.reservoirs.entry(key).or_insert_with(|| {
+            let reservoir = Reservoir::growable(capacity, weighting, cost, self.max_bytes);
+            (reservoir, 0)
+        });
+        *used = self.tick;
+        reservoir
+    }
+
+    /// Events offered to the keys' reservoirs since the last drain.
+    pub(crate) fn seen(&self) -> usize {
+        (self.reservoirs.values())
+            .map(|(reservoir, _)| reservoir.seen())
+            .sum()
+    }
+
+    /// Drain `own` and every key's reservoir, keeping at most `capacity`
+    /// between them, in events or bytes as `cost` says. Keys take turns,
+    /// quietest first, so those needing less than an even share keep
+    /// everything and the rest split what's left evenly. Events over
+    /// capacity are pushed to `dropped`, and keys that saw nothing are
+    /// forgotten.
+    pub(crate) fn drain(
+        &mut self,
+        own: &mut Reservoir<Buffered>,
+        capacity: usize,
+        cost: Cost,
+        kept: &mut Vec<Buffered>,
+        dropped: &mut Vec<Buffered>,
+    ) {
+        self.reservoirs
+            .retain(|_, (reservoir, _)| reservoir.seen() > 0);
+        let units = |event: &Buffered| {
+            let level = event.meta.map_or(&Level::INFO, |meta| meta.level());
+            cost.units(level, event.bytes.len())
+        };
+        let mut groups: Vec<(u64, Vec<Buffered>)> = std::iter::once(own)
+            .chain(self.reservoirs.values_mut().map(|(reservoir, _)| reservoir))
+            .map(|reservoir| {
+                let mut events: Vec<Buffered> = reservoir.drain().collect();
+                // Events already written are kept whatever the cost.
+                fastrand::shuffle(&mut events);
+                events.sort_by_key(|event| event.written);
+                (events.iter().map(units).sum(), events)
+            })
+            .collect();
+        groups.sort_by_key(|&(total, _)| total);
+        let per_unit = if cost == Cost::Bytes { 1 } else { UNIT };
+        let mut left = (capacity as u64).saturating_mul(per_unit);
+        while !groups.is_empty() {
+            groups.retain_mut(|(_, events)| {
+                let Some(event) = events.pop() else {
+                    return false;
+                };
+                let units = units(&event);
+                if event.written || units <= left {
+                    left = left.saturating_sub(units);
+                    kept.push(event);
+                } else {
+                    dropped.push(event);
+                }
+                true
+            });
+        }
+    }
+
+    /// Resize the keys' emptied reservoirs for a new per-bucket `capacity`.
+    pub(crate) fn resize(&mut self, capacity: usize, weighting: Weighting, cost: Cost) {
+        for (reservoir, _) in self.reservoirs.values_mut() {
+            *reservoir = Reservoir::growable(capacity, weighting, cost, self.max_bytes);
+        }
+    }
+}
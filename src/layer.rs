@@ -1,121 +1,336 @@
+use std::any::TypeId;
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::io::{self, Write};
 use std::marker::PhantomData;
-use std::sync::Mutex;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 
+use thread_local::ThreadLocal;
+use tracing::dispatcher::WeakDispatch;
 use tracing::subscriber::Interest;
-use tracing::{Event, Metadata, Subscriber};
+use tracing::{Dispatch, Event, Level, Metadata, Subscriber, span};
 use tracing_subscriber::Layer;
-use tracing_subscriber::filter::EnvFilter;
+use tracing_subscriber::filter::{Filtered, LevelFilter};
 use tracing_subscriber::fmt::format::{DefaultFields, Format, Full};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::fmt::{self, FormatFields, MakeWriter};
-use tracing_subscriber::layer::Context;
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::layer::{Context, Filter};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
 
+use crate::adaptive::{self, Adaptive, Backpressure, Burst};
 use crate::capture::{CaptureMakeWriter, return_captured, take_captured};
-use crate::reservoir::Reservoir;
+use crate::consistent::{self, SampleKey, SpanHashes};
+use crate::error::MAX_CAPACITY;
+use crate::filter::BudgetFilter;
+use crate::handle::{Control, Handle};
+use crate::keyed::KeyedReservoirs;
+use crate::quota::QuotaProvider;
+use crate::recent::RecentEvents;
+use crate::reemit::{Fields, capture, re_emitting};
+use crate::reservoir::{
+    Cost, Reservoir, UNIT, Weighting, decayed_key, field_weight, random, weighted_key,
+};
+use crate::sink::{Batch, Buffered, Sink};
+use crate::stats::Stats;
+use crate::summary::{SummaryConfig, level_index, render_stats};
+
+/// A budget's [`hard_limit`](crate::Budget::hard_limit), checked before
+/// events are formatted.
+pub(crate) struct HardLimit {
+    pub(crate) per_second: u64,
+    /// The limit for the current bucket.
+    pub(crate) capacity: AtomicUsize,
+    /// Events matched so far in the current bucket.
+    pub(crate) seen: AtomicUsize,
+}
+
+impl HardLimit {
+    pub(crate) fn reset(&self, bucket: Duration) {
+        let capacity = (self.per_second as f64 * bucket.as_secs_f64()).ceil() as usize;
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.seen.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Events each budget has been offered from within a root span, by layer,
+/// for [`per_root_span`](crate::Budget::per_root_span).
+#[derive(Default)]
+struct RootSpanCounts(HashMap<usize, Vec<usize>>);
 
 pub(crate) struct State {
     pub(crate) bucket_start: Instant,
+    /// Kept with the reservoirs, whose capacities follow from it, so both
+    /// change together in [`set_bucket_duration`](Shared::set_bucket_duration).
+    pub(crate) bucket_duration: Duration,
     pub(crate) seq: u64,
-    pub(crate) reservoirs: Vec<Reservoir<(u64, Vec<u8>)>>,
-    pub(crate) pending: std::vec::IntoIter<(u64, Vec<u8>)>,
+    pub(crate) reservoirs: Vec<Reservoir<Buffered>>,
+    pub(crate) pending: std::vec::IntoIter<Buffered>,
+    /// How many events at the front of `pending` were carried over from an
+    /// earlier bucket by [`max_release`](Shared::max_release).
+    pub(crate) pending_carried: usize,
     pub(crate) last_release: Instant,
+    /// Events dropped in the current bucket, indexed by [`level_index`].
+    pub(crate) bucket_dropped: [u64; 5],
+    /// Events offered to the reservoirs in the current bucket.
+    pub(crate) bucket_received: u64,
+    /// Events written unsampled by each budget's
+    /// [`head`](crate::Budget::head) in the current bucket.
+    pub(crate) head_written: Vec<usize>,
+    /// How many events each budget writes directly in the current bucket:
+    /// its head, plus its limit while in [`passthrough`](crate::Budget::passthrough).
+    pub(crate) head_limit: Vec<usize>,
+    /// Set by [`target_rate`](crate::SamplingLayerBuilder::target_rate).
+    pub(crate) adaptive: Option<Adaptive>,
+    /// Set by [`backpressure`](crate::SamplingLayerBuilder::backpressure).
+    pub(crate) backpressure: Option<Backpressure>,
+    /// Indexed by budget.
+    pub(crate) bursts: Vec<Option<Burst>>,
+    /// Indexed by budget.
+    pub(crate) keyed: Vec<Option<KeyedReservoirs>>,
+    pub(crate) last_report: Instant,
 }
 
-/// Shared handle for reading layer event counters.
+/// How a budget was configured.
 ///
-/// Returned by [`SamplingLayerBuilder::build`](crate::SamplingLayerBuilder::build).
-/// All counts are cumulative since layer creation.
-#[derive(Clone)]
-pub struct Stats {
-    received: std::sync::Arc<AtomicU64>,
-    sampled: std::sync::Arc<AtomicU64>,
-    dropped: std::sync::Arc<AtomicU64>,
+/// Returned by [`SamplingLayer::budgets`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BudgetInfo {
+    /// The name given to
+    /// [`budget_named`](crate::SamplingLayerBuilder::budget_named), if any.
+    pub name: Option<String>,
+    /// The budget's filter, in `EnvFilter` directive syntax. Level range
+    /// budgets are shown as `verbose..=severe`, e.g. `warn..=error`, and
+    /// exclusions as `include except exclude`.
+    pub filter: String,
+    /// The configured per-second event limit, or byte limit for budgets set
+    /// with [`limit_bytes`](crate::Budget::limit_bytes).
+    pub limit_per_second: u64,
+    /// Maximum events, or bytes, the reservoir held in the first bucket,
+    /// with any [`ramp_up`](crate::Budget::ramp_up) and
+    /// [`burst`](crate::Budget::burst) applied, as
+    /// [`BudgetStats`](crate::BudgetStats) reports it then. Zero for budgets
+    /// set with [`fraction`](crate::Budget::fraction).
+    pub capacity: usize,
+    /// Whether events ejected from this budget are offered to later matching
+    /// budgets. See [`Budget::no_cascade`](crate::Budget::no_cascade).
+    pub cascade: bool,
 }
 
-impl Stats {
-    pub(crate) fn new() -> Self {
-        Self {
-            received: std::sync::Arc::new(AtomicU64::new(0)),
-            sampled: std::sync::Arc::new(AtomicU64::new(0)),
-            dropped: std::sync::Arc::new(AtomicU64::new(0)),
-        }
-    }
-
-    /// Events that matched at least one filter.
-    pub fn received(&self) -> u64 {
-        self.received.load(Ordering::Relaxed)
-    }
-
-    /// Events that were kept in a reservoir.
-    pub fn sampled(&self) -> u64 {
-        self.sampled.load(Ordering::Relaxed)
-    }
-
-    /// Events that were dropped after failing to enter any reservoir.
-    pub fn dropped(&self) -> u64 {
-        self.dropped.load(Ordering::Relaxed)
-    }
+/// How an event is emitted, as decided by the function given to
+/// [`priority`](crate::SamplingLayerBuilder::priority).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Priority {
+    /// Sampled and smeared as usual.
+    #[default]
+    Sampled,
+    /// Written as soon as it is emitted, without sampling.
+    Immediate,
 }
 
-/// A [`tracing_subscriber::Layer`] that samples events into time-bucketed reservoirs.
+/// When events sampled by a budget are written.
 ///
-/// Uses `tracing_subscriber::fmt::Layer` internally for event formatting.
-/// Sampled events are smeared across the bucket duration to reduce tail-latency
-/// spikes from burst writes.
-///
-/// Construct via [`SamplingLayer::builder()`](crate::SamplingLayerBuilder).
-pub struct SamplingLayer<
-    S,
-    N = DefaultFields,
-    E = Format<Full>,
-    W: for<'a> MakeWriter<'a> = fn() -> io::Stderr,
-> {
-    pub(crate) filters: Vec<EnvFilter>,
+/// Set for every budget with
+/// [`SamplingLayerBuilder::emit`](crate::SamplingLayerBuilder::emit), or for
+/// one with [`Budget::emit`](crate::Budget::emit).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Emit {
+    /// As soon as the event enters the reservoir. An event written this way
+    /// may later be ejected to make room for another, which is then written
+    /// too, so a busy budget writes more than its limit: about
+    /// `limit * (1 + ln(received / limit))` events per bucket.
+    Immediate,
+    /// All at once when the bucket ends.
+    AtRotation,
+    /// Spread evenly over the bucket after the one they were sampled in.
+    #[default]
+    Smeared,
+}
+
+pub(crate) type PriorityFn = Box<dyn Fn(&Metadata<'_>, &Event<'_>) -> Priority + Send + Sync>;
+
+/// State shared between the layer and any background flusher.
+pub(crate) struct Shared<W> {
     pub(crate) state: Mutex<State>,
-    pub(crate) bucket_duration: Duration,
-    pub(crate) writer: W,
-    pub(crate) fmt_layer: fmt::Layer<S, N, E, CaptureMakeWriter>,
+    /// Most smeared events released by a single tick.
+    pub(crate) max_release: usize,
+    /// Roughly how long writing a single tick's release may take, going by
+    /// the measured cost of recent writes.
+    pub(crate) max_release_time: Option<Duration>,
+    /// Bound on how long a sampled event may wait to be written, already
+    /// no shorter than the bucket duration.
+    pub(crate) max_delay: Option<Duration>,
+    pub(crate) budgets: Vec<BudgetInfo>,
+    /// Indexed by budget.
+    pub(crate) emit: Vec<Emit>,
+    /// Indexed by budget.
+    pub(crate) weighting: Vec<Weighting>,
+    /// Indexed by budget.
+    pub(crate) cost: Vec<Cost>,
+    /// Indexed by budget.
+    pub(crate) sample_keys: Vec<Option<SampleKey>>,
+    /// Indexed by budget.
+    pub(crate) head: Vec<usize>,
+    /// Indexed by budget.
+    pub(crate) passthrough: Vec<bool>,
+    /// Indexed by budget.
+    pub(crate) quotas: Vec<Option<Box<dyn QuotaProvider>>>,
+    /// Indexed by budget.
+    pub(crate) ramp_up: Vec<Option<Duration>>,
+    /// When the layer was built, for [`ramp_up`](crate::Budget::ramp_up).
+    pub(crate) started: Instant,
+    /// Indexed by budget.
+    pub(crate) hard: Vec<Option<HardLimit>>,
+    /// Indexed by budget.
+    pub(crate) per_root_span: Vec<Option<usize>>,
+    /// Set by [`global_limit`](crate::SamplingLayerBuilder::global_limit),
+    /// per second.
+    pub(crate) global_limit: Option<(u64, Cost)>,
+    /// Indexed by budget; always a later budget.
+    pub(crate) parents: Vec<Option<usize>>,
+    /// Cleared by [`set_enabled`](Handle::set_enabled) to write every
+    /// matching event immediately.
+    pub(crate) enabled: AtomicBool,
+    pub(crate) sink: Sink<W>,
     pub(crate) stats: Stats,
-    pub(crate) _subscriber: PhantomData<fn(S)>,
+    pub(crate) summary: Option<SummaryConfig>,
+    pub(crate) report_every: Option<Duration>,
+    pub(crate) recent: Option<RecentEvents>,
 }
 
-impl<S, N, E, W: for<'a> MakeWriter<'a>> SamplingLayer<S, N, E, W> {
-    fn drain_all(state: &mut State) -> Vec<(u64, Vec<u8>)> {
-        let mut events: Vec<_> = state
-            .reservoirs
-            .iter_mut()
-            .flat_map(|r| r.drain())
-            .collect();
-        events.sort_unstable_by_key(|(seq, _)| *seq);
-        events
+impl State {
+    /// Events offered to budget `i`'s reservoirs since the last drain.
+    fn seen(&self, i: usize) -> usize {
+        self.reservoirs[i].seen() + self.keyed[i].as_ref().map_or(0, KeyedReservoirs::seen)
     }
+}
 
-    #[cold]
-    fn write_events(&self, events: &[(u64, Vec<u8>)]) {
-        if events.is_empty() {
-            return;
+impl<W: for<'a> MakeWriter<'a>> Shared<W> {
+    fn drain_all(&self, state: &mut State) -> Batch {
+        let capacities = self.next_capacities(state);
+        let mut events = Vec::new();
+        let mut dropped = Vec::new();
+        let mut drained_from = Vec::with_capacity(capacities.len());
+        let seen: Vec<usize> = (0..capacities.len()).map(|i| state.seen(i)).collect();
+        let budgets = state.reservoirs.iter_mut().zip(&*self.stats.budgets);
+        for (i, (reservoir, counters)) in budgets.enumerate() {
+            drained_from.push(reservoir.capacity());
+            let quiet = seen[i] + state.head_written[i] <= reservoir.capacity();
+            let (before, dropped_before) = (events.len(), dropped.len());
+            match &mut state.keyed[i] {
+                Some(keyed) => {
+                    let capacity = reservoir.capacity();
+                    keyed.drain(reservoir, capacity, self.cost[i], &mut events, &mut dropped);
+                }
+                None => events.extend(reservoir.drain()),
+            }
+            for event in (events[before..].iter_mut()).chain(&mut dropped[dropped_before..]) {
+                event.budget = Some(i);
+            }
+            self.resize(i, reservoir, capacities[i]);
+            if let Some(keyed) = &mut state.keyed[i] {
+                keyed.resize(reservoir.capacity(), self.weighting[i], self.cost[i]);
+            }
+            // Pass the next bucket through if this one stayed within the limit.
+            state.head_limit[i] = self.head[i]
+                + if self.passthrough[i] && quiet {
+                    reservoir.capacity()
+                } else {
+                    0
+                };
+            counters.fill.store(0, Ordering::Relaxed);
+            (counters.last_bucket_received).store(seen[i] as u64, Ordering::Relaxed);
+        }
+        for event in &dropped {
+            self.record_drop(state, event);
+        }
+        // Children come before their parents, so inner subtrees are trimmed
+        // first.
+        for (parent, &capacity) in drained_from.iter().enumerate() {
+            if self.parents.contains(&Some(parent)) {
+                let in_subtree = |event: &Buffered| {
+                    let mut budget = event.budget;
+                    while let Some(i) = budget {
+                        if i == parent {
+                            return true;
+                        }
+                        budget = self.parents[i];
+                    }
+                    false
+                };
+                self.trim(
+                    state,
+                    &mut events,
+                    capacity as u64,
+                    self.cost[parent],
+                    in_subtree,
+                );
+            }
+        }
+        if let Some((limit, cost)) = self.global_limit {
+            let capacity = (limit as f64 * state.bucket_duration.as_secs_f64()).ceil() as u64;
+            self.trim(state, &mut events, capacity, cost, |_| true);
+        }
+        let mut drained = vec![0; state.reservoirs.len()];
+        for i in events.iter().filter_map(|event| event.budget) {
+            drained[i] += 1;
+        }
+        for (counters, drained) in self.stats.budgets.iter().zip(drained) {
+            counters.sampled.fetch_add(drained, Ordering::Relaxed);
+            counters
+                .last_bucket_sampled
+                .store(drained, Ordering::Relaxed);
         }
-        let mut writer = self.writer.make_writer();
-        for (_, buf) in events {
-            let _ = writer.write_all(buf);
+        state.head_written.fill(0);
+        for hard in self.hard.iter().flatten() {
+            hard.reset(state.bucket_duration);
         }
+        events.sort_unstable_by_key(|event| event.seq);
+        self.stats.record_bucket(
+            std::mem::take(&mut state.bucket_received),
+            events.len() as u64,
+        );
+        self.stats
+            .record_sampled(events.iter().filter_map(|event| event.meta));
+        events.retain(|event| !event.written);
+        events
     }
 
-    fn smear_collect(
-        state: &mut State,
-        now: Instant,
-        bucket_duration: Duration,
-    ) -> Vec<(u64, Vec<u8>)> {
+    /// Drain the reservoirs at the end of a bucket, split into events to
+    /// write now and events to smear.
+    fn drain_rotated(&self, state: &mut State) -> (Batch, Batch) {
+        self.drain_all(state).into_iter().partition(|event| {
+            event
+                .budget
+                .is_some_and(|i| self.emit[i] == Emit::AtRotation)
+        })
+    }
+
+    #[inline]
+    pub(crate) fn write_events(&self, events: Batch, block: bool) {
+        if let Some(recent) = &self.recent {
+            recent.record(&events);
+        }
+        self.sink.write(events, block, &self.stats);
+    }
+
+    fn smear_collect(&self, state: &mut State, now: Instant) -> Batch {
         let n = state.pending.len();
         if n == 0 {
             return Vec::new();
         }
 
-        let bucket_end = state.bucket_start + bucket_duration;
-        let remaining = bucket_end.saturating_duration_since(now);
+        let smear_end = state.bucket_start + self.smear_window(state);
+        let remaining = smear_end.saturating_duration_since(now);
+        if remaining.is_zero() && self.max_delay.is_some() {
+            state.pending_carried = 0;
+            state.last_release = now;
+            return state.pending.by_ref().collect();
+        }
         let to_release = if remaining.is_zero() {
             n
         } else {
@@ -128,8 +343,10 @@ impl<S, N, E, W: for<'a> MakeWriter<'a>> SamplingLayer<S, N, E, W> {
             }
         };
 
+        let to_release = to_release.min(self.release_limit());
         if to_release > 0 {
             let batch: Vec<_> = state.pending.by_ref().take(to_release).collect();
+            state.pending_carried = state.pending_carried.saturating_sub(batch.len());
             state.last_release = now;
             batch
         } else {
@@ -137,85 +354,655 @@ impl<S, N, E, W: for<'a> MakeWriter<'a>> SamplingLayer<S, N, E, W> {
         }
     }
 
+    /// How long after a bucket starts the previous bucket's events must all
+    /// be released.
+    fn smear_window(&self, state: &State) -> Duration {
+        let bucket = state.bucket_duration;
+        match self.max_delay {
+            Some(max_delay) => max_delay.saturating_sub(bucket).min(bucket),
+            None => bucket,
+        }
+    }
+
+    /// Most smeared events to release in one tick.
+    fn release_limit(&self) -> usize {
+        let Some(budget) = self.max_release_time else {
+            return self.max_release;
+        };
+        match self.stats.write_cost.load(Ordering::Relaxed) {
+            0 => self.max_release,
+            cost => {
+                let affordable = (budget.as_nanos() / u128::from(cost)).max(1);
+                self.max_release
+                    .min(affordable.try_into().unwrap_or(usize::MAX))
+            }
+        }
+    }
+
+    /// Count a drained event as dropped after all, rather than sampled as
+    /// it was when it entered its reservoir.
+    fn record_drop(&self, state: &mut State, event: &Buffered) {
+        let Some(meta) = event.meta else {
+            return;
+        };
+        self.stats.sampled.fetch_sub(1, Ordering::Relaxed);
+        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        if let Some(i) = event.budget {
+            (self.stats.budgets[i].dropped).fetch_add(1, Ordering::Relaxed);
+        }
+        state.bucket_dropped[level_index(meta.level())] += 1;
+        self.stats.record_dropped(meta);
+    }
+
+    /// Drop drained events for which `in_scope` is true at random until
+    /// they fit `capacity`, in events or bytes as `cost` says, for a
+    /// [`parent`](crate::Budget::parent) budget or the
+    /// [`global_limit`](crate::SamplingLayerBuilder::global_limit).
+    fn trim(
+        &self,
+        state: &mut State,
+        events: &mut Batch,
+        capacity: u64,
+        cost: Cost,
+        in_scope: impl Fn(&Buffered) -> bool,
+    ) {
+        let per_unit = if cost == Cost::Bytes { 1 } else { UNIT };
+        let capacity = capacity.saturating_mul(per_unit);
+        let units = |event: &Buffered| {
+            let level = event.meta.map_or(&Level::INFO, |meta| meta.level());
+            cost.units(level, event.bytes.len())
+        };
+        let total: u64 = events
+            .iter()
+            .filter(|event| in_scope(event))
+            .map(units)
+            .sum();
+        if total <= capacity {
+            return;
+        }
+        // Events already written are kept whatever the cost.
+        fastrand::shuffle(events);
+        events.sort_by_key(|event| !event.written);
+        let mut used = 0;
+        events.retain(|event| {
+            if !in_scope(event) {
+                return true;
+            }
+            used += units(event);
+            if event.written || used <= capacity {
+                return true;
+            }
+            used -= units(event);
+            self.record_drop(state, event);
+            false
+        });
+    }
+
+    /// Reset the bucket's drop counters, rendering a summary line if enabled.
+    fn take_summary(&self, state: &mut State) -> Option<Buffered> {
+        let dropped = std::mem::take(&mut state.bucket_dropped);
+        let bytes = self.summary.as_ref()?.render(dropped)?;
+        Some(Buffered {
+            seq: state.seq,
+            meta: None,
+            budget: None,
+            bytes,
+            fields: Vec::new(),
+            written: false,
+        })
+    }
+
+    /// Render a stats report line if one is due.
+    fn take_report(&self, state: &mut State, now: Instant) -> Option<Buffered> {
+        let every = self.report_every?;
+        if now.duration_since(state.last_report) < every {
+            return None;
+        }
+        state.last_report = now;
+        Some(Buffered {
+            seq: state.seq,
+            meta: None,
+            budget: None,
+            bytes: render_stats(&self.stats),
+            fields: Vec::new(),
+            written: false,
+        })
+    }
+
     #[cold]
-    fn rotate_bucket(&self, state: &mut State, batch: &mut Vec<(u64, Vec<u8>)>, now: Instant) {
-        batch.extend(state.pending.by_ref());
-        let drained = Self::drain_all(state);
-        state.pending = drained.into_iter();
+    fn rotate_bucket(&self, state: &mut State, batch: &mut Batch, now: Instant) {
+        // Events held back by `max_release` are carried into the next bucket,
+        // but only once, so a cap the sink can't keep up with doesn't grow
+        // the queue without bound. The summary for the bucket follows them.
+        let uncapped = self.max_release == usize::MAX && self.max_release_time.is_none();
+        if uncapped || self.max_delay.is_some() {
+            batch.extend(state.pending.by_ref());
+            batch.extend(self.take_summary(state));
+            batch.extend(self.take_report(state, now));
+            let (at_rotation, smeared) = self.drain_rotated(state);
+            batch.extend(at_rotation);
+            state.pending = smeared.into_iter();
+        } else {
+            batch.extend(state.pending.by_ref().take(state.pending_carried));
+            let mut pending: Batch = state.pending.by_ref().collect();
+            state.pending_carried = pending.len();
+            pending.extend(self.take_summary(state));
+            pending.extend(self.take_report(state, now));
+            let (at_rotation, smeared) = self.drain_rotated(state);
+            batch.extend(at_rotation);
+            pending.extend(smeared);
+            state.pending = pending.into_iter();
+        }
         state.bucket_start = now;
         state.last_release = now;
     }
 
+    /// Release any smeared events that are due and rotate the bucket if it
+    /// has expired. Returns the instant at which the next release is due.
     #[inline]
-    fn tick_smear(&self) {
+    pub(crate) fn tick_smear(&self) -> Instant {
         let now = Instant::now();
-        let to_write = {
+        let (to_write, next) = {
             let mut state = self.state.lock().unwrap();
-            let mut batch = Self::smear_collect(&mut state, now, self.bucket_duration);
-            if now.duration_since(state.bucket_start) >= self.bucket_duration {
+            let mut batch = self.smear_collect(&mut state, now);
+            if now.duration_since(state.bucket_start) >= state.bucket_duration {
                 self.rotate_bucket(&mut state, &mut batch, now);
+                if self.max_delay.is_some() {
+                    batch.extend(self.smear_collect(&mut state, now));
+                }
             }
-            batch
+            (batch, self.next_release(&state, now))
+        };
+        self.write_events(to_write, false);
+        next
+    }
+
+    fn next_release(&self, state: &State, now: Instant) -> Instant {
+        let bucket_end = state.bucket_start + state.bucket_duration;
+        let n = state.pending.len();
+        if n == 0 {
+            return bucket_end;
+        }
+        let smear_end = state.bucket_start + self.smear_window(state);
+        let interval = smear_end.saturating_duration_since(now) / n as u32;
+        (state.last_release + interval).min(smear_end)
+    }
+
+    /// Take everything still buffered: pending smeared events followed by
+    /// the current reservoir contents.
+    fn take_all(&self, state: &mut State) -> Batch {
+        let mut events: Batch = state.pending.by_ref().collect();
+        state.pending_carried = 0;
+        events.extend(self.take_summary(state));
+        events.extend(self.drain_all(state));
+        events
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Pause or resume sampling. Pausing flushes whatever is buffered, so
+    /// it is written ahead of the events passed through.
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        if !self.enabled.swap(enabled, Ordering::Relaxed) || enabled {
+            return;
+        }
+        self.flush();
+    }
+
+    /// Each budget's capacity for the next bucket, from its current limit,
+    /// the bucket duration and, with a target rate, the traffic each budget
+    /// matched in the bucket ending, scaled down while the sink struggles or
+    /// the budget ramps up, plus any burst credit, and capped at
+    /// [`MAX_CAPACITY`] unless counted in bytes, since every slot is
+    /// allocated up front.
+    fn next_capacities(&self, state: &mut State) -> Vec<usize> {
+        let seen: Vec<usize> = (0..state.reservoirs.len())
+            .map(|i| state.seen(i) + state.head_written[i])
+            .collect();
+        let limits: Vec<u64> = (0..state.reservoirs.len())
+            .map(|i| match &self.quotas[i] {
+                Some(quota) => {
+                    quota.report(seen[i] as u64, state.bucket_duration);
+                    quota.limit()
+                }
+                None => self.budgets[i].limit_per_second,
+            })
+            .collect();
+        let mut scale = match &mut state.adaptive {
+            Some(adaptive) => adaptive.scale(state.bucket_duration, &seen, &limits),
+            None => 1.0,
         };
-        self.write_events(&to_write);
+        if let Some(backpressure) = &mut state.backpressure {
+            scale *= backpressure.scale(&self.stats);
+        }
+        let secs = state.bucket_duration.as_secs_f64();
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.started) + state.bucket_duration;
+        (limits.iter().zip(&self.ramp_up).enumerate())
+            .map(|(i, (&limit, &ramp_up))| {
+                let scale = scale * adaptive::ramp(ramp_up, elapsed);
+                let capacity = (limit as f64 * secs * scale).ceil() as usize;
+                let (seen, current) = (state.seen(i), state.reservoirs[i].capacity());
+                let burst =
+                    (state.bursts[i].as_mut()).map_or(0, |burst| burst.next(seen, current, now));
+                let capacity = capacity.saturating_add(burst);
+                match self.cost[i] {
+                    Cost::Bytes => capacity,
+                    _ => capacity.min(MAX_CAPACITY),
+                }
+            })
+            .collect()
+    }
+
+    /// Resize an emptied reservoir for budget `i`, in case its limit or the
+    /// bucket duration has changed.
+    fn resize(&self, i: usize, reservoir: &mut Reservoir<Buffered>, capacity: usize) {
+        if capacity != reservoir.capacity() {
+            reservoir.resize(capacity, self.weighting[i], self.cost[i]);
+            (self.stats.budgets[i].capacity).store(capacity as u64, Ordering::Relaxed);
+        }
     }
 
+    /// Start a new bucket of `duration`, resizing every reservoir for its
+    /// budget's per-second limit. Whatever is buffered is written first.
+    pub(crate) fn set_bucket_duration(&self, duration: Duration) {
+        let duration = match self.max_delay {
+            Some(max_delay) => duration.min(max_delay),
+            None => duration,
+        };
+        if duration.is_zero() {
+            return;
+        }
+        let events = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            state.bucket_start = now;
+            state.last_release = now;
+            // Draining resizes the reservoirs for the new duration.
+            state.bucket_duration = duration;
+            self.take_all(&mut state)
+        };
+        self.write_events(events, true);
+    }
+
+    pub(crate) fn flush(&self) {
+        let events = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            state.bucket_start = now;
+            state.last_release = now;
+            self.take_all(&mut state)
+        };
+        self.write_events(events, true);
+        self.sink.sync(&self.stats);
+    }
+
+    /// Like [`flush`](Self::flush), but gives up rather than blocking if the
+    /// state is already locked, e.g. by a thread that panicked mid-sample.
+    pub(crate) fn try_flush(&self) {
+        let events = match self.state.try_lock() {
+            Ok(mut state) => self.take_all(&mut state),
+            Err(_) => return,
+        };
+        self.write_events(events, true);
+        self.sink.sync(&self.stats);
+    }
+}
+
+impl<W> Control for Shared<W>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync,
+{
+    fn flush(&self) {
+        Shared::flush(self);
+    }
+
+    fn try_flush(&self) {
+        Shared::try_flush(self);
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        Shared::set_enabled(self, enabled);
+    }
+
+    fn set_bucket_duration(&self, duration: Duration) {
+        Shared::set_bucket_duration(self, duration);
+    }
+
+    fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    #[cfg(feature = "debug-server")]
+    fn debug_json(&self) -> String {
+        serde_json::to_string(&self.debug_state()).unwrap()
+    }
+}
+
+/// A [`tracing_subscriber::Layer`] that samples events into time-bucketed reservoirs.
+///
+/// Uses `tracing_subscriber::fmt::Layer` internally for event formatting.
+/// Sampled events are smeared across the bucket duration to reduce tail-latency
+/// spikes from burst writes.
+///
+/// Construct via [`SamplingLayer::builder()`](crate::SamplingLayerBuilder).
+///
+/// The layer can be wrapped in a
+/// [`reload::Layer`](tracing_subscriber::reload::Layer) and replaced at
+/// runtime; the replaced layer writes whatever it still buffers when dropped.
+pub struct SamplingLayer<
+    S,
+    N = DefaultFields,
+    E = Format<Full>,
+    W: for<'a> MakeWriter<'a> = fn() -> io::Stderr,
+> {
+    pub(crate) filters: Vec<Box<dyn BudgetFilter<S>>>,
+    /// Bitset of budgets whose ejected events are dropped rather than
+    /// cascaded.
+    pub(crate) no_cascade: u64,
+    /// Most reservoirs a single event is offered to.
+    pub(crate) max_cascade_depth: usize,
+    /// Only match the first budget whose filter accepts an event.
+    pub(crate) first_match_only: bool,
+    /// Events at or above this level are written immediately, unsampled.
+    pub(crate) bypass_sampling: LevelFilter,
+    pub(crate) priority: Option<PriorityFn>,
+    /// Checked before any budget filter.
+    pub(crate) global_filter: Option<Box<dyn BudgetFilter<S>>>,
+    /// Wrapped in a per-layer filter, so events outside every budget must not
+    /// be disabled for the rest of the subscriber.
+    pub(crate) per_layer: bool,
+    pub(crate) shared: Arc<Shared<W>>,
+    pub(crate) handle: Handle,
+    pub(crate) fmt_layer: fmt::Layer<S, N, E, CaptureMakeWriter>,
+    /// Budgets matched by the last call to `event_enabled` on each thread,
+    /// consumed by the following `on_event`.
+    pub(crate) matched: ThreadLocal<Cell<Option<u64>>>,
+    /// Set when built with `re_emit`, filled in once the layer is registered
+    /// with a subscriber.
+    pub(crate) re_emit: Option<Arc<OnceLock<WeakDispatch>>>,
+    /// The fmt layer writes span events, which are sampled too.
+    pub(crate) span_events: bool,
+    /// Receives every matched event before sampling.
+    pub(crate) archive: Option<BoxMakeWriter>,
+    pub(crate) _subscriber: PhantomData<fn(S)>,
+}
+
+impl<S: 'static, N, E, W: for<'a> MakeWriter<'a>> SamplingLayer<S, N, E, W> {
     #[inline]
-    fn match_filters<S2: Subscriber + for<'a> LookupSpan<'a>>(
-        &self,
-        meta: &Metadata<'_>,
-        ctx: &Context<'_, S2>,
-    ) -> u64 {
+    fn match_filters(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> u64 {
+        let meta = event.metadata();
+        if let Some(global) = &self.global_filter
+            && !(global.enabled(meta, ctx) && global.event_enabled(event, ctx))
+        {
+            return 0;
+        }
+        let mut matched: u64 = 0;
+        for (i, filter) in self.filters.iter().enumerate() {
+            if filter.enabled(meta, ctx) && filter.event_enabled(event, ctx) {
+                matched |= 1 << i;
+                if self.first_match_only {
+                    break;
+                }
+            }
+        }
+        matched
+    }
+
+    /// Budgets matching a span event, judged on the span's metadata.
+    fn match_span(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> u64 {
+        if let Some(global) = &self.global_filter
+            && !global.enabled(meta, ctx)
+        {
+            return 0;
+        }
         let mut matched: u64 = 0;
         for (i, filter) in self.filters.iter().enumerate() {
-            if <EnvFilter as tracing_subscriber::Layer<S2>>::enabled(filter, meta, ctx.clone()) {
+            if filter.enabled(meta, ctx) {
                 matched |= 1 << i;
+                if self.first_match_only {
+                    break;
+                }
             }
         }
         matched
     }
 
     #[cold]
-    fn sample_event(&self, bytes: Vec<u8>, matched: u64) {
-        let mut state = self.state.lock().unwrap();
+    fn sample_event(
+        &self,
+        meta: &'static Metadata<'static>,
+        event: Option<&Event<'_>>,
+        bytes: Vec<u8>,
+        fields: Fields,
+        matched: u64,
+        span_hashes: SpanHashes,
+    ) {
+        let stats = &self.shared.stats;
+        let mut state = self.shared.state.lock().unwrap();
         state.seq += 1;
-        let mut current = (state.seq, bytes);
-        for (i, reservoir) in state.reservoirs.iter_mut().enumerate() {
+        state.bucket_received += 1;
+        let mut current = Buffered {
+            seq: state.seq,
+            meta: Some(meta),
+            budget: None,
+            bytes,
+            fields,
+            written: false,
+        };
+        let bucket_start = state.bucket_start;
+        let mut written = None;
+        let mut overflow = Vec::new();
+        let mut last = None;
+        let mut depth = 0;
+        let State {
+            reservoirs,
+            bucket_dropped,
+            head_written,
+            head_limit,
+            keyed,
+            ..
+        } = &mut *state;
+        for (i, reservoir) in reservoirs.iter_mut().enumerate() {
             if matched & (1 << i) == 0 {
                 continue;
             }
-            current = reservoir.sample(current);
-            if current.1.is_empty() {
-                self.stats.sampled.fetch_add(1, Ordering::Relaxed);
+            if depth == self.max_cascade_depth {
+                break;
+            }
+            depth += 1;
+            let counters = &stats.budgets[i];
+            counters.received.fetch_add(1, Ordering::Relaxed);
+            let hash =
+                self.shared.sample_keys[i].and_then(|key| span_hashes.hash(key, meta, event));
+            let admitted = if head_written[i] < head_limit[i] {
+                head_written[i] += 1;
+                Some(true)
+            } else if reservoir.is_unbuffered() {
+                Some(reservoir.admit(hash))
+            } else {
+                None
+            };
+            match admitted {
+                Some(true) => {
+                    counters.sampled.fetch_add(1, Ordering::Relaxed);
+                    stats.sampled.fetch_add(1, Ordering::Relaxed);
+                    stats.record_sampled([meta]);
+                    current.budget = Some(i);
+                    drop(state);
+                    self.shared.write_events(vec![current], false);
+                    return;
+                }
+                Some(false) => {
+                    last = Some(counters);
+                    if self.no_cascade & (1 << i) != 0 {
+                        break;
+                    }
+                    continue;
+                }
+                None => {}
+            }
+            let reservoir = match &mut keyed[i] {
+                Some(keyed) => keyed.reservoir(
+                    meta,
+                    event,
+                    reservoir,
+                    self.shared.weighting[i],
+                    self.shared.cost[i],
+                    &mut overflow,
+                ),
+                None => reservoir,
+            };
+            let immediate = self.shared.emit[i] == Emit::Immediate && !current.written;
+            let seq = current.seq;
+            if immediate {
+                written = Some(Buffered {
+                    seq,
+                    meta: current.meta,
+                    budget: Some(i),
+                    bytes: std::mem::take(&mut current.bytes),
+                    fields: std::mem::take(&mut current.fields),
+                    written: false,
+                });
+                current.written = true;
+            }
+            current = if reservoir.is_keyed() {
+                let key = match self.shared.weighting[i] {
+                    Weighting::Uniform => random(),
+                    Weighting::Decay { rate } => decayed_key(rate, bucket_start.elapsed()),
+                    Weighting::Field(field) => {
+                        weighted_key(event.map_or(1.0, |event| field_weight(event, field)))
+                    }
+                };
+                let bytes = current.bytes.len();
+                let units = self.shared.cost[i].units(meta.level(), bytes);
+                reservoir.sample_keyed(current, key, units, bytes, &mut overflow)
+            } else if let Some(hash) = hash {
+                reservoir.sample_hashed(current, hash)
+            } else {
+                reservoir.sample(current)
+            };
+            // Further events ejected to make room, or evicted with their
+            // key, aren't cascaded. They were counted as sampled on entry.
+            for ejected in overflow.drain(..) {
+                let Some(meta) = ejected.meta.filter(|_| !ejected.written) else {
+                    continue;
+                };
+                stats.sampled.fetch_sub(1, Ordering::Relaxed);
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+                counters.dropped.fetch_add(1, Ordering::Relaxed);
+                bucket_dropped[level_index(meta.level())] += 1;
+                stats.record_dropped(meta);
+            }
+            if immediate
+                && current.seq == seq
+                && let Some(rejected) = written.take()
+            {
+                current.bytes = rejected.bytes;
+                current.fields = rejected.fields;
+                current.written = false;
+            }
+            counters
+                .fill
+                .store(reservoir.len() as u64, Ordering::Relaxed);
+            // Either a free slot was taken, or the event ejected to make room
+            // has already been written.
+            if current.meta.is_none() || current.written {
+                stats.sampled.fetch_add(1, Ordering::Relaxed);
+                drop(state);
+                self.shared
+                    .write_events(written.into_iter().collect(), false);
                 return;
             }
+            last = Some(counters);
+            if self.no_cascade & (1 << i) != 0 {
+                break;
+            }
         }
-        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
-        return_captured(&self.fmt_layer.writer().0, current.1);
+        stats.dropped.fetch_add(1, Ordering::Relaxed);
+        if let Some(counters) = last {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(meta) = current.meta {
+            state.bucket_dropped[level_index(meta.level())] += 1;
+            stats.record_dropped(meta);
+        }
+        drop(state);
+        return_captured(&self.fmt_layer.writer().0, current.bytes);
+        self.shared
+            .write_events(written.into_iter().collect(), false);
     }
 
-    /// Drain all reservoirs and write their contents immediately.
-    pub fn flush(&self) {
-        let (pending, drained) = {
-            let mut state = self.state.lock().unwrap();
-            let pending: Vec<_> = state.pending.by_ref().collect();
-            let drained = Self::drain_all(&mut state);
-            (pending, drained)
+    /// Write an event without sampling it, routed as if sampled by the first
+    /// budget it matched.
+    fn write_immediately(
+        &self,
+        meta: &'static Metadata<'static>,
+        bytes: Vec<u8>,
+        fields: Fields,
+        matched: u64,
+    ) {
+        let seq = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.seq += 1;
+            state.seq
+        };
+        let stats = &self.shared.stats;
+        stats.sampled.fetch_add(1, Ordering::Relaxed);
+        stats.record_sampled([meta]);
+        let event = Buffered {
+            seq,
+            meta: Some(meta),
+            budget: Some(matched.trailing_zeros() as usize),
+            bytes,
+            fields,
+            written: false,
         };
-        self.write_events(&pending);
-        self.write_events(&drained);
+        self.shared.write_events(vec![event], false);
+    }
+
+    /// Drain all reservoirs and write their contents immediately, starting a
+    /// new bucket.
+    pub fn flush(&self) {
+        self.shared.flush();
+    }
+
+    /// Pause sampling, writing every matching event immediately, or resume
+    /// it. See [`Handle::set_enabled`].
+    pub fn set_enabled(&self, enabled: bool) {
+        self.shared.set_enabled(enabled);
+    }
+
+    /// Change the bucket duration, starting a new bucket. See
+    /// [`Handle::set_bucket_duration`].
+    pub fn set_bucket_duration(&self, duration: Duration) {
+        self.shared.set_bucket_duration(duration);
+    }
+
+    /// The configuration of every budget, in the order budgets were added.
+    ///
+    /// Budgets skipped at build time because their limit rounded to zero
+    /// events per bucket are not included.
+    pub fn budgets(&self) -> &[BudgetInfo] {
+        &self.shared.budgets
+    }
+
+    /// A type-erased [`Handle`] to this layer.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
     }
 }
 
 impl<S, N, E, W: for<'a> MakeWriter<'a>> Drop for SamplingLayer<S, N, E, W> {
     fn drop(&mut self) {
-        if let Ok(mut state) = self.state.lock() {
-            let pending: Vec<_> = state.pending.by_ref().collect();
-            let drained = Self::drain_all(&mut state);
+        if let Ok(mut state) = self.shared.state.lock() {
+            let events = self.shared.take_all(&mut state);
             drop(state);
-            self.write_events(&pending);
-            self.write_events(&drained);
+            self.shared.write_events(events, true);
         }
+        self.shared.sink.sync(&self.shared.stats);
     }
 }
 
@@ -233,11 +1020,194 @@ where
         &self.fmt_layer
     }
 
+    /// Wrap this layer in a per-layer [`Filter`], like
+    /// [`Layer::with_filter`](tracing_subscriber::Layer::with_filter).
+    ///
+    /// `filter` decides which events reach the budgets. Unlike the layer on
+    /// its own, which disables events no budget matches for the whole
+    /// subscriber, a filtered layer only ever skips events for itself, so
+    /// other layers in the stack still see everything.
+    pub fn with_filter<F: Filter<S>>(mut self, filter: F) -> Filtered<Self, F, S> {
+        self.per_layer = true;
+        tracing_subscriber::Layer::with_filter(self, filter)
+    }
+
     #[inline]
     fn format_event(&self, event: &Event<'_>, ctx: Context<'_, S>) -> Vec<u8> {
         self.inner().on_event(event, ctx);
         take_captured(&self.inner().writer().0)
     }
+
+    /// Sample the span event the fmt layer just wrote for `id`, if any.
+    #[cold]
+    fn sample_span_event(&self, id: &span::Id, ctx: &Context<'_, S>) {
+        let bytes = take_captured(&self.inner().writer().0);
+        if bytes.is_empty() {
+            return;
+        }
+        // Re-emitting needs an event's fields, which span events don't have.
+        let matched = match ctx.metadata(id) {
+            Some(meta) if self.re_emit.is_none() => Some((meta, self.match_span(meta, ctx))),
+            _ => None,
+        };
+        let Some((meta, matched @ 1..)) = matched else {
+            return_captured(&self.inner().writer().0, bytes);
+            return;
+        };
+        self.shared.stats.received.fetch_add(1, Ordering::Relaxed);
+        self.shared.tick_smear();
+        self.write_archive(meta, &bytes);
+        if *meta.level() <= self.bypass_sampling || !self.shared.is_enabled() {
+            self.write_immediately(meta, bytes, Vec::new(), matched);
+        } else {
+            let span_hashes = self.span_hashes(None, || ctx.span(id));
+            self.sample_event(meta, None, bytes, Vec::new(), matched, span_hashes);
+        }
+    }
+
+    /// What `event`, or a span event if `None`, is decided by for budgets
+    /// [`consistent`](crate::Budget::consistent) by a key that depends on
+    /// its spans, the innermost of which `leaf` returns.
+    fn span_hashes<'a>(
+        &self,
+        event: Option<&Event<'_>>,
+        leaf: impl FnOnce() -> Option<SpanRef<'a, S>>,
+    ) -> SpanHashes
+    where
+        S: for<'l> LookupSpan<'l>,
+    {
+        let keys = &self.shared.sample_keys;
+        let by_root_span = keys.contains(&Some(SampleKey::RootSpan));
+        let by_trace_id = keys.contains(&Some(SampleKey::TraceId));
+        if !by_root_span && !by_trace_id {
+            return SpanHashes::default();
+        }
+        let leaf = leaf();
+        SpanHashes {
+            root_span: (leaf.as_ref())
+                .and_then(|leaf| leaf.scope().from_root().next())
+                .filter(|_| by_root_span)
+                .map(|root| consistent::root_span_hash(&root)),
+            trace_id: (by_trace_id)
+                .then(|| consistent::trace_id_hash(event, leaf.as_ref()))
+                .flatten(),
+        }
+    }
+
+    fn write_archive(&self, meta: &Metadata<'_>, bytes: &[u8]) {
+        if let Some(archive) = &self.archive {
+            let _ = archive.make_writer_for(meta).write_all(bytes);
+        }
+    }
+
+    fn priority(&self, event: &Event<'_>) -> Priority {
+        let meta = event.metadata();
+        if *meta.level() <= self.bypass_sampling || !self.shared.is_enabled() {
+            return Priority::Immediate;
+        }
+        match &self.priority {
+            Some(priority) => priority(meta, event),
+            None => Priority::Sampled,
+        }
+    }
+
+    /// Remove the budgets `event` would first be offered to that are over
+    /// their [`hard_limit`](crate::Budget::hard_limit) from `matched`,
+    /// counting the event as dropped if none are left.
+    fn hard_limit(&self, meta: &'static Metadata<'static>, matched: u64) -> u64 {
+        self.drop_over_limit(meta, matched, |i| {
+            let hard = self.shared.hard[i].as_ref()?;
+            Some(hard.seen.fetch_add(1, Ordering::Relaxed) >= hard.capacity.load(Ordering::Relaxed))
+        })
+    }
+
+    /// Like [`hard_limit`](Self::hard_limit), for budgets limited
+    /// [`per_root_span`](crate::Budget::per_root_span), counting events in
+    /// the extensions of the event's root span.
+    fn root_span_limit(&self, event: &Event<'_>, ctx: &Context<'_, S>, matched: u64) -> u64
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        if self.shared.per_root_span.iter().all(Option::is_none) {
+            return matched;
+        }
+        let Some(root) = (ctx.event_scope(event)).and_then(|scope| scope.from_root().next()) else {
+            return matched;
+        };
+        let mut extensions = root.extensions_mut();
+        if extensions.get_mut::<RootSpanCounts>().is_none() {
+            extensions.insert(RootSpanCounts::default());
+        }
+        let Some(RootSpanCounts(layers)) = extensions.get_mut::<RootSpanCounts>() else {
+            return matched;
+        };
+        // Several layers may count events in the same span.
+        let layer = Arc::as_ptr(&self.shared) as *const () as usize;
+        let counts =
+            (layers.entry(layer)).or_insert_with(|| vec![0; self.shared.per_root_span.len()]);
+        self.drop_over_limit(event.metadata(), matched, |i| {
+            let limit = self.shared.per_root_span[i]?;
+            counts[i] += 1;
+            Some(counts[i] > limit)
+        })
+    }
+
+    /// Remove the budgets `event` would first be offered to for which
+    /// `over` is true from `matched`, counting the event as dropped if none
+    /// are left. `over` is `None` for a budget without the limit, which
+    /// then takes the event as usual.
+    fn drop_over_limit(
+        &self,
+        meta: &'static Metadata<'static>,
+        matched: u64,
+        mut over: impl FnMut(usize) -> Option<bool>,
+    ) -> u64 {
+        let stats = &self.shared.stats;
+        let mut remaining = matched;
+        for i in 0..self.filters.len() {
+            if remaining & (1 << i) == 0 {
+                continue;
+            }
+            if over(i) != Some(true) {
+                break;
+            }
+            let counters = &stats.budgets[i];
+            counters.received.fetch_add(1, Ordering::Relaxed);
+            remaining &= !(1 << i);
+            if self.no_cascade & (1 << i) != 0 {
+                remaining = 0;
+            }
+            if remaining == 0 {
+                counters.dropped.fetch_add(1, Ordering::Relaxed);
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+                stats.record_dropped(meta);
+                break;
+            }
+        }
+        remaining
+    }
+
+    fn emit(
+        &self,
+        event: &Event<'_>,
+        priority: Priority,
+        bytes: Vec<u8>,
+        fields: Fields,
+        matched: u64,
+        span_hashes: SpanHashes,
+    ) {
+        match priority {
+            Priority::Sampled => self.sample_event(
+                event.metadata(),
+                Some(event),
+                bytes,
+                fields,
+                matched,
+                span_hashes,
+            ),
+            Priority::Immediate => self.write_immediately(event.metadata(), bytes, fields, matched),
+        }
+    }
 }
 
 impl<S, N, E, W> tracing_subscriber::Layer<S> for SamplingLayer<S, N, E, W>
@@ -247,10 +1217,24 @@ where
     E: fmt::FormatEvent<S, N> + 'static,
     W: for<'a> MakeWriter<'a> + 'static,
 {
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else if id == TypeId::of::<Handle>() {
+            Some(&self.handle as *const Handle as *const ())
+        } else {
+            None
+        }
+    }
+
     fn register_callsite(&self, meta: &'static Metadata<'static>) -> Interest {
+        if let Some(global) = &self.global_filter
+            && global.callsite_enabled(meta).is_never()
+        {
+            return Interest::never();
+        }
         for filter in &self.filters {
-            let interest =
-                <EnvFilter as tracing_subscriber::Layer<S>>::register_callsite(filter, meta);
+            let interest = filter.callsite_enabled(meta);
             if interest.is_sometimes() || interest.is_always() {
                 return Interest::sometimes();
             }
@@ -258,28 +1242,107 @@ where
         Interest::never()
     }
 
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        if let Some(dispatch) = &self.re_emit {
+            let _ = dispatch.set(subscriber.downgrade());
+        }
+    }
+
     fn enabled(&self, meta: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
-        self.filters.iter().any(|filter| {
-            <EnvFilter as tracing_subscriber::Layer<S>>::enabled(filter, meta, ctx.clone())
-        })
+        if self.per_layer || self.re_emit.is_some() && re_emitting() {
+            return true;
+        }
+        if let Some(global) = &self.global_filter
+            && !global.enabled(meta, &ctx)
+        {
+            return false;
+        }
+        self.filters.iter().any(|filter| filter.enabled(meta, &ctx))
+    }
+
+    /// The most verbose level any budget accepts, so that rebuilding the
+    /// interest cache, e.g. when replaced inside a
+    /// [`reload::Layer`](tracing_subscriber::reload::Layer), lowers or
+    /// raises the global maximum level to match.
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        if self.per_layer || self.re_emit.is_some() {
+            return None;
+        }
+        let hint = (self.filters.iter()).try_fold(LevelFilter::OFF, |max, filter| {
+            Some(max.max(filter.max_level_hint()?))
+        })?;
+        match self
+            .global_filter
+            .as_ref()
+            .and_then(|global| global.max_level_hint())
+        {
+            Some(global) => Some(hint.min(global)),
+            None => Some(hint),
+        }
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: Context<'_, S>) -> bool {
+        if self.re_emit.is_some() && re_emitting() {
+            return true;
+        }
+        let matched = self.match_filters(event, &ctx);
+        self.matched.get_or_default().set(Some(matched));
+        matched != 0 || self.per_layer
     }
 
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
-        let matched = self.match_filters(event.metadata(), &ctx);
+        if self.re_emit.is_some() && re_emitting() {
+            return;
+        }
+        let matched = match self.matched.get_or_default().take() {
+            Some(matched) => matched,
+            None => self.match_filters(event, &ctx),
+        };
         if matched == 0 {
             return;
         }
 
-        self.stats.received.fetch_add(1, Ordering::Relaxed);
+        self.shared.stats.received.fetch_add(1, Ordering::Relaxed);
+
+        self.shared.tick_smear();
 
-        self.tick_smear();
+        let priority = self.priority(event);
+        let matched = match priority {
+            Priority::Sampled => {
+                let matched = self.hard_limit(event.metadata(), matched);
+                self.root_span_limit(event, &ctx, matched)
+            }
+            Priority::Immediate => matched,
+        };
+        if matched == 0 {
+            return;
+        }
+        let span_hashes = match priority {
+            Priority::Sampled => self.span_hashes(Some(event), || ctx.event_span(event)),
+            Priority::Immediate => SpanHashes::default(),
+        };
+        if self.re_emit.is_some() {
+            if self.archive.is_some() {
+                self.write_archive(event.metadata(), &self.format_event(event, ctx));
+            }
+            self.emit(
+                event,
+                priority,
+                Vec::new(),
+                capture(event),
+                matched,
+                span_hashes,
+            );
+            return;
+        }
 
         let bytes = self.format_event(event, ctx);
         if bytes.is_empty() {
             return;
         }
 
-        self.sample_event(bytes, matched);
+        self.write_archive(event.metadata(), &bytes);
+        self.emit(event, priority, bytes, Vec::new(), matched, span_hashes);
     }
 
     #[inline]
@@ -289,7 +1352,15 @@ where
         id: &tracing::span::Id,
         ctx: Context<'_, S>,
     ) {
-        self.inner().on_new_span(attrs, id, ctx);
+        self.inner().on_new_span(attrs, id, ctx.clone());
+        if (self.shared.sample_keys).contains(&Some(SampleKey::TraceId))
+            && let Some(span) = ctx.span(id)
+        {
+            consistent::record_span_trace_id(&span, |visitor| attrs.record(visitor));
+        }
+        if self.span_events {
+            self.sample_span_event(id, &ctx);
+        }
     }
 
     #[inline]
@@ -299,21 +1370,35 @@ where
         values: &tracing::span::Record<'_>,
         ctx: Context<'_, S>,
     ) {
-        self.inner().on_record(id, values, ctx);
+        self.inner().on_record(id, values, ctx.clone());
+        if (self.shared.sample_keys).contains(&Some(SampleKey::TraceId))
+            && let Some(span) = ctx.span(id)
+        {
+            consistent::record_span_trace_id(&span, |visitor| values.record(visitor));
+        }
     }
 
     #[inline]
     fn on_enter(&self, id: &tracing::span::Id, ctx: Context<'_, S>) {
-        self.inner().on_enter(id, ctx);
+        self.inner().on_enter(id, ctx.clone());
+        if self.span_events {
+            self.sample_span_event(id, &ctx);
+        }
     }
 
     #[inline]
     fn on_exit(&self, id: &tracing::span::Id, ctx: Context<'_, S>) {
-        self.inner().on_exit(id, ctx);
+        self.inner().on_exit(id, ctx.clone());
+        if self.span_events {
+            self.sample_span_event(id, &ctx);
+        }
     }
 
     #[inline]
     fn on_close(&self, id: tracing::span::Id, ctx: Context<'_, S>) {
-        self.inner().on_close(id, ctx);
+        self.inner().on_close(id.clone(), ctx.clone());
+        if self.span_events {
+            self.sample_span_event(&id, &ctx);
+        }
     }
 }
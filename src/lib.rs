@@ -32,13 +32,83 @@
 //! // tracing::subscriber::set_global_default(subscriber).unwrap();
 //! ```
 
+mod adaptive;
+mod ansi;
+#[cfg(feature = "tokio")]
+mod async_sink;
+mod budget;
 mod builder;
 mod capture;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+mod compress;
+#[cfg(feature = "serde")]
+mod config;
+mod consistent;
+#[cfg(feature = "debug-server")]
+mod debug;
+mod env;
+mod error;
+mod filter;
+mod flusher;
+mod format;
+mod handle;
+mod histogram;
+#[cfg(all(unix, feature = "journald"))]
+mod journald;
+mod keyed;
 mod layer;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+mod prometheus;
+mod quota;
+mod recent;
+mod reemit;
 mod reservoir;
+mod sampling_filter;
+#[cfg(feature = "sentry")]
+mod sentry;
+mod sink;
+#[cfg(feature = "socket")]
+mod socket;
+mod stats;
+#[cfg(feature = "statsd")]
+mod statsd;
+mod summary;
+#[cfg(feature = "syslog")]
+mod syslog;
 
+pub use ansi::{StripAnsi, StripAnsiWriter};
+pub use budget::Budget;
 pub use builder::SamplingLayerBuilder;
-pub use layer::{SamplingLayer, Stats};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub use compress::{Compressed, CompressedWriter};
+#[cfg(feature = "serde")]
+pub use config::{
+    BudgetConfig, ConfigFields, ConfigFormat, FormatConfig, SamplingConfig, ScheduleConfig,
+    WindowConfig,
+};
+pub use consistent::SampleKey;
+pub use error::{BuildError, EnvError};
+pub use filter::{BudgetFilter, FieldValue};
+pub use format::{Gelf, Json, JsonFields, Logfmt};
+pub use handle::{Handle, SamplingGuard};
+pub use histogram::LatencyHistogram;
+#[cfg(all(unix, feature = "journald"))]
+pub use journald::{Journald, JournaldEntries, JournaldWriter};
+pub use layer::{BudgetInfo, Emit, Priority, SamplingLayer};
+pub use quota::{QuotaProvider, Schedule};
+pub use recent::RecentEvents;
+pub use reemit::ReEmitted;
+pub use sampling_filter::{SamplingFilter, SamplingFilterBuilder};
+pub use sink::FlushPolicy;
+#[cfg(feature = "socket")]
+pub use socket::SocketWriter;
+pub use stats::{BudgetStats, CallsiteDrops, OTHER_TARGETS, SampleCounts, Stats, StatsSnapshot};
+#[cfg(feature = "statsd")]
+pub use statsd::{StatsdGuard, StatsdReporter};
+pub use summary::DropSummary;
+#[cfg(feature = "syslog")]
+pub use syslog::{Facility, Syslog, SyslogLines, SyslogWriter};
 
 #[cfg(test)]
 mod tests {
@@ -46,12 +116,16 @@ mod tests {
     use std::sync::{Arc, Mutex};
     use std::time::Duration;
 
+    use tracing::Level;
     use tracing_subscriber::Registry;
     use tracing_subscriber::filter::EnvFilter;
     use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::fmt::format::{DefaultFields, Format, Full};
     use tracing_subscriber::layer::SubscriberExt;
 
-    use crate::SamplingLayer;
+    use crate::{
+        Budget, BuildError, FlushPolicy, QuotaProvider, SamplingLayer, SamplingLayerBuilder,
+    };
 
     #[derive(Clone, Default)]
     struct SharedBuf(Arc<Mutex<Vec<u8>>>);
@@ -80,14 +154,29 @@ mod tests {
         }
     }
 
-    fn capture_layer(
-        bucket_ms: u64,
-        budgets: &[(&str, u64)],
-    ) -> (impl tracing_subscriber::Layer<Registry>, SharedBuf) {
+    /// The fmt layer colours output when ANSI is enabled, which would break
+    /// field parsing in assertions.
+    fn strip_ansi(line: &str) -> String {
+        let mut out = String::with_capacity(line.len());
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    type TestLayer = SamplingLayer<Registry, DefaultFields, Format<Full, ()>, SharedBuf>;
+
+    fn capture_layer(bucket_ms: u64, budgets: &[(&str, u64)]) -> (TestLayer, SharedBuf) {
         let buf = SharedBuf::default();
         let mut builder = SamplingLayer::<Registry>::builder()
             .without_time()
             .with_target(false)
+            .with_ansi(false)
             .bucket_duration(Duration::from_millis(bucket_ms))
             .writer(buf.clone());
         for &(filter, limit) in budgets {
@@ -363,4 +452,3322 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn background_flush_rotates_without_events() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(20))
+            .budget(EnvFilter::new("error"), 1000)
+            .writer(buf.clone())
+            .with_background_flush()
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10 {
+                tracing::error!("event");
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            assert_eq!(
+                buf.lines().len(),
+                10,
+                "background thread should release the bucket without new events"
+            );
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_flusher_rotates_without_events() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(20))
+            .budget(EnvFilter::new("error"), 1000)
+            .writer(buf.clone())
+            .with_tokio_flusher(rt.handle().clone())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10 {
+                tracing::error!("event");
+            }
+            rt.block_on(async { tokio::time::sleep(Duration::from_millis(100)).await });
+            assert_eq!(
+                buf.lines().len(),
+                10,
+                "tokio task should release the bucket without new events"
+            );
+        });
+    }
+
+    #[test]
+    fn non_blocking_writes_from_worker() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("error"), 10)
+            .writer(buf.clone())
+            .non_blocking(16)
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                tracing::error!("event");
+            }
+        });
+
+        // Dropping the layer joins the worker, so everything is written.
+        assert_eq!(buf.lines().len(), 10);
+        assert_eq!(stats.lost(), 0);
+    }
+
+    #[test]
+    fn handle_downcasts_from_dispatch() {
+        let (layer, buf) = capture_layer(1_000, &[("error", 10)]);
+        let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            for _ in 0..100 {
+                tracing::error!("event");
+            }
+            assert!(buf.lines().is_empty());
+
+            tracing::dispatcher::get_default(|d| {
+                d.downcast_ref::<crate::Handle>().unwrap().flush()
+            });
+        });
+
+        assert_eq!(buf.lines().len(), 10);
+    }
+
+    #[test]
+    fn flush_on_panic_writes_buffered_events() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("error"), 10)
+            .writer(buf.clone())
+            .flush_on_panic()
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                tracing::error!("event");
+            }
+            let result = std::panic::catch_unwind(|| panic!("boom"));
+            assert!(result.is_err());
+            assert_eq!(buf.lines().len(), 10);
+        });
+    }
+
+    #[cfg(all(unix, feature = "signal"))]
+    #[test]
+    fn signal_triggers_flush() {
+        use signal_hook::consts::SIGUSR1;
+
+        let (layer, buf) = capture_layer(1_000, &[("error", 10)]);
+        layer.handle().flush_on_signal([SIGUSR1]).unwrap();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                tracing::error!("event");
+            }
+            signal_hook::low_level::raise(SIGUSR1).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(buf.lines().len(), 10);
+        });
+    }
+
+    #[test]
+    fn guard_flushes_on_drop() {
+        let buf = SharedBuf::default();
+        let (layer, _stats, guard) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("error"), 10)
+            .writer(buf.clone())
+            .non_blocking(16)
+            .build_with_guard();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                tracing::error!("event");
+            }
+            assert!(buf.lines().is_empty());
+            drop(guard);
+            assert_eq!(buf.lines().len(), 10);
+        });
+    }
+
+    #[test]
+    fn drop_summary_reports_dropped_levels() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("info"), 10)
+            .writer(buf.clone())
+            .drop_summary(tracing::Level::WARN)
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..50 {
+                tracing::error!("event");
+                tracing::info!("event");
+            }
+        });
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 11);
+        let summary = lines
+            .iter()
+            .find(|l| l.contains("tracing_log_sample"))
+            .expect("summary line");
+        assert!(summary.starts_with(" WARN"), "{summary}");
+        assert!(summary.contains("90 events dropped (error="), "{summary}");
+    }
+
+    #[test]
+    fn drop_summary_custom_format() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("error"), 10)
+            .writer(buf.clone())
+            .drop_summary_format(|s| format!("dropped {} at {}\n", s.total(), s.level()))
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                tracing::error!("event");
+            }
+        });
+
+        assert!(buf.lines().contains(&"dropped 90 at WARN".to_string()));
+    }
+
+    #[test]
+    fn report_stats_every_writes_counters() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(10))
+            .budget(EnvFilter::new("error"), 1000)
+            .writer(buf.clone())
+            .report_stats_every(Duration::from_millis(20))
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("event");
+            std::thread::sleep(Duration::from_millis(30));
+            tracing::error!("event");
+        });
+
+        let lines = buf.lines();
+        let report = lines
+            .iter()
+            .find(|l| l.contains("sampling stats"))
+            .expect("stats report line");
+        assert!(report.contains("received=2"), "{report}");
+        assert!(report.contains("dropped=0"), "{report}");
+    }
+
+    #[test]
+    fn per_budget_counters() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("error"), 5)
+            .budget(EnvFilter::new("info"), 10)
+            .writer(buf.clone())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..20 {
+                tracing::error!("event");
+            }
+            for _ in 0..20 {
+                tracing::info!("event");
+            }
+        });
+
+        let error = stats.budget(0).unwrap();
+        let info = stats.budget(1).unwrap();
+        assert_eq!(error.received, 20);
+        assert_eq!(error.sampled, 5);
+        assert_eq!(error.dropped, 0, "error ejections cascade into info");
+        assert_eq!(info.received, 35);
+        assert_eq!(info.sampled, 10);
+        assert_eq!(info.dropped, 25);
+        assert_eq!(stats.dropped(), 25);
+        assert!(stats.budget(2).is_none());
+        assert_eq!(buf.lines().len(), 15);
+    }
+
+    #[test]
+    fn per_level_and_target_breakdown() {
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("debug"), 10)
+            .writer(SharedBuf::default())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..50 {
+                tracing::debug!(target: "noisy", "event");
+            }
+            tracing::error!(target: "quiet", "event");
+        });
+
+        let debug = stats.level(tracing::Level::DEBUG);
+        let error = stats.level(tracing::Level::ERROR);
+        assert_eq!(debug.sampled + debug.dropped, 50);
+        assert_eq!(error.sampled + error.dropped, 1);
+        assert_eq!(debug.dropped + error.dropped, 41);
+
+        let targets = stats.targets();
+        assert_eq!(targets[0].0, "noisy");
+        assert_eq!(stats.target("noisy").unwrap().dropped, debug.dropped);
+        assert!(stats.target("missing").is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn stats_snapshot_serializes() {
+        let (layer, buf) = capture_layer(1_000, &[("error", 5)]);
+        let stats = layer.shared.stats.clone();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10 {
+                tracing::error!("event");
+            }
+        });
+        assert_eq!(buf.lines().len(), 5);
+
+        let json = serde_json::to_value(stats.snapshot()).unwrap();
+        assert_eq!(json["received"], 10);
+        assert_eq!(json["dropped"], 5);
+        assert_eq!(json["budgets"][0]["sampled"], 5);
+        assert_eq!(json["levels"]["error"]["dropped"], 5);
+    }
+
+    #[test]
+    fn render_prometheus_counters() {
+        let (layer, _buf) = capture_layer(1_000, &[("error", 5), ("info", 5)]);
+        let stats = layer.shared.stats.clone();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..20 {
+                tracing::error!("event");
+            }
+        });
+
+        let text = stats.render_prometheus();
+        assert!(text.contains("# TYPE tracing_log_sample_received_total counter\n"));
+        assert!(text.contains("\ntracing_log_sample_received_total 20\n"));
+        assert!(text.contains("\ntracing_log_sample_dropped_total 10\n"));
+        assert!(text.contains("\ntracing_log_sample_budget_sampled_total{budget=\"1\"} 5\n"));
+        assert!(text.contains("\ntracing_log_sample_level_dropped_total{level=\"error\"} 10\n"));
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn opentelemetry_observes_counters() {
+        use opentelemetry::KeyValue;
+        use opentelemetry::metrics::{
+            AsyncInstrument, AsyncInstrumentBuilder, Callback, InstrumentProvider, Meter,
+            ObservableCounter,
+        };
+
+        #[derive(Default)]
+        struct Capture(Mutex<Vec<(String, Callback<u64>)>>);
+
+        impl InstrumentProvider for Capture {
+            fn u64_observable_counter(
+                &self,
+                builder: AsyncInstrumentBuilder<'_, ObservableCounter<u64>, u64>,
+            ) -> ObservableCounter<u64> {
+                let mut callbacks = self.0.lock().unwrap();
+                for callback in builder.callbacks {
+                    callbacks.push((builder.name.to_string(), callback));
+                }
+                ObservableCounter::new()
+            }
+        }
+
+        struct Observed(Mutex<Vec<(u64, Vec<KeyValue>)>>);
+
+        impl AsyncInstrument<u64> for Observed {
+            fn observe(&self, value: u64, attributes: &[KeyValue]) {
+                self.0.lock().unwrap().push((value, attributes.to_vec()));
+            }
+        }
+
+        let (layer, _buf) = capture_layer(1_000, &[("error", 5)]);
+        let stats = layer.shared.stats.clone();
+        let provider = Arc::new(Capture::default());
+        stats.register_opentelemetry(&Meter::new(provider.clone()));
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..20 {
+                tracing::error!("event");
+            }
+        });
+
+        let observe = |name: &str| {
+            let callbacks = provider.0.lock().unwrap();
+            let (_, callback) = callbacks.iter().find(|(n, _)| n == name).unwrap();
+            let observed = Observed(Mutex::default());
+            callback(&observed);
+            observed.0.into_inner().unwrap()
+        };
+        assert_eq!(observe("tracing_log_sample.received")[0].0, 20);
+        assert_eq!(observe("tracing_log_sample.dropped")[0].0, 15);
+        let budget = observe("tracing_log_sample.budget.sampled");
+        assert_eq!(budget[0].0, 5);
+        assert_eq!(budget[0].1, vec![KeyValue::new("budget", 0i64)]);
+    }
+
+    #[cfg(feature = "statsd")]
+    #[test]
+    fn statsd_reports_deltas() {
+        let agent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut reporter = crate::StatsdReporter::new(agent.local_addr().unwrap())
+            .unwrap()
+            .prefix("app")
+            .tag("env", "test");
+
+        let (layer, _buf) = capture_layer(1_000, &[("error", 5)]);
+        let stats = layer.shared.stats.clone();
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..20 {
+                tracing::error!("event");
+            }
+        });
+
+        let mut datagram = [0; 1500];
+        reporter.report(&stats).unwrap();
+        let n = agent.recv(&mut datagram).unwrap();
+        let lines = std::str::from_utf8(&datagram[..n]).unwrap();
+        assert!(lines.contains("app.received:20|c|#env:test"), "{lines}");
+        assert!(
+            lines.contains("app.budget.0.dropped:15|c|#env:test"),
+            "{lines}"
+        );
+        assert!(!lines.contains("lost"), "unchanged counters are omitted");
+
+        reporter.report(&stats).unwrap();
+        assert!(
+            agent.recv(&mut datagram).is_err(),
+            "no deltas means nothing is sent"
+        );
+    }
+
+    #[cfg(feature = "debug-server")]
+    #[test]
+    fn debug_server_serves_state() {
+        use std::io::Read;
+
+        let (layer, _buf) = capture_layer(1_000, &[("error", 5)]);
+        let addr = layer.handle().serve_debug("127.0.0.1:0").unwrap();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                tracing::error!("event");
+            }
+
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+            let body = response.split("\r\n\r\n").nth(1).unwrap();
+            let json: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(json["bucket_duration_ms"], 1000.0);
+            assert_eq!(json["budgets"][0]["filter"], "error");
+            assert_eq!(json["budgets"][0]["capacity"], 5);
+            assert_eq!(json["budgets"][0]["fill"], 3);
+            assert_eq!(json["stats"]["received"], 3);
+        });
+    }
+
+    #[test]
+    fn recent_events_keeps_last_written() {
+        let recent = crate::RecentEvents::new(3);
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_millis(1_000))
+            .budget(EnvFilter::new("error"), 10)
+            .writer(SharedBuf::default())
+            .recent_events(recent.clone())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..5 {
+                tracing::error!(i, "seq");
+            }
+        });
+
+        let events: Vec<_> = recent.get().iter().map(|e| strip_ansi(e)).collect();
+        assert_eq!(events.len(), 3);
+        assert!(events[0].trim_end().ends_with("i=2"), "{events:?}");
+        assert!(events[2].trim_end().ends_with("i=4"), "{events:?}");
+    }
+
+    #[test]
+    fn reservoir_occupancy_gauges() {
+        let (layer, _buf) = capture_layer(50, &[("error", 200)]);
+        let stats = layer.shared.stats.clone();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..4 {
+                tracing::error!("event");
+            }
+            let budget = stats.budget(0).unwrap();
+            assert_eq!(budget.capacity, 10);
+            assert_eq!(budget.fill, 4);
+
+            for _ in 0..26 {
+                tracing::error!("event");
+            }
+            std::thread::sleep(Duration::from_millis(60));
+            tracing::error!("rotate");
+
+            let budget = stats.budget(0).unwrap();
+            assert_eq!(budget.last_bucket_received, 30);
+            assert_eq!(budget.fill, 1);
+        });
+    }
+
+    #[test]
+    fn write_latency_is_recorded() {
+        let (layer, _buf) = capture_layer(1_000, &[("error", 5)]);
+        let stats = layer.shared.stats.clone();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("event");
+        });
+
+        let latency = stats.write_latency();
+        assert_eq!(latency.count(), 1);
+        assert!(latency.quantile(0.99) <= latency.max());
+    }
+
+    #[test]
+    fn sample_ratio_tracks_last_bucket() {
+        let (layer, _buf) = capture_layer(10_000, &[("error", 10)]);
+        let stats = layer.shared.stats.clone();
+        let shared = layer.shared.clone();
+        let subscriber = Registry::default().with(layer);
+
+        assert_eq!(stats.sample_ratio(), 1.0);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..400 {
+                tracing::error!(i, "event");
+            }
+            shared.flush();
+
+            assert_eq!(stats.sample_ratio(), 0.25);
+            let budget = stats.budget(0).unwrap();
+            assert_eq!(budget.last_bucket_sampled, 100);
+            assert_eq!(budget.sample_ratio(), 0.25);
+        });
+    }
+
+    #[test]
+    fn rates_are_per_second() {
+        let (layer, _buf) = capture_layer(10_000, &[("error", 10)]);
+        let stats = layer.shared.stats.clone();
+        let shared = layer.shared.clone();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            std::thread::sleep(Duration::from_millis(100));
+            for i in 0..200 {
+                tracing::error!(i, "event");
+            }
+            assert_eq!(stats.received_per_sec(), 0.0);
+            shared.flush();
+
+            // 200 events in a little over 100ms.
+            let received = stats.received_per_sec();
+            assert!(received > 200.0 && received <= 2_000.0, "{received}");
+            assert!(stats.sampled_per_sec() > 0.0);
+            assert!((stats.sampled_per_sec() + stats.dropped_per_sec() - received).abs() < 1e-6);
+        });
+    }
+
+    #[test]
+    fn drops_are_counted_per_callsite() {
+        let (layer, _buf) = capture_layer(60_000, &[("info", 1)]);
+        let stats = layer.shared.stats.clone();
+        let subscriber = Registry::default().with(layer);
+
+        let loud_line = line!() + 5;
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..100 {
+                tracing::info!(i, "quiet");
+                for _ in 0..5 {
+                    tracing::info!(i, "loud");
+                }
+            }
+        });
+
+        let top = stats.top_dropped_callsites(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].metadata.line(), Some(loud_line));
+        assert_eq!(stats.top_dropped_callsites(10).len(), 2);
+        let total: u64 = stats
+            .top_dropped_callsites(10)
+            .iter()
+            .map(|c| c.dropped)
+            .sum();
+        assert_eq!(total, stats.dropped());
+    }
+
+    #[test]
+    fn budgets_can_be_looked_up_by_name() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_secs(60))
+            .budget_named("errors", EnvFilter::new("error"), 1)
+            .budget(EnvFilter::new("info"), 1)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                tracing::error!("error");
+            }
+            assert_eq!(handle.budget("errors").unwrap().received, 3);
+        });
+
+        assert_eq!(stats.budget_index("errors"), Some(0));
+        assert_eq!(stats.budget_name(1), None);
+        assert_eq!(stats.budget_named("errors").unwrap().received, 3);
+        assert!(stats.budget_named("missing").is_none());
+        assert!(handle.budget("errors").is_none());
+        assert!(stats.render_prometheus().contains(r#"budget="errors""#));
+    }
+
+    #[test]
+    fn budgets_report_live_configuration() {
+        let (layer, _) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_millis(100))
+            .budget_named("errors", EnvFilter::new("error"), 1000)
+            .budget(EnvFilter::new("info"), 5)
+            .budget(EnvFilter::new("debug"), 0)
+            .build();
+
+        let budgets = layer.budgets();
+        assert_eq!(budgets.len(), 2);
+        assert_eq!(budgets[0].name.as_deref(), Some("errors"));
+        assert_eq!(budgets[0].filter, "error");
+        assert_eq!(budgets[0].limit_per_second, 1000);
+        assert_eq!(budgets[0].capacity, 100);
+        assert_eq!(budgets[1].name, None);
+        assert_eq!(budgets[1].capacity, 1);
+    }
+
+    #[test]
+    fn try_build_reports_invalid_configuration() {
+        let err = |builder: SamplingLayerBuilder<Registry>| builder.try_build().err();
+
+        assert_eq!(
+            err(SamplingLayer::builder().bucket_duration(Duration::ZERO)),
+            Some(BuildError::ZeroBucketDuration)
+        );
+        assert_eq!(err(SamplingLayer::builder()), Some(BuildError::NoBudgets));
+        assert_eq!(
+            err(SamplingLayer::builder()
+                .budget(EnvFilter::new("error"), 10)
+                .budget(EnvFilter::new("info"), 0)),
+            Some(BuildError::ZeroCapacity { budget: 1 })
+        );
+        assert_eq!(
+            err(SamplingLayer::builder()
+                .bucket_duration(Duration::from_secs(1))
+                .budget(EnvFilter::new("info"), u64::MAX)),
+            Some(BuildError::CapacityTooLarge {
+                budget: 0,
+                capacity: usize::MAX,
+            })
+        );
+        let many = (0..65).fold(SamplingLayer::builder(), |builder, _| {
+            builder.budget(EnvFilter::new("info"), 100)
+        });
+        assert_eq!(err(many), Some(BuildError::TooManyBudgets { count: 65 }));
+        assert!(err(SamplingLayer::builder().budget(EnvFilter::new("info"), 100)).is_none());
+    }
+
+    #[test]
+    fn budget_str_surfaces_parse_errors() {
+        assert!(
+            SamplingLayer::<Registry>::builder()
+                .budget_str("mycrate=debug", 500)
+                .is_ok()
+        );
+        assert!(
+            SamplingLayer::<Registry>::builder()
+                .budget_str("mycrate=notalevel", 500)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn level_budgets_match_by_level() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .with_target(false)
+            .bucket_duration(Duration::from_secs(60))
+            .budget_level(Level::WARN, 100)
+            .budget_level_range(Level::DEBUG..=Level::INFO, 100)
+            .writer(buf.clone())
+            .build();
+        let budgets = layer.budgets();
+        assert_eq!(budgets[0].filter, "warn");
+        assert_eq!(budgets[1].filter, "debug..=info");
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("error");
+            tracing::warn!("warn");
+            tracing::info!("info");
+            tracing::debug!("debug");
+            tracing::trace!("trace");
+        });
+
+        assert_eq!(stats.budget(0).unwrap().received, 2);
+        assert_eq!(stats.budget(1).unwrap().received, 2);
+        assert_eq!(buf.lines().len(), 4);
+    }
+
+    #[test]
+    fn no_cascade_drops_ejected_events() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .with_target(false)
+            .bucket_duration(Duration::from_secs(1))
+            .budget_with(Budget::new(EnvFilter::new("error")).limit(5).no_cascade())
+            .budget_with(Budget::level(Level::INFO).named("rest").limit(50))
+            .writer(buf.clone())
+            .build();
+        assert!(!layer.budgets()[0].cascade);
+        assert!(layer.budgets()[1].cascade);
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..20 {
+                tracing::error!(i, "error");
+            }
+        });
+
+        let errors = stats.budget(0).unwrap();
+        assert_eq!(errors.sampled, 5);
+        assert_eq!(errors.dropped, 15);
+        assert_eq!(stats.budget_named("rest").unwrap().received, 0);
+        assert_eq!(buf.lines().len(), 5);
+    }
+
+    #[test]
+    fn budgets_can_have_their_own_writer() {
+        let buf = SharedBuf::default();
+        let errors = SharedBuf::default();
+        let (layer, _) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .with_target(false)
+            .bucket_duration(Duration::from_secs(1))
+            .budget_with(Budget::level(Level::ERROR).limit(10).writer(errors.clone()))
+            .budget_level(Level::INFO, 10)
+            .with_ansi(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("error");
+            tracing::info!("info");
+        });
+
+        assert_eq!(errors.lines(), ["ERROR error"]);
+        assert_eq!(buf.lines(), [" INFO info"]);
+    }
+
+    #[test]
+    fn cascade_depth_limits_reservoirs_visited() {
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_secs(1))
+            .budget_level(Level::ERROR, 1)
+            .budget_level(Level::ERROR, 1)
+            .budget_level(Level::ERROR, 1)
+            .max_cascade_depth(2)
+            .writer(SharedBuf::default())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                tracing::error!(i, "error");
+            }
+        });
+
+        assert_eq!(stats.budget(0).unwrap().received, 10);
+        assert_eq!(stats.budget(1).unwrap().received, 9);
+        assert_eq!(stats.budget(1).unwrap().dropped, 8);
+        assert_eq!(stats.budget(2).unwrap().received, 0);
+        assert_eq!(stats.sampled(), 2);
+    }
+
+    #[test]
+    fn first_match_only_books_events_once() {
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_secs(1))
+            .budget_level(Level::ERROR, 1)
+            .budget_level(Level::INFO, 100)
+            .first_match_only()
+            .writer(SharedBuf::default())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                tracing::error!(i, "error");
+                tracing::info!(i, "info");
+            }
+        });
+
+        assert_eq!(stats.budget(0).unwrap().received, 10);
+        assert_eq!(stats.budget(0).unwrap().dropped, 9);
+        assert_eq!(stats.budget(1).unwrap().received, 10);
+        assert_eq!(stats.sampled(), 11);
+    }
+
+    #[test]
+    fn excluded_events_skip_budget() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .bucket_duration(Duration::from_secs(1))
+            .budget_with(
+                Budget::new(EnvFilter::new("info"))
+                    .exclude(EnvFilter::new("noisy=info"))
+                    .limit(100),
+            )
+            .with_ansi(false)
+            .writer(buf.clone())
+            .build();
+        assert_eq!(layer.budgets()[0].filter, "info except noisy=info");
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "noisy", "skipped");
+            tracing::warn!(target: "noisy", "also skipped");
+            tracing::info!(target: "quiet", "kept");
+            tracing::debug!(target: "quiet", "too verbose");
+        });
+
+        assert_eq!(stats.received(), 1);
+        assert_eq!(buf.lines(), [" INFO quiet: kept"]);
+    }
+
+    #[test]
+    fn targets_and_level_filters_as_budgets() {
+        use tracing_subscriber::filter::{LevelFilter, Targets};
+
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_secs(1))
+            .budget(Targets::new().with_target("app", Level::DEBUG), 100)
+            .budget(LevelFilter::WARN, 100)
+            .writer(SharedBuf::default())
+            .build();
+        assert_eq!(layer.budgets()[1].filter, LevelFilter::WARN.to_string());
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!(target: "app", "kept");
+            tracing::trace!(target: "app", "too verbose");
+            tracing::info!(target: "dep", "too verbose");
+            tracing::warn!(target: "dep", "kept");
+        });
+
+        assert_eq!(stats.budget(0).unwrap().received, 1);
+        assert_eq!(stats.budget(1).unwrap().received, 1);
+    }
+
+    #[test]
+    fn closure_budgets_match_custom_logic() {
+        let allow = ["payments", "billing"];
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_secs(1))
+            .budget_fn(
+                move |meta, _| allow.iter().any(|t| meta.target().starts_with(t)),
+                100,
+            )
+            .writer(SharedBuf::default())
+            .build();
+        assert_eq!(layer.budgets()[0].filter, "<fn>");
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(target: "payments::card", "kept");
+            tracing::trace!(target: "billing", "kept");
+            tracing::error!(target: "other", "skipped");
+        });
+
+        assert_eq!(stats.received(), 2);
+    }
+
+    #[test]
+    fn budgets_match_on_field_values() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .without_time()
+            .with_target(false)
+            .bucket_duration(Duration::from_secs(1))
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .field_matches("status", |v| v.as_u64().is_some_and(|s| s >= 500))
+                    .limit(100),
+            )
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .field_matches("tenant", |v| v.as_str() == Some("acme"))
+                    .limit(100),
+            )
+            .writer(buf.clone())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for status in [200u64, 404, 500, 503] {
+                tracing::info!(status, "request");
+            }
+            tracing::info!(tenant = "acme", "kept");
+            tracing::info!(tenant = "other", "skipped");
+            tracing::info!("no fields");
+        });
+
+        assert_eq!(stats.budget(0).unwrap().received, 2);
+        assert_eq!(stats.budget(1).unwrap().received, 1);
+        assert_eq!(buf.lines().len(), 3);
+    }
+
+    #[test]
+    fn span_budgets_match_enclosing_spans() {
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_secs(1))
+            .budget_with(Budget::level(Level::INFO).in_span("batch_job").limit(100))
+            .writer(SharedBuf::default())
+            .build();
+        assert_eq!(layer.budgets()[0].filter, "info in span batch_job");
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("outside");
+            let job = tracing::info_span!("batch_job");
+            let _job = job.enter();
+            tracing::info!("inside");
+            let _step = tracing::info_span!("step").entered();
+            tracing::info!("nested");
+            tracing::info!(parent: None, "explicit root");
+        });
+
+        assert_eq!(stats.received(), 2);
+    }
+
+    #[test]
+    fn global_filter_rejects_before_budgets() {
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_secs(1))
+            .with_global_filter(EnvFilter::new("trace,noisy=off"))
+            .budget_level(Level::ERROR, 100)
+            .budget_level(Level::INFO, 100)
+            .writer(SharedBuf::default())
+            .build();
+        let subscriber = Registry::default().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!(target: "noisy", "rejected");
+            tracing::info!(target: "noisy", "rejected");
+            tracing::error!(target: "app", "kept");
+            tracing::info!(target: "app", "kept");
+        });
+
+        assert_eq!(stats.received(), 2);
+        assert_eq!(stats.dropped(), 0);
+    }
+
+    #[test]
+    fn per_layer_filter_does_not_disable_other_layers() {
+        use tracing_subscriber::filter::filter_fn;
+
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .bucket_duration(Duration::from_secs(1))
+            .budget_level(Level::ERROR, 100)
+            .writer(SharedBuf::default())
+            .build();
+        let other = SharedBuf::default();
+        let subscriber = Registry::default()
+            .with(layer.with_filter(filter_fn(|meta| meta.target() != "hidden")))
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .without_time()
+                    .with_target(false)
+                    .with_writer(other.clone()),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!(target: "hidden", "filtered out");
+            tracing::error!(target: "shown", "sampled");
+            tracing::info!(target: "shown", "no budget");
+        });
+
+        assert_eq!(stats.received(), 1);
+        assert_eq!(other.lines().len(), 3);
+    }
+
+    #[test]
+    fn sampling_filter_gates_downstream_layer() {
+        use crate::SamplingFilter;
+        use tracing_subscriber::Layer;
+
+        let (filter, stats) = SamplingFilter::builder()
+            .budget_level(Level::WARN, 1)
+            .bucket_duration(Duration::from_secs(1))
+            .build();
+        let buf = SharedBuf::default();
+        let fmt = tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(buf.clone())
+            .without_time()
+            .with_filter(filter);
+        let subscriber = tracing_subscriber::registry().with(fmt);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                tracing::warn!(i, "noisy");
+            }
+            tracing::info!("unbudgeted");
+        });
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        assert!(lines[0].contains("i=0"));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.received, 10);
+        assert_eq!(snapshot.sampled, 1);
+        assert_eq!(snapshot.dropped, 9);
+    }
+
+    #[test]
+    fn re_emit_forwards_sampled_events() {
+        use crate::ReEmitted;
+        use tracing_subscriber::Layer;
+
+        let (layer, stats) = SamplingLayer::builder()
+            .budget_level(Level::WARN, 3)
+            .bucket_duration(Duration::from_secs(1))
+            .re_emit()
+            .build();
+        let handle = layer.handle();
+        let buf = SharedBuf::default();
+        let downstream = tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_writer(buf.clone())
+            .without_time()
+            .with_filter(ReEmitted);
+        let subscriber = tracing_subscriber::registry().with(layer).with(downstream);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                tracing::warn!(i, flag = true, "noisy {}", "event");
+            }
+            assert!(buf.lines().is_empty());
+            handle.flush();
+        });
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 3, "{lines:?}");
+        for line in &lines {
+            assert!(line.contains("noisy event"), "{line}");
+            assert!(line.contains("flag=true"), "{line}");
+        }
+        assert_eq!(stats.sampled(), 3);
+        assert_eq!(stats.lost(), 0);
+    }
+
+    #[test]
+    fn pretty_formatter() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .with_ansi(false)
+            .writer(buf.clone())
+            .pretty()
+            .without_time()
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(answer = 42, "pretty");
+        });
+
+        let output = buf.lines().join("\n");
+        assert!(output.contains("pretty"), "{output}");
+        assert!(output.contains("answer: 42"), "{output}");
+        assert!(output.contains(file!()), "{output}");
+    }
+
+    #[test]
+    fn with_ansi_false_writes_plain_text() {
+        let (layer, buf) = capture_layer(1_000, &[("info", 10)]);
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(field = 1, "plain");
+        });
+
+        let raw = buf.0.lock().unwrap();
+        assert!(!raw.is_empty());
+        assert!(!raw.contains(&b'\x1b'), "{}", String::from_utf8_lossy(&raw));
+    }
+
+    #[test]
+    fn source_locations() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .with_ansi(false)
+            .writer(buf.clone())
+            .without_time()
+            .with_file(true)
+            .with_line_number(true)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let line = tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("located");
+            line!() - 1
+        });
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(
+            lines[0].contains(&format!("{}:{line}", file!())),
+            "{}",
+            lines[0]
+        );
+    }
+
+    #[test]
+    fn thread_names_are_those_of_the_emitter() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(buf.clone())
+            .without_time()
+            .with_thread_names(true)
+            .build();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+
+        let worker = dispatch.clone();
+        std::thread::Builder::new()
+            .name("emitter".into())
+            .spawn(move || {
+                tracing::dispatcher::with_default(&worker, || tracing::info!("from a thread"));
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        drop(dispatch);
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("emitter"), "{}", lines[0]);
+    }
+
+    #[test]
+    fn custom_timer() {
+        use tracing_subscriber::fmt::format::Writer;
+        use tracing_subscriber::fmt::time::FormatTime;
+
+        struct Fixed;
+
+        impl FormatTime for Fixed {
+            fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+                write!(w, "T+0")
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .with_ansi(false)
+            .writer(buf.clone())
+            .with_timer(Fixed)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || tracing::info!("timed"));
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("T+0 "), "{}", lines[0]);
+    }
+
+    #[test]
+    fn span_events_are_sampled() {
+        use tracing_subscriber::fmt::format::FmtSpan;
+
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .with_ansi(false)
+            .writer(buf.clone())
+            .without_time()
+            .with_span_events(FmtSpan::CLOSE)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("kept").in_scope(|| {});
+            tracing::debug_span!("unbudgeted").in_scope(|| {});
+        });
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        assert!(lines[0].contains("kept: "), "{}", lines[0]);
+        assert!(lines[0].contains("close"), "{}", lines[0]);
+        assert_eq!(stats.received(), 1);
+    }
+
+    #[test]
+    fn json_format() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(buf.clone())
+            .json()
+            .without_time()
+            .flatten_event(true)
+            .with_span_list(true)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("outer", id = 7);
+            let _guard = span.enter();
+            tracing::info!(n = 1, quoted = "a \"b\"\n", "hello");
+        });
+
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        let value: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "level": "INFO",
+                "message": "hello",
+                "n": 1,
+                "quoted": "a \"b\"\n",
+                "target": "tracing_log_sample::tests",
+                "span": {"name": "outer", "id": 7},
+                "spans": [{"name": "outer", "id": 7}],
+            })
+        );
+    }
+
+    #[test]
+    fn logfmt_format() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(buf.clone())
+            .logfmt()
+            .without_time()
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _outer = tracing::info_span!("outer").entered();
+            let _inner = tracing::info_span!("inner").entered();
+            tracing::info!(n = 1, path = "/a b", empty = "", "hello \"world\"");
+        });
+
+        assert_eq!(
+            buf.lines(),
+            [concat!(
+                r#"level=info target=tracing_log_sample::tests span=outer:inner "#,
+                r#"msg="hello \"world\"" n=1 path="/a b" empty="""#,
+            )]
+        );
+    }
+
+    #[test]
+    fn gelf_format() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(buf.clone())
+            .gelf("web-1")
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(id = 3, n = 1.5, "hello");
+        });
+
+        let raw = buf.0.lock().unwrap().clone();
+        let message = raw.strip_suffix(b"\0").expect("null terminated");
+        let mut value: serde_json::Value = serde_json::from_slice(message).unwrap();
+        let object = value.as_object_mut().unwrap();
+        assert!(object.remove("timestamp").unwrap().is_f64());
+        assert!(object.remove("_line").unwrap().is_u64());
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "version": "1.1",
+                "host": "web-1",
+                "short_message": "hello",
+                "level": 4,
+                "_target": "tracing_log_sample::tests",
+                "_file": file!(),
+                "__id": 3,
+                "_n": 1.5,
+            })
+        );
+    }
+
+    #[cfg(feature = "syslog")]
+    #[test]
+    fn syslog_sends_rfc5424_datagrams() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let writer = crate::SyslogWriter::udp(server.local_addr().unwrap()).unwrap();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .syslog(crate::Facility::Local0, writer)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(n = 1, "first");
+            tracing::info!("second");
+        });
+
+        let mut datagram = [0; 1500];
+        let mut recv = || {
+            let n = server.recv(&mut datagram).unwrap();
+            String::from_utf8_lossy(&datagram[..n]).into_owned()
+        };
+        let (first, second) = (recv(), recv());
+        // local0 is facility 16: 16 * 8 + warn (4).
+        assert!(first.starts_with("<132>1 "), "{first}");
+        assert!(
+            first.ends_with(&format!(" {} - - first n=1", std::process::id())),
+            "{first}"
+        );
+        assert!(second.starts_with("<134>1 "), "{second}");
+    }
+
+    #[cfg(all(unix, feature = "journald"))]
+    #[test]
+    fn journald_native_protocol() {
+        let dir = std::env::temp_dir().join(format!("tls-journald-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("socket");
+        let _ = std::fs::remove_file(&path);
+        let journal = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        journal
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let writer = crate::JournaldWriter::with_path(&path).unwrap();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .journald(writer)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!(request_id = 7, "two\nlines");
+        });
+
+        let mut datagram = [0; 4096];
+        let n = journal.recv(&mut datagram).unwrap();
+        let entry = &datagram[..n];
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let contains = |needle: &[u8]| entry.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"PRIORITY=3\n"));
+        assert!(contains(b"REQUEST_ID=7\n"));
+        let mut message = b"MESSAGE\n".to_vec();
+        message.extend_from_slice(&9u64.to_le_bytes());
+        message.extend_from_slice(b"two\nlines\n");
+        assert!(contains(&message), "{}", String::from_utf8_lossy(entry));
+    }
+
+    #[cfg(feature = "sentry")]
+    #[test]
+    fn sampled_errors_are_forwarded_to_sentry() {
+        let captured = sentry_core::test::with_captured_events(|| {
+            let (layer, _stats) = SamplingLayer::<Registry>::builder()
+                .budget_with(Budget::level(Level::ERROR).limit(2).forward_to_sentry())
+                .budget_level(Level::INFO, 100)
+                .writer(SharedBuf::default())
+                .with_ansi(false)
+                .bucket_duration(Duration::from_secs(1))
+                .build();
+            let subscriber = tracing_subscriber::registry().with(layer);
+            tracing::subscriber::with_default(subscriber, || {
+                for i in 0..5 {
+                    tracing::error!(i, "failed");
+                }
+                tracing::info!("fine");
+            });
+        });
+
+        assert_eq!(captured.len(), 2, "{captured:?}");
+        for event in &captured {
+            assert_eq!(event.level, sentry_core::protocol::Level::Error);
+            assert!(event.message.as_ref().unwrap().contains("failed"));
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_output_is_flushed_and_finished() {
+        use std::io::Read;
+
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(crate::Compressed::gzip(buf.clone()).flush_interval(Duration::ZERO))
+            .with_ansi(false)
+            .build();
+        let handle = layer.handle();
+        let guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+        tracing::info!("first");
+        tracing::info!("second");
+        handle.flush();
+
+        let decode = |raw: Vec<u8>| {
+            let mut out = String::new();
+            let result = flate2::read::GzDecoder::new(&raw[..]).read_to_string(&mut out);
+            (out, result.is_ok())
+        };
+        let (partial, finished) = decode(buf.0.lock().unwrap().clone());
+        assert!(!finished);
+        assert!(
+            partial.contains("first") && partial.contains("second"),
+            "{partial}"
+        );
+
+        drop(guard);
+        let (all, finished) = decode(buf.0.lock().unwrap().clone());
+        assert!(finished);
+        assert_eq!(all.lines().count(), 2, "{all}");
+    }
+
+    #[cfg(feature = "socket")]
+    #[test]
+    fn socket_writer_reconnects_and_bounds_backlog() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let socket = Arc::new(crate::SocketWriter::tcp(addr).unwrap().max_backlog(50));
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(socket.clone())
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .build();
+        let handle = layer.handle();
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+        // Nothing is listening, so these wait in the backlog, which only
+        // has room for the last two.
+        for i in 0..3 {
+            tracing::info!(i, "offline");
+            handle.flush();
+        }
+        assert_eq!(socket.dropped(), 1);
+
+        let listener = TcpListener::bind(addr).unwrap();
+        std::thread::sleep(Duration::from_millis(150));
+        tracing::info!("online");
+        handle.flush();
+
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let lines: Vec<String> = BufReader::new(stream)
+            .lines()
+            .take(3)
+            .map_while(Result::ok)
+            .collect();
+        assert_eq!(
+            lines,
+            [" INFO offline i=1", " INFO offline i=2", " INFO online"]
+        );
+    }
+
+    #[test]
+    fn archive_writer_receives_every_matched_event() {
+        let sampled = SharedBuf::default();
+        let archive = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 10)
+            .with_ansi(false)
+            .writer(sampled.clone())
+            .archive_writer(archive.clone())
+            .bucket_duration(Duration::from_secs(1))
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..100 {
+                tracing::info!(i, "event");
+            }
+            tracing::debug!("unmatched");
+        });
+
+        assert_eq!(sampled.lines().len(), 10);
+        let archived = archive.lines();
+        assert_eq!(archived.len(), 100);
+        assert!(archived[99].ends_with("event i=99"), "{archived:?}");
+        assert_eq!(stats.received(), 100);
+    }
+
+    #[test]
+    fn writers_route_by_event_metadata() {
+        use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+        let errors = SharedBuf::default();
+        let rest = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(
+                errors
+                    .clone()
+                    .with_max_level(Level::WARN)
+                    .and(rest.clone().with_min_level(Level::INFO)),
+            )
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::error!("broken");
+            tracing::info!("fine");
+            tracing::warn!("odd");
+        });
+
+        assert_eq!(errors.lines(), ["ERROR broken", " WARN odd"]);
+        assert_eq!(rest.lines(), [" INFO fine"]);
+    }
+
+    #[test]
+    fn strip_ansi_writer() {
+        use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+        let console = SharedBuf::default();
+        let file = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(console.clone().and(crate::StripAnsi::new(file.clone())))
+            .without_time()
+            .with_ansi(true)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(n = 1, "coloured");
+        });
+
+        let console = console.0.lock().unwrap().clone();
+        assert!(console.contains(&0x1b));
+        let file = String::from_utf8(file.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(file, " WARN tracing_log_sample::tests: coloured n=1\n");
+    }
+
+    #[test]
+    fn batches_are_coalesced_into_few_writes() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Clone, Default)]
+        struct CountingWriter(Arc<AtomicUsize>, SharedBuf);
+
+        impl Write for CountingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                self.1.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for CountingWriter {
+            type Writer = CountingWriter;
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let writer = CountingWriter::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(writer.clone())
+            .bucket_duration(Duration::from_secs(1))
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                tracing::info!(i, "same");
+            }
+            tracing::warn!("different");
+            tracing::info!("same again");
+        });
+
+        assert_eq!(writer.1.lines().len(), 12);
+        assert_eq!(writer.0.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn flush_policy() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Clone, Default)]
+        struct FlushCounter(Arc<AtomicUsize>);
+
+        impl Write for FlushCounter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for FlushCounter {
+            type Writer = FlushCounter;
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let flushes = |policy| {
+            let writer = FlushCounter::default();
+            let (layer, _stats) = SamplingLayer::<Registry>::builder()
+                .budget_level(Level::INFO, 100)
+                .writer(writer.clone())
+                .bucket_duration(Duration::from_secs(1))
+                .flush_policy(policy)
+                .build();
+            let handle = layer.handle();
+            let subscriber = tracing_subscriber::registry().with(layer);
+            let before_drop = tracing::subscriber::with_default(subscriber, || {
+                tracing::info!("a");
+                tracing::warn!("b");
+                handle.flush();
+                let before_drop = writer.0.load(Ordering::Relaxed);
+                tracing::info!("c");
+                before_drop
+            });
+            (before_drop, writer.0.load(Ordering::Relaxed))
+        };
+
+        // Every handle flush, and dropping the layer, ends with a flush of
+        // the writer.
+        assert_eq!(flushes(FlushPolicy::Never), (1, 2));
+        assert_eq!(flushes(FlushPolicy::PerBucket), (1, 2));
+        assert_eq!(flushes(FlushPolicy::PerEvent), (3, 5));
+        assert_eq!(flushes(FlushPolicy::Every(Duration::ZERO)), (2, 4));
+
+        // The worker thread flushes what it wrote last before exiting.
+        let writer = FlushCounter::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(writer.clone())
+            .non_blocking(16)
+            .flush_policy(FlushPolicy::Never)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || tracing::info!("a"));
+        assert!(writer.0.load(Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn write_errors_are_counted_and_reported() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(|| Broken)
+            .on_write_error({
+                let errors = errors.clone();
+                move |e| errors.lock().unwrap().push(e.kind())
+            })
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("a");
+            tracing::warn!("b");
+        });
+
+        assert_eq!(stats.write_errors(), 2);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            [io::ErrorKind::BrokenPipe, io::ErrorKind::BrokenPipe]
+        );
+    }
+
+    #[test]
+    fn fallback_writer_takes_over_from_failing_writer() {
+        use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+        #[derive(Clone, Default)]
+        struct Flaky {
+            broken: Arc<AtomicBool>,
+            attempts: Arc<AtomicUsize>,
+            buf: SharedBuf,
+        }
+
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.attempts.fetch_add(1, Ordering::Relaxed);
+                if self.broken.load(Ordering::Relaxed) {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.buf.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for Flaky {
+            type Writer = Flaky;
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let primary = Flaky::default();
+        let fallback = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .writer(primary.clone())
+            .fallback_writer(fallback.clone())
+            .fallback_policy(2, Duration::from_millis(100))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let emit = |message: &str| {
+                tracing::info!("{message}");
+                handle.flush();
+            };
+            primary.broken.store(true, Ordering::Relaxed);
+            for message in ["a", "b", "c", "d"] {
+                emit(message);
+            }
+            // Two failures switch to the fallback, so the primary is only
+            // tried for the first two events.
+            assert_eq!(primary.attempts.load(Ordering::Relaxed), 2);
+
+            primary.broken.store(false, Ordering::Relaxed);
+            emit("e");
+            std::thread::sleep(Duration::from_millis(150));
+            emit("f");
+            emit("g");
+        });
+
+        assert_eq!(
+            fallback.lines(),
+            [" INFO a", " INFO b", " INFO c", " INFO d", " INFO e"]
+        );
+        assert_eq!(primary.buf.lines(), [" INFO f", " INFO g"]);
+        assert_eq!(stats.write_errors(), 2);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_writer() {
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        struct AsyncBuf(SharedBuf);
+
+        impl tokio::io::AsyncWrite for AsyncBuf {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Poll::Ready(self.0.write(buf))
+            }
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+            fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .async_writer(rt.handle().clone(), AsyncBuf(buf.clone()), 16)
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("a");
+            tracing::warn!("b");
+        });
+        assert!(buf.lines().is_empty(), "written by the task");
+
+        rt.block_on(async { tokio::time::sleep(Duration::from_millis(10)).await });
+        assert_eq!(buf.lines(), [" INFO a", " WARN b"]);
+        assert_eq!(stats.lost(), 0);
+    }
+
+    #[test]
+    fn max_release_batch_caps_and_carries_over() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 10_000)
+            .bucket_duration(Duration::from_millis(20))
+            .max_release_batch(10)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let written = || buf.lines().iter().filter(|l| l.contains("event")).count();
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..50 {
+                tracing::info!(i, "event");
+            }
+            let tick = || {
+                std::thread::sleep(Duration::from_millis(30));
+                tracing::info!("tick");
+            };
+            // Rotation moves the bucket into the smear queue.
+            tick();
+            assert_eq!(written(), 0);
+            // Overdue, so everything would be released at once without the
+            // cap. The rest is carried into the next bucket.
+            tick();
+            assert_eq!(written(), 10);
+            // Carried events are not carried twice.
+            tick();
+            assert_eq!(written(), 50);
+        });
+    }
+
+    #[test]
+    fn max_release_time_paces_slow_writers() {
+        #[derive(Clone, Default)]
+        struct SlowWriter(SharedBuf);
+
+        impl Write for SlowWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let lines = buf.iter().filter(|&&b| b == b'\n').count() as u32;
+                std::thread::sleep(Duration::from_millis(1) * lines);
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for SlowWriter {
+            type Writer = SlowWriter;
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let writer = SlowWriter::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 10_000)
+            .bucket_duration(Duration::from_millis(20))
+            .max_release_time(Duration::from_millis(5))
+            .writer(writer.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let written = || {
+            writer
+                .0
+                .lines()
+                .iter()
+                .filter(|l| l.contains("event"))
+                .count()
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            // Measure the writer.
+            for _ in 0..5 {
+                tracing::info!("warmup");
+            }
+            handle.flush();
+
+            for i in 0..50 {
+                tracing::info!(i, "event");
+            }
+            let tick = || {
+                std::thread::sleep(Duration::from_millis(30));
+                tracing::info!("tick");
+            };
+            tick();
+            assert_eq!(written(), 0);
+            // At 1ms per event, only about 5 fit in the budget.
+            tick();
+            let released = written();
+            assert!((1..=5).contains(&released), "{released}");
+        });
+    }
+
+    #[test]
+    fn bypass_sampling_writes_errors_immediately() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 1)
+            .bucket_duration(Duration::from_secs(1))
+            .bypass_sampling(tracing_subscriber::filter::LevelFilter::ERROR)
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("held");
+            for i in 0..3 {
+                tracing::error!(i, "failed");
+            }
+            assert_eq!(
+                buf.lines(),
+                ["ERROR failed i=0", "ERROR failed i=1", "ERROR failed i=2"]
+            );
+        });
+
+        assert_eq!(buf.lines().len(), 4);
+        assert_eq!(stats.dropped(), 0);
+        assert_eq!(stats.sampled(), 4);
+    }
+
+    #[test]
+    fn priority_fn_forces_immediate_emission() {
+        use tracing::field::{Field, Visit};
+
+        struct Fatal(bool);
+
+        impl Visit for Fatal {
+            fn record_bool(&mut self, field: &Field, value: bool) {
+                if field.name() == "fatal" {
+                    self.0 = value;
+                }
+            }
+            fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+        }
+
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 1)
+            .bucket_duration(Duration::from_secs(1))
+            .priority(|_meta, event| {
+                let mut fatal = Fatal(false);
+                event.record(&mut fatal);
+                if fatal.0 {
+                    crate::Priority::Immediate
+                } else {
+                    crate::Priority::Sampled
+                }
+            })
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(fatal = false, "held");
+            tracing::warn!(fatal = true, "now");
+            assert_eq!(buf.lines(), [" WARN now fatal=true"]);
+        });
+        assert_eq!(buf.lines().len(), 2);
+    }
+
+    #[test]
+    fn max_delay_bounds_time_to_write() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .bucket_duration(Duration::from_secs(1))
+            .max_delay(Duration::from_millis(100))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        // The buckets are shortened to fit the delay.
+        assert_eq!(layer.budgets()[0].capacity, 10);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let start = std::time::Instant::now();
+            tracing::info!("alert");
+            while buf.lines().is_empty() {
+                assert!(start.elapsed() < Duration::from_millis(500));
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            assert_eq!(buf.lines(), [" INFO alert"]);
+        });
+    }
+
+    #[test]
+    fn emit_policies() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::WARN)
+                    .limit(10)
+                    .emit(crate::Emit::Immediate),
+            )
+            .budget_level(Level::INFO, 10)
+            .emit(crate::Emit::AtRotation)
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("a");
+            tracing::info!("b");
+            tracing::warn!("c");
+            assert_eq!(buf.lines(), [" WARN a", " WARN c"]);
+
+            // Written events aren't written again when the bucket ends.
+            handle.flush();
+            assert_eq!(buf.lines(), [" WARN a", " WARN c", " INFO b"]);
+        });
+
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 100)
+            .emit(crate::Emit::AtRotation)
+            .bucket_duration(Duration::from_millis(50))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..3 {
+                tracing::info!(i);
+            }
+            std::thread::sleep(Duration::from_millis(60));
+            tracing::info!(i = 3);
+            assert_eq!(buf.lines(), [" INFO i=0", " INFO i=1", " INFO i=2"]);
+        });
+    }
+
+    #[test]
+    fn jitter_buckets_offsets_first_bucket() {
+        let bucket = Duration::from_secs(1);
+        let before = std::time::Instant::now();
+        let starts: Vec<_> = (0..8)
+            .map(|_| {
+                let (layer, _stats) = SamplingLayer::<Registry>::builder()
+                    .budget_level(Level::INFO, 10)
+                    .bucket_duration(bucket)
+                    .jitter_buckets()
+                    .build();
+                let start = layer.shared.state.lock().unwrap().bucket_start;
+                assert!(start <= std::time::Instant::now());
+                assert!(before.saturating_duration_since(start) <= bucket);
+                start
+            })
+            .collect();
+        assert!(starts.windows(2).any(|w| w[0] != w[1]));
+    }
+
+    #[test]
+    fn forward_decay_favours_recent_events() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .limit(10)
+                    .forward_decay(Duration::from_millis(1)),
+            )
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..100 {
+                tracing::info!("old");
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            for _ in 0..10 {
+                tracing::info!("new");
+            }
+            handle.flush();
+        });
+        assert_eq!(buf.lines(), vec![" INFO new"; 10]);
+    }
+
+    #[test]
+    fn weight_field_favours_heavy_events() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::level(Level::INFO).limit(5).weight_field("bytes"))
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..100 {
+                if i % 20 == 0 {
+                    tracing::info!(bytes = 1e12, "heavy");
+                } else {
+                    tracing::info!(bytes = 0, "empty");
+                }
+            }
+            handle.flush();
+        });
+        assert_eq!(buf.lines(), vec![" INFO heavy bytes=1000000000000.0"; 5]);
+    }
+
+    #[test]
+    fn level_weight_counts_toward_limit() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::DEBUG)
+                    .limit(10)
+                    .level_weight(Level::DEBUG, 0.1),
+            )
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..1000 {
+                tracing::debug!("cheap");
+            }
+            handle.flush();
+            assert_eq!(buf.lines().len(), 100);
+
+            for _ in 0..5 {
+                tracing::warn!("full");
+            }
+            for _ in 0..50 {
+                tracing::debug!("cheap");
+            }
+            handle.flush();
+        });
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 155);
+        assert_eq!(stats.dropped(), 900);
+    }
+
+    #[test]
+    fn max_bytes_caps_buffered_size() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::level(Level::INFO).limit(100).max_bytes(100))
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..20 {
+                // 20 bytes each.
+                tracing::info!("fifteen bytes");
+            }
+            tracing::info!(payload = "x".repeat(200));
+            handle.flush();
+        });
+        assert_eq!(buf.lines(), vec![" INFO fifteen bytes"; 5]);
+        assert_eq!(stats.dropped(), 16);
+    }
+
+    #[test]
+    fn budget_bytes_limits_output_size() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_bytes(tracing_subscriber::filter::LevelFilter::INFO, 100)
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        assert_eq!(layer.budgets()[0].capacity, 100);
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..20 {
+                // 20 bytes each.
+                tracing::info!("fifteen bytes");
+            }
+            handle.flush();
+        });
+        assert_eq!(buf.lines(), vec![" INFO fifteen bytes"; 5]);
+    }
+
+    #[test]
+    fn budget_fraction_keeps_proportion() {
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_fraction(tracing_subscriber::filter::LevelFilter::INFO, 0.1)
+            .bucket_duration(Duration::from_secs(1))
+            .writer(io::sink)
+            .build();
+        assert_eq!(layer.budgets()[0].capacity, 0);
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10_000 {
+                tracing::info!("event");
+            }
+            handle.flush();
+        });
+        let sampled = stats.sampled();
+        assert!((800..1200).contains(&sampled), "{sampled}");
+        assert_eq!(stats.sampled() + stats.dropped(), 10_000);
+    }
+
+    #[test]
+    fn unbuffered_writes_straight_through() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_level(Level::INFO, 3)
+            .bucket_duration(Duration::from_secs(1))
+            .unbuffered()
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..5 {
+                tracing::info!(i);
+            }
+            assert_eq!(buf.lines(), [" INFO i=0", " INFO i=1", " INFO i=2"]);
+        });
+        assert_eq!(stats.sampled(), 3);
+        assert_eq!(stats.dropped(), 2);
+        assert_eq!(stats.budgets()[0].sampled, 3);
+    }
+
+    #[test]
+    fn consistent_sampling_by_field() {
+        use crate::SampleKey;
+
+        let run = || {
+            let buf = SharedBuf::default();
+            let (layer, _stats) = SamplingLayer::<Registry>::builder()
+                .budget_with(
+                    Budget::level(Level::INFO)
+                        .fraction(0.5)
+                        .consistent(SampleKey::Field("trace_id")),
+                )
+                .without_time()
+                .with_ansi(false)
+                .with_target(false)
+                .writer(buf.clone())
+                .build();
+            let subscriber = tracing_subscriber::registry().with(layer);
+            tracing::subscriber::with_default(subscriber, || {
+                for trace_id in 0..100 {
+                    tracing::info!(trace_id, "start");
+                    tracing::info!(trace_id = %trace_id, "end");
+                }
+            });
+            buf.lines()
+        };
+        let lines = run();
+        assert!((20..80).contains(&(lines.len() / 2)), "{}", lines.len());
+        // Both events of a trace are kept or dropped together, the same way
+        // on every run.
+        for pair in lines.chunks(2) {
+            let id = pair[0].strip_prefix(" INFO start trace_id=").unwrap();
+            assert_eq!(pair[1], format!(" INFO end trace_id={id}"));
+        }
+        assert_eq!(run(), lines);
+    }
+
+    #[test]
+    fn head_writes_first_events_unsampled() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::level(Level::INFO).limit(1).head(2))
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..5 {
+                tracing::info!(i);
+            }
+            assert_eq!(buf.lines(), [" INFO i=0", " INFO i=1"]);
+            handle.flush();
+            assert_eq!(buf.lines().len(), 3);
+
+            // The head starts again with each bucket.
+            tracing::info!(i = 5);
+            assert_eq!(buf.lines().len(), 4);
+        });
+        assert_eq!(stats.dropped(), 2);
+    }
+
+    #[test]
+    fn passthrough_writes_directly_until_limit_exceeded() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::level(Level::INFO).limit(2).passthrough())
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(i = 0);
+            tracing::info!(i = 1);
+            assert_eq!(buf.lines(), [" INFO i=0", " INFO i=1"]);
+            // Over the limit, the rest of the bucket is sampled.
+            for i in 2..6 {
+                tracing::info!(i);
+            }
+            assert_eq!(buf.lines().len(), 2);
+            handle.flush();
+            assert_eq!(buf.lines().len(), 4);
+
+            // The bucket exceeded the limit, so the next is sampled too.
+            tracing::info!(i = 6);
+            assert_eq!(buf.lines().len(), 4);
+            handle.flush();
+            assert_eq!(buf.lines().len(), 5);
+
+            // That one stayed within it, so this one passes through again.
+            tracing::info!(i = 7);
+            assert_eq!(buf.lines().len(), 6);
+        });
+    }
+
+    #[test]
+    fn set_enabled_pauses_sampling() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget(tracing_subscriber::filter::LevelFilter::INFO, 1)
+            .bucket_duration(Duration::from_secs(60))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(i = 0);
+            assert!(buf.lines().is_empty());
+
+            // Pausing flushes the buffered event ahead of those passed through.
+            handle.set_enabled(false);
+            for i in 1..4 {
+                tracing::info!(i);
+            }
+            assert_eq!(
+                buf.lines(),
+                [" INFO i=0", " INFO i=1", " INFO i=2", " INFO i=3"]
+            );
+
+            handle.set_enabled(true);
+            tracing::info!(i = 4);
+            tracing::info!(i = 5);
+            assert_eq!(buf.lines().len(), 4);
+        });
+    }
+
+    #[test]
+    fn set_bucket_duration_resizes_reservoirs() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_named("info", EnvFilter::new("info"), 10)
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(i = 0);
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 10);
+
+            // The buffered event is written before the bucket changes.
+            handle.set_bucket_duration(Duration::from_millis(200));
+            assert_eq!(buf.lines(), [" INFO i=0"]);
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 2);
+
+            for i in 1..10 {
+                tracing::info!(i);
+            }
+            handle.flush();
+            assert_eq!(buf.lines().len(), 3);
+        });
+    }
+
+    #[test]
+    fn reload_flushes_replaced_layer() {
+        let buf = SharedBuf::default();
+        let build_for = |filter| {
+            SamplingLayer::<Registry>::builder()
+                .budget(EnvFilter::new(filter), 1)
+                .bucket_duration(Duration::from_secs(60))
+                .without_time()
+                .with_ansi(false)
+                .with_target(false)
+                .writer(buf.clone())
+                .build()
+                .0
+        };
+        let (layer, reload) = tracing_subscriber::reload::Layer::new(build_for("info"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(i = 0);
+            assert!(buf.lines().is_empty());
+
+            // The replaced layer writes what it buffered on the way out.
+            reload.reload(build_for("info")).unwrap();
+            assert_eq!(buf.lines(), [" INFO i=0"]);
+
+            tracing::info!(i = 1);
+            tracing::debug!(i = 2);
+            reload.with_current(|layer| layer.flush()).unwrap();
+            assert_eq!(buf.lines(), [" INFO i=0", " INFO i=1"]);
+
+            // Callsites the old layer had no interest in are enabled again.
+            reload.reload(build_for("debug")).unwrap();
+            tracing::debug!(i = 3);
+            reload.with_current(|layer| layer.flush()).unwrap();
+            assert_eq!(buf.lines().last().unwrap(), "DEBUG i=3");
+        });
+    }
+
+    #[test]
+    fn limit_fn_is_read_at_rotation() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let buf = SharedBuf::default();
+        let limit = Arc::new(AtomicU64::new(1));
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::level(Level::INFO).named("info").limit_fn({
+                let limit = limit.clone();
+                move || limit.load(Ordering::Relaxed)
+            }))
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            // The new limit applies from the next bucket.
+            limit.store(3, Ordering::Relaxed);
+            for i in 0..5 {
+                tracing::info!(i);
+            }
+            handle.flush();
+            assert_eq!(buf.lines().len(), 1);
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 3);
+
+            for i in 0..5 {
+                tracing::info!(i);
+            }
+            handle.flush();
+            assert_eq!(buf.lines().len(), 4);
+
+            limit.store(0, Ordering::Relaxed);
+            handle.flush();
+            tracing::info!(i = 5);
+            handle.flush();
+            assert_eq!(buf.lines().len(), 4);
+        });
+    }
+
+    #[test]
+    fn budget_from_env_parses_and_rereads() {
+        // SAFETY: no other test reads or writes these variables.
+        unsafe {
+            std::env::set_var("TLS_TEST_BUDGET", "info@1");
+            std::env::set_var("TLS_TEST_BAD_LIMIT", "info@lots");
+            std::env::set_var("TLS_TEST_NO_LIMIT", "info");
+        }
+        assert!(matches!(
+            Budget::<Registry>::from_env("TLS_TEST_MISSING"),
+            Err(crate::EnvError::NotPresent { .. })
+        ));
+        assert!(matches!(
+            Budget::<Registry>::from_env("TLS_TEST_BAD_LIMIT"),
+            Err(crate::EnvError::InvalidLimit { .. })
+        ));
+        assert!(matches!(
+            Budget::<Registry>::from_env("TLS_TEST_NO_LIMIT"),
+            Err(crate::EnvError::MissingLimit { .. })
+        ));
+
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::from_env_every("TLS_TEST_BUDGET", Duration::ZERO).unwrap())
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        assert_eq!(layer.budgets()[0].filter, "info");
+        assert_eq!(layer.budgets()[0].limit_per_second, 1);
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            unsafe { std::env::set_var("TLS_TEST_BUDGET", "info@2") };
+            handle.flush();
+            assert_eq!(stats.budget_named("TLS_TEST_BUDGET").unwrap().capacity, 2);
+
+            // An invalid value keeps the last good limit.
+            unsafe { std::env::set_var("TLS_TEST_BUDGET", "info@") };
+            handle.flush();
+            assert_eq!(stats.budget_named("TLS_TEST_BUDGET").unwrap().capacity, 2);
+        });
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_config_builds_layer() {
+        let config: crate::SamplingConfig = serde_json::from_str(
+            r#"{
+                "bucket_duration": "1s",
+                "format": "logfmt",
+                "time": false,
+                "budgets": [
+                    {"filter": "error", "limit": 1},
+                    {"name": "info", "filter": "info", "limit": 2, "cascade": false}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayerBuilder::<Registry>::from_config(&config)
+            .unwrap()
+            .writer(buf.clone())
+            .build();
+        let budgets = layer.budgets();
+        assert_eq!(budgets[0].capacity, 1);
+        assert_eq!(budgets[1].name.as_deref(), Some("info"));
+        assert!(!budgets[1].cascade);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(i = 0);
+        });
+        assert_eq!(
+            buf.lines(),
+            ["level=info target=tracing_log_sample::tests i=0"]
+        );
+
+        let invalid = serde_json::from_str::<crate::SamplingConfig>(r#"{"bucket_duration": "5"}"#);
+        assert!(invalid.is_err());
+        let bad_filter: crate::SamplingConfig =
+            serde_json::from_str(r#"{"budgets": [{"filter": "=[", "limit": 1}]}"#).unwrap();
+        assert!(SamplingLayerBuilder::<Registry>::from_config(&bad_filter).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn from_config_reads_schedule() {
+        let config: crate::SamplingConfig = serde_json::from_str(
+            r#"{
+                "bucket_duration": "1s",
+                "budgets": [{
+                    "filter": "info",
+                    "limit": 1,
+                    "schedule": {"windows": [{"from": "00:00", "to": "00:00", "limit": 3}]}
+                }]
+            }"#,
+        )
+        .unwrap();
+        let (layer, _stats) = SamplingLayerBuilder::<Registry>::from_config(&config)
+            .unwrap()
+            .build();
+        // The window covers the whole day.
+        assert_eq!(layer.budgets()[0].capacity, 3);
+
+        let invalid = serde_json::from_str::<crate::SamplingConfig>(
+            r#"{"budgets": [{
+                "filter": "info",
+                "limit": 1,
+                "schedule": {"windows": [{"from": "9", "to": "24:00", "limit": 3}]}
+            }]}"#,
+        );
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn parse_spec_configures_builder() {
+        let (layer, _stats) = SamplingLayerBuilder::<Registry>::parse(
+            "error@1000, info,hyper=warn@5000;bucket=100ms",
+        )
+        .unwrap()
+        .build();
+        let budgets = layer.budgets();
+        assert_eq!(budgets.len(), 2);
+        assert_eq!(budgets[0].filter, "error");
+        assert_eq!(budgets[0].capacity, 100);
+        assert_eq!(budgets[1].limit_per_second, 5000);
+        assert_eq!(budgets[1].capacity, 500);
+
+        let parse = SamplingLayerBuilder::<Registry>::parse;
+        assert!(matches!(
+            parse("error@1000,info"),
+            Err(crate::EnvError::MissingLimit { .. })
+        ));
+        assert!(matches!(
+            parse("error@1000;bucket=soon"),
+            Err(crate::EnvError::InvalidDuration { .. })
+        ));
+        assert!(matches!(
+            parse("error@1000;colour=yes"),
+            Err(crate::EnvError::UnknownOption { .. })
+        ));
+        assert!(matches!(parse("=[@1000"), Err(crate::EnvError::Filter(_))));
+    }
+
+    #[test]
+    fn target_rate_scales_budgets_to_traffic() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_named("error", EnvFilter::new("error"), 100)
+            .budget_named("info", EnvFilter::new("info"), 100)
+            .target_rate(10)
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..50 {
+                tracing::info!(i);
+            }
+            handle.flush();
+            // Errors are quiet, so their share goes to info, which keeps
+            // the whole target.
+            assert_eq!(stats.budget_named("error").unwrap().capacity, 10);
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 10);
+
+            for i in 0..50 {
+                tracing::info!(i);
+            }
+            handle.flush();
+            assert_eq!(buf.lines().len(), 60);
+        });
+    }
+
+    #[test]
+    fn backpressure_scales_budgets_while_sink_fails() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        #[derive(Clone, Default)]
+        struct Flaky {
+            broken: Arc<AtomicBool>,
+            buf: SharedBuf,
+        }
+
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.broken.load(Ordering::Relaxed) {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.buf.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl<'a> MakeWriter<'a> for Flaky {
+            type Writer = Flaky;
+            fn make_writer(&'a self) -> Self::Writer {
+                self.clone()
+            }
+        }
+
+        let writer = Flaky::default();
+        writer.broken.store(true, Ordering::Relaxed);
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_named("info", EnvFilter::new("info"), 64)
+            .bucket_duration(Duration::from_secs(1))
+            .backpressure(Duration::from_secs(1))
+            .writer(writer.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let bucket = || {
+                for i in 0..10 {
+                    tracing::info!(i);
+                }
+                handle.flush();
+            };
+            bucket();
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 64);
+            bucket();
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 32);
+            bucket();
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 16);
+
+            // Capacity comes back gradually once writes succeed.
+            writer.broken.store(false, Ordering::Relaxed);
+            bucket();
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 8);
+            bucket();
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 12);
+        });
+        assert_eq!(writer.buf.lines().len(), 18);
+    }
+
+    #[test]
+    fn quota_provider_is_told_demand_and_sets_limit() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        #[derive(Default)]
+        struct Fleet {
+            granted: AtomicU64,
+            reports: Mutex<Vec<u64>>,
+        }
+
+        impl QuotaProvider for Fleet {
+            fn limit(&self) -> u64 {
+                self.granted.load(Ordering::Relaxed)
+            }
+
+            fn report(&self, matched: u64, bucket: Duration) {
+                assert_eq!(bucket, Duration::from_secs(1));
+                self.reports.lock().unwrap().push(matched);
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let fleet = Arc::new(Fleet::default());
+        fleet.granted.store(2, Ordering::Relaxed);
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .named("info")
+                    .quota(fleet.clone()),
+            )
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..5 {
+                tracing::info!(i);
+            }
+            fleet.granted.store(4, Ordering::Relaxed);
+            handle.flush();
+            assert_eq!(buf.lines().len(), 2);
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 4);
+
+            for i in 0..3 {
+                tracing::info!(i);
+            }
+            handle.flush();
+            assert_eq!(buf.lines().len(), 5);
+        });
+        // The last report comes from the flush as the layer is dropped.
+        assert_eq!(*fleet.reports.lock().unwrap(), [5, 3, 0]);
+    }
+
+    #[test]
+    fn ramp_up_starts_budget_small() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .named("info")
+                    .limit(1000)
+                    .ramp_up(Duration::from_secs(10)),
+            )
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        // A tenth of the way through the ramp as the first bucket ends.
+        assert_eq!(stats.budget_named("info").unwrap().capacity, 100);
+        assert_eq!(layer.budgets()[0].capacity, 100);
+        assert_eq!(layer.budgets()[0].limit_per_second, 1000);
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..200 {
+                tracing::info!(i);
+            }
+            handle.flush();
+            assert_eq!(buf.lines().len(), 100);
+            let capacity = stats.budget_named("info").unwrap().capacity;
+            assert!((100..200).contains(&capacity), "{capacity}");
+        });
+    }
+
+    #[test]
+    fn hard_limit_drops_without_formatting() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use tracing_subscriber::fmt::format::{FormatEvent, Writer};
+        use tracing_subscriber::fmt::{FmtContext, FormatFields};
+        use tracing_subscriber::registry::LookupSpan;
+
+        struct Counting(Arc<AtomicUsize>);
+
+        impl<S, N> FormatEvent<S, N> for Counting
+        where
+            S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+            N: for<'a> FormatFields<'a> + 'static,
+        {
+            fn format_event(
+                &self,
+                _ctx: &FmtContext<'_, S, N>,
+                mut writer: Writer<'_>,
+                _event: &tracing::Event<'_>,
+            ) -> std::fmt::Result {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                writeln!(writer, "event")
+            }
+        }
+
+        let formatted = Arc::new(AtomicUsize::new(0));
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .named("info")
+                    .limit(2)
+                    .hard_limit(5),
+            )
+            .bucket_duration(Duration::from_secs(1))
+            .event_format(Counting(formatted.clone()))
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..20 {
+                tracing::info!(i);
+            }
+            assert_eq!(formatted.load(Ordering::Relaxed), 5);
+            handle.flush();
+            assert_eq!(buf.lines().len(), 2);
+
+            // The hard limit resets with the bucket.
+            for i in 0..20 {
+                tracing::info!(i);
+            }
+            assert_eq!(formatted.load(Ordering::Relaxed), 10);
+        });
+        let info = stats.budget_named("info").unwrap();
+        assert_eq!(info.received, 40);
+        assert_eq!(info.dropped, 36);
+    }
+
+    #[test]
+    fn burst_credit_keeps_spike_then_samples() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .named("info")
+                    .limit(2)
+                    .burst(5, Duration::from_secs(60)),
+            )
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        assert_eq!(stats.budget_named("info").unwrap().capacity, 7);
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let bucket = || {
+                for i in 0..10 {
+                    tracing::info!(i);
+                }
+                handle.flush();
+            };
+            bucket();
+            assert_eq!(buf.lines().len(), 7);
+            // The credit is spent until it refills.
+            assert_eq!(stats.budget_named("info").unwrap().capacity, 2);
+            bucket();
+            assert_eq!(buf.lines().len(), 9);
+        });
+    }
+
+    #[test]
+    fn global_limit_caps_all_budgets() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_named("warn", EnvFilter::new("warn"), 10)
+            .budget_named("info", EnvFilter::new("info"), 10)
+            .global_limit(8)
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                tracing::warn!(i);
+                tracing::info!(i);
+            }
+            handle.flush();
+        });
+        assert_eq!(buf.lines().len(), 8);
+        assert_eq!(stats.dropped(), 12);
+        assert_eq!(stats.sampled(), 8);
+        let sampled = ["warn", "info"].map(|name| stats.budget_named(name).unwrap().sampled);
+        assert_eq!(sampled.iter().sum::<u64>(), 8);
+    }
+
+    #[test]
+    fn parent_budget_caps_its_children() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::new(EnvFilter::new("db=info"))
+                    .named("db")
+                    .limit(5)
+                    .parent("info"),
+            )
+            .budget_with(
+                Budget::new(EnvFilter::new("http=info"))
+                    .named("http")
+                    .limit(5)
+                    .parent("info"),
+            )
+            .budget_named("info", EnvFilter::new("info"), 6)
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..10 {
+                tracing::info!(target: "db", i);
+                tracing::info!(target: "http", i);
+            }
+            handle.flush();
+        });
+        assert_eq!(buf.lines().len(), 6);
+        assert_eq!(stats.sampled(), 6);
+        assert_eq!(stats.dropped(), 14);
+        let sampled = ["db", "http", "info"].map(|name| stats.budget_named(name).unwrap().sampled);
+        assert_eq!(sampled.iter().sum::<u64>(), 6);
+
+        let err = SamplingLayer::<Registry>::builder()
+            .budget_named("info", EnvFilter::new("info"), 6)
+            .budget_with(
+                Budget::new(EnvFilter::new("db=info"))
+                    .limit(5)
+                    .parent("info"),
+            )
+            .try_build()
+            .err();
+        assert_eq!(
+            err,
+            Some(BuildError::UnknownParent {
+                budget: 1,
+                parent: "info".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn keyed_budget_shares_limit_between_keys() {
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_keyed(EnvFilter::new("info"), "tenant", 6)
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..100 {
+                tracing::info!(tenant = "noisy", i);
+            }
+            for i in 0..2 {
+                tracing::info!(tenant = "a", i);
+                tracing::info!(tenant = "b", i);
+            }
+            tracing::info!("untagged");
+            handle.flush();
+        });
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 6);
+        for tenant in ["tenant=\"a\"", "tenant=\"b\""] {
+            assert_eq!(lines.iter().filter(|line| line.contains(tenant)).count(), 2);
+        }
+        assert!(lines.iter().any(|line| line.contains("untagged")));
+        assert_eq!(stats.dropped(), 99);
+        assert_eq!(stats.sampled(), 6);
+
+        // Events evicted with their key are dropped, not sampled.
+        let buf = SharedBuf::default();
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::level(Level::INFO).limit(6).keyed("tenant", 1))
+            .bucket_duration(Duration::from_secs(1))
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for tenant in ["a", "b"] {
+                for i in 0..2 {
+                    tracing::info!(tenant, i);
+                }
+            }
+            handle.flush();
+        });
+        assert_eq!(buf.lines().len(), 2);
+        assert_eq!(stats.sampled(), 2);
+        assert_eq!(stats.dropped(), 2);
+    }
+
+    #[test]
+    fn per_root_span_limits_each_request() {
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .named("info")
+                    .limit(100)
+                    .per_root_span(3),
+            )
+            .bucket_duration(Duration::from_secs(1))
+            .with_ansi(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for request in 0..2 {
+                let _root = tracing::info_span!("request", request).entered();
+                for i in 0..5 {
+                    tracing::info!(i);
+                }
+                // Events in child spans count towards the root span.
+                let _child = tracing::info_span!("child").entered();
+                for i in 0..5 {
+                    tracing::info!(i);
+                }
+            }
+            // Events outside any span aren't limited.
+            for i in 0..5 {
+                tracing::info!(i);
+            }
+            handle.flush();
+        });
+        let lines = buf.lines();
+        assert_eq!(lines.len(), 11);
+        for request in 0..2 {
+            let needle = format!("request{{request={request}}}");
+            let kept = lines.iter().filter(|line| line.contains(&needle)).count();
+            assert_eq!(kept, 3, "{lines:?}");
+        }
+    }
+
+    #[test]
+    fn consistent_sampling_by_root_span() {
+        use crate::SampleKey;
+
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .fraction(0.5)
+                    .consistent(SampleKey::RootSpan),
+            )
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for request in 0..100 {
+                let _root = tracing::info_span!("request", request).entered();
+                tracing::info!("start");
+                let _child = tracing::info_span!("child").entered();
+                tracing::info!("end");
+            }
+        });
+        let lines = buf.lines();
+        assert!((20..80).contains(&(lines.len() / 2)), "{}", lines.len());
+        // Both events of a request are kept or dropped together.
+        for pair in lines.chunks(2) {
+            let request = pair[0].strip_suffix(": start").unwrap();
+            assert_eq!(pair[1], format!("{request}:child: end"));
+        }
+    }
+
+    #[test]
+    fn consistent_sampling_by_trace_id() {
+        use crate::SampleKey;
+
+        // The lowest and highest trace ids for OpenTelemetry's ratio sampler.
+        const KEPT: &str = "0123456789abcdef0000000000000000";
+        const DROPPED: &str = "0123456789abcdefffffffffffffffff";
+
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .fraction(0.5)
+                    .consistent(SampleKey::TraceId),
+            )
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for trace_id in [KEPT, DROPPED] {
+                let _root = tracing::info_span!("request", trace_id).entered();
+                tracing::info!("root");
+                let _child = tracing::info_span!("child").entered();
+                tracing::info!("child");
+            }
+            // Recorded after the span was created.
+            let span = tracing::info_span!("late", trace_id = tracing::field::Empty);
+            span.record("trace_id", KEPT);
+            span.in_scope(|| tracing::info!("late"));
+            // An event's own trace id wins over its spans'.
+            tracing::info_span!("request", trace_id = DROPPED)
+                .in_scope(|| tracing::info!(trace_id = KEPT, "own"));
+        });
+        assert_eq!(
+            buf.lines(),
+            [
+                format!(" INFO request{{trace_id=\"{KEPT}\"}}: root"),
+                format!(" INFO request{{trace_id=\"{KEPT}\"}}:child: child"),
+                format!(" INFO late{{trace_id=\"{KEPT}\"}}: late"),
+                format!(" INFO request{{trace_id=\"{DROPPED}\"}}: own trace_id=\"{KEPT}\""),
+            ]
+        );
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[test]
+    fn consistent_sampling_by_otel_context() {
+        use opentelemetry::trace::{
+            SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+        };
+
+        use crate::SampleKey;
+
+        let buf = SharedBuf::default();
+        let (layer, _stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(
+                Budget::level(Level::INFO)
+                    .fraction(0.5)
+                    .consistent(SampleKey::TraceId),
+            )
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            for (i, trace_id) in [0, u64::MAX as u128].into_iter().enumerate() {
+                let span_context = SpanContext::new(
+                    TraceId::from(trace_id | 1 << 64),
+                    SpanId::from(1),
+                    TraceFlags::SAMPLED,
+                    true,
+                    TraceState::default(),
+                );
+                let _guard = opentelemetry::Context::current()
+                    .with_remote_span_context(span_context)
+                    .attach();
+                tracing::info!(i);
+            }
+        });
+        assert_eq!(buf.lines(), [" INFO i=0"]);
+    }
+
+    #[test]
+    fn limit_fn_capacity_is_capped() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        use crate::error::MAX_CAPACITY;
+
+        let buf = SharedBuf::default();
+        let limit = Arc::new(AtomicU64::new(1));
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_with(Budget::level(Level::INFO).named("info").limit_fn({
+                let limit = limit.clone();
+                move || limit.load(Ordering::Relaxed)
+            }))
+            .bucket_duration(Duration::from_secs(1))
+            .without_time()
+            .with_ansi(false)
+            .with_target(false)
+            .writer(buf.clone())
+            .build();
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            limit.store(u64::MAX, Ordering::Relaxed);
+            handle.flush();
+            let capacity = stats.budget_named("info").unwrap().capacity;
+            assert!(capacity <= MAX_CAPACITY as u64, "{capacity}");
+
+            // The layer keeps working after the resize.
+            tracing::info!(i = 0);
+            handle.flush();
+            assert_eq!(buf.lines(), [" INFO i=0"]);
+        });
+    }
+
+    #[test]
+    fn set_bucket_duration_caps_capacity() {
+        use crate::error::MAX_CAPACITY;
+
+        let (layer, stats) = SamplingLayer::<Registry>::builder()
+            .budget_named("info", EnvFilter::new("info"), 1000)
+            .bucket_duration(Duration::from_secs(1))
+            .writer(std::io::sink)
+            .build();
+        layer.set_bucket_duration(Duration::from_secs(3600));
+        assert_eq!(
+            stats.budget_named("info").unwrap().capacity,
+            MAX_CAPACITY as u64
+        );
+    }
+
+    #[cfg(all(feature = "sentry", feature = "tokio"))]
+    #[test]
+    fn async_writer_still_forwards_to_sentry() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let captured = sentry_core::test::with_captured_events(|| {
+            let (layer, _stats) = SamplingLayer::<Registry>::builder()
+                .budget_with(Budget::level(Level::ERROR).limit(2).forward_to_sentry())
+                .async_writer(rt.handle().clone(), Vec::new(), 16)
+                .with_ansi(false)
+                .bucket_duration(Duration::from_secs(1))
+                .build();
+            let subscriber = tracing_subscriber::registry().with(layer);
+            tracing::subscriber::with_default(subscriber, || {
+                for i in 0..5 {
+                    tracing::error!(i, "failed");
+                }
+            });
+        });
+        assert_eq!(captured.len(), 2, "{captured:?}");
+    }
 }
@@ -0,0 +1,97 @@
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::{AsyncInstrument, Meter};
+
+use crate::stats::{BudgetStats, Stats};
+
+/// Instrument name suffix, description, and how to read its value.
+type Instrument<T> = (&'static str, &'static str, fn(&T) -> u64);
+
+impl Stats {
+    /// Register observable counters for these stats on an OpenTelemetry
+    /// [`Meter`], so they are exported alongside the rest of the
+    /// application's metrics.
+    ///
+    /// Registers
+    /// `tracing_log_sample.{received,sampled,dropped,lost,write_errors}` and
+    /// `tracing_log_sample.budget.{received,sampled,dropped}`, the latter
+    /// with a `budget` attribute holding the budget's name, or its index if
+    /// it is unnamed. Values are read
+    /// from the shared counters each time the meter provider collects.
+    pub fn register_opentelemetry(&self, meter: &Meter) {
+        let totals: [Instrument<Stats>; 5] = [
+            (
+                "received",
+                "Events that matched at least one budget.",
+                Stats::received,
+            ),
+            (
+                "sampled",
+                "Events that were kept in a reservoir.",
+                Stats::sampled,
+            ),
+            (
+                "dropped",
+                "Events dropped after failing to enter any reservoir.",
+                Stats::dropped,
+            ),
+            (
+                "lost",
+                "Sampled events discarded because the I/O queue was full.",
+                Stats::lost,
+            ),
+            (
+                "write_errors",
+                "Writes and flushes that returned an error.",
+                Stats::write_errors,
+            ),
+        ];
+        for (name, description, value) in totals {
+            let stats = self.clone();
+            meter
+                .u64_observable_counter(format!("tracing_log_sample.{name}"))
+                .with_description(description)
+                .with_unit("{event}")
+                .with_callback(move |observer| observer.observe(value(&stats), &[]))
+                .build();
+        }
+
+        let per_budget: [Instrument<BudgetStats>; 3] = [
+            ("received", "Events offered to a budget's reservoir.", |b| {
+                b.received
+            }),
+            (
+                "sampled",
+                "Events written out of a budget's reservoir.",
+                |b| b.sampled,
+            ),
+            (
+                "dropped",
+                "Events dropped by the last matching budget.",
+                |b| b.dropped,
+            ),
+        ];
+        for (name, description, value) in per_budget {
+            let stats = self.clone();
+            meter
+                .u64_observable_counter(format!("tracing_log_sample.budget.{name}"))
+                .with_description(description)
+                .with_unit("{event}")
+                .with_callback(move |observer| observe_budgets(&stats, observer, value))
+                .build();
+        }
+    }
+}
+
+fn observe_budgets(
+    stats: &Stats,
+    observer: &dyn AsyncInstrument<u64>,
+    value: fn(&BudgetStats) -> u64,
+) {
+    for (i, budget) in stats.budgets().iter().enumerate() {
+        let label = match stats.budget_name(i) {
+            Some(name) => KeyValue::new("budget", name.to_owned()),
+            None => KeyValue::new("budget", i as i64),
+        };
+        observer.observe(value(budget), &[label]);
+    }
+}
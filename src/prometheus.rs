@@ -0,0 +1,122 @@
+use std::fmt::{Display, Write};
+
+use crate::stats::Stats;
+use crate::summary::{LEVELS, level_name};
+
+const PREFIX: &str = "tracing_log_sample";
+
+/// Write one counter family: the `HELP`/`TYPE` header followed by a sample
+/// per `(label value, count)` pair. Samples without a label value are written
+/// unlabelled.
+fn counter<L: Display>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    samples: impl IntoIterator<Item = (Option<L>, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {PREFIX}_{name} {help}");
+    let _ = writeln!(out, "# TYPE {PREFIX}_{name} counter");
+    for (value, count) in samples {
+        match value {
+            Some(value) => writeln!(out, "{PREFIX}_{name}{{{label}=\"{value}\"}} {count}"),
+            None => writeln!(out, "{PREFIX}_{name} {count}"),
+        }
+        .unwrap();
+    }
+}
+
+impl Stats {
+    /// Render all counters in the Prometheus text exposition format.
+    ///
+    /// Global counters are unlabelled, per-budget counters carry a `budget`
+    /// label with the budget's name, or its index if it is unnamed, and per-level counters carry a `level`
+    /// label. The output can be appended to an existing `/metrics` response.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let total = |count| [(None::<&str>, count)];
+
+        counter(
+            &mut out,
+            "received_total",
+            "Events that matched at least one budget.",
+            "",
+            total(self.received()),
+        );
+        counter(
+            &mut out,
+            "sampled_total",
+            "Events that were kept in a reservoir.",
+            "",
+            total(self.sampled()),
+        );
+        counter(
+            &mut out,
+            "dropped_total",
+            "Events dropped after failing to enter any reservoir.",
+            "",
+            total(self.dropped()),
+        );
+        counter(
+            &mut out,
+            "lost_total",
+            "Sampled events discarded because the I/O queue was full.",
+            "",
+            total(self.lost()),
+        );
+        counter(
+            &mut out,
+            "write_errors_total",
+            "Writes and flushes that returned an error.",
+            "",
+            total(self.write_errors()),
+        );
+
+        let budgets = self.budgets();
+        let by_budget = |count: fn(&crate::BudgetStats) -> u64| {
+            budgets
+                .iter()
+                .enumerate()
+                .map(move |(i, b)| (Some(self.budget_label(i)), count(b)))
+        };
+        counter(
+            &mut out,
+            "budget_received_total",
+            "Events offered to a budget's reservoir.",
+            "budget",
+            by_budget(|b| b.received),
+        );
+        counter(
+            &mut out,
+            "budget_sampled_total",
+            "Events written out of a budget's reservoir.",
+            "budget",
+            by_budget(|b| b.sampled),
+        );
+        counter(
+            &mut out,
+            "budget_dropped_total",
+            "Events dropped by the last matching budget.",
+            "budget",
+            by_budget(|b| b.dropped),
+        );
+
+        let levels = LEVELS.map(|level| (level_name(level), self.level(level)));
+        counter(
+            &mut out,
+            "level_sampled_total",
+            "Events written out of a reservoir, by level.",
+            "level",
+            levels.iter().map(|(name, c)| (Some(name), c.sampled)),
+        );
+        counter(
+            &mut out,
+            "level_dropped_total",
+            "Events dropped after failing to enter any reservoir, by level.",
+            "level",
+            levels.iter().map(|(name, c)| (Some(name), c.dropped)),
+        );
+
+        out
+    }
+}
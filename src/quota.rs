@@ -0,0 +1,212 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Supplies a budget's per-second limit, read as each bucket ends. Set with
+/// [`Budget::quota`](crate::Budget::quota).
+///
+/// Implemented for:
+///
+/// - `u64`, a fixed limit;
+/// - [`AtomicU64`], a limit adjusted locally, e.g. from a feature flag,
+///   through an [`Arc`] shared with whatever updates it;
+/// - closures returning `u64`, as taken by
+///   [`Budget::limit_fn`](crate::Budget::limit_fn);
+/// - [`Schedule`], a limit that varies with the time of day.
+///
+/// Implement it to enforce a rate across processes: a provider for a
+/// fleet-wide budget can send each process's [`report`](Self::report)s to a
+/// quota service in the background, and return the share it was last
+/// granted from [`limit`](Self::limit).
+///
+/// ```
+/// use std::sync::Mutex;
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use std::time::Duration;
+///
+/// use tracing::Level;
+/// use tracing_log_sample::{Budget, QuotaProvider, SamplingLayer};
+///
+/// #[derive(Default)]
+/// struct FleetQuota {
+///     granted: AtomicU64,
+///     demand: Mutex<Vec<u64>>,
+/// }
+///
+/// impl QuotaProvider for FleetQuota {
+///     fn limit(&self) -> u64 {
+///         self.granted.load(Ordering::Relaxed)
+///     }
+///
+///     fn report(&self, matched: u64, _bucket: Duration) {
+///         // Picked up by a task that talks to the quota service.
+///         self.demand.lock().unwrap().push(matched);
+///     }
+/// }
+///
+/// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder()
+///     .budget_with(Budget::level(Level::INFO).quota(FleetQuota::default()));
+/// ```
+pub trait QuotaProvider: Send + Sync + 'static {
+    /// The per-second limit for the next bucket.
+    ///
+    /// Called once when the layer is built and again as each bucket ends,
+    /// with the layer's state locked, so it should be cheap and must not
+    /// log. A limit of zero drops every event until it rises.
+    fn limit(&self) -> u64;
+
+    /// The number of events the budget matched in the bucket just ended,
+    /// and the bucket's duration. Called just before [`limit`](Self::limit)
+    /// as each bucket ends, under the same lock. Does nothing by default.
+    fn report(&self, matched: u64, bucket: Duration) {
+        let _ = (matched, bucket);
+    }
+}
+
+impl QuotaProvider for u64 {
+    fn limit(&self) -> u64 {
+        *self
+    }
+}
+
+impl QuotaProvider for AtomicU64 {
+    fn limit(&self) -> u64 {
+        self.load(Ordering::Relaxed)
+    }
+}
+
+impl<F> QuotaProvider for F
+where
+    F: Fn() -> u64 + Send + Sync + 'static,
+{
+    fn limit(&self) -> u64 {
+        self()
+    }
+}
+
+impl<Q: QuotaProvider + ?Sized> QuotaProvider for Arc<Q> {
+    fn limit(&self) -> u64 {
+        (**self).limit()
+    }
+
+    fn report(&self, matched: u64, bucket: Duration) {
+        (**self).report(matched, bucket);
+    }
+}
+
+const DAY: u32 = 24 * 60 * 60;
+
+/// A limit that varies with the time of day, such as a larger debug budget
+/// during business hours:
+///
+/// ```
+/// use tracing::Level;
+/// use tracing_log_sample::{Budget, SamplingLayer, Schedule};
+///
+/// let builder = SamplingLayer::<tracing_subscriber::Registry>::builder().budget_with(
+///     Budget::level(Level::DEBUG).quota(
+///         Schedule::new(100)
+///             .window((9, 0), (17, 0), 1000)
+///             .utc_offset_minutes(60),
+///     ),
+/// );
+/// ```
+///
+/// The time is checked as each bucket ends, so a change applies from the
+/// first bucket after it.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    limit: u64,
+    /// Start and end in seconds after midnight, and the limit between.
+    windows: Vec<(u32, u32, u64)>,
+    offset: i64,
+}
+
+impl Schedule {
+    /// A schedule with `limit` outside every window.
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            windows: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Use `limit` from `from` until `to`, given as hour and minute. A
+    /// window ending at or before its start runs past midnight; one ending
+    /// at its start covers the whole day. Where windows overlap, the first
+    /// added wins.
+    ///
+    /// # Panics
+    ///
+    /// If an hour is over 23 or a minute over 59.
+    pub fn window(mut self, from: (u32, u32), to: (u32, u32), limit: u64) -> Self {
+        self.windows
+            .push((seconds_of_day(from), seconds_of_day(to), limit));
+        self
+    }
+
+    /// Read times of day in a time zone `minutes` ahead of UTC, or behind
+    /// it if negative. Defaults to UTC.
+    pub fn utc_offset_minutes(mut self, minutes: i32) -> Self {
+        self.offset = i64::from(minutes) * 60;
+        self
+    }
+
+    fn limit_at(&self, seconds: u32) -> u64 {
+        (self.windows.iter())
+            .find(|&&(from, to, _)| {
+                if from < to {
+                    (from..to).contains(&seconds)
+                } else {
+                    seconds >= from || seconds < to
+                }
+            })
+            .map_or(self.limit, |&(_, _, limit)| limit)
+    }
+}
+
+fn seconds_of_day((hour, minute): (u32, u32)) -> u32 {
+    assert!(
+        hour < 24 && minute < 60,
+        "invalid time of day {hour}:{minute:02}"
+    );
+    (hour * 60 + minute) * 60
+}
+
+impl QuotaProvider for Schedule {
+    fn limit(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        self.limit_at((now + self.offset).rem_euclid(i64::from(DAY)) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_apply_between_their_times() {
+        let schedule = Schedule::new(1)
+            .window((9, 0), (17, 30), 10)
+            .window((22, 0), (6, 0), 2);
+        assert_eq!(schedule.limit_at(seconds_of_day((8, 59))), 1);
+        assert_eq!(schedule.limit_at(seconds_of_day((9, 0))), 10);
+        assert_eq!(schedule.limit_at(seconds_of_day((17, 29))), 10);
+        assert_eq!(schedule.limit_at(seconds_of_day((17, 30))), 1);
+        assert_eq!(schedule.limit_at(seconds_of_day((23, 0))), 2);
+        assert_eq!(schedule.limit_at(seconds_of_day((0, 0))), 2);
+        assert_eq!(schedule.limit_at(seconds_of_day((6, 0))), 1);
+    }
+
+    #[test]
+    fn first_window_wins() {
+        let schedule = Schedule::new(1)
+            .window((0, 0), (12, 0), 5)
+            .window((12, 0), (12, 0), 7);
+        assert_eq!(schedule.limit_at(seconds_of_day((11, 0))), 5);
+        assert_eq!(schedule.limit_at(seconds_of_day((13, 0))), 7);
+    }
+}
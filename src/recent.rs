@@ -0,0 +1,53 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::sink::Buffered;
+
+/// A bounded ring buffer of the most recently written events.
+///
+/// Create one with [`RecentEvents::new`], pass a clone to
+/// [`SamplingLayerBuilder::recent_events`](crate::SamplingLayerBuilder::recent_events),
+/// and call [`get`](Self::get) at runtime, e.g. from a `/debug/logs` endpoint,
+/// to see exactly what made it through sampling.
+#[derive(Clone)]
+pub struct RecentEvents {
+    inner: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    capacity: usize,
+}
+
+impl RecentEvents {
+    /// Create a buffer that keeps the last `capacity` written events.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// The formatted events currently held, oldest first.
+    pub fn get(&self) -> Vec<String> {
+        let events = self.inner.lock().unwrap();
+        events
+            .iter()
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+            .collect()
+    }
+
+    pub(crate) fn record(&self, events: &[Buffered]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut recent = self.inner.lock().unwrap();
+        let skip = events.len().saturating_sub(self.capacity);
+        for event in events
+            .iter()
+            .skip(skip)
+            .filter(|e| e.meta.is_some() && !e.bytes.is_empty())
+        {
+            if recent.len() == self.capacity {
+                recent.pop_front();
+            }
+            recent.push_back(event.bytes.clone());
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::array;
+use std::cell::Cell;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use tracing::dispatcher::WeakDispatch;
+use tracing::field::{Field, Value, Visit, display};
+use tracing::subscriber::Interest;
+use tracing::{Event, Metadata};
+use tracing_subscriber::layer::{Context, Filter};
+
+use crate::sink::Buffered;
+
+/// An event's field values, indexed by their position in the callsite's
+/// field set.
+pub(crate) type Fields = Vec<(usize, Box<dyn Value + Send + Sync>)>;
+
+/// The most fields a `tracing` macro can record on one event.
+const MAX_FIELDS: usize = 32;
+
+thread_local! {
+    static RE_EMITTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the current thread is re-dispatching a sampled event.
+pub(crate) fn re_emitting() -> bool {
+    RE_EMITTING.with(Cell::get)
+}
+
+/// Copy an event's field values so it can be dispatched again later.
+pub(crate) fn capture(event: &Event<'_>) -> Fields {
+    let mut visitor = CaptureVisitor(Vec::new());
+    event.record(&mut visitor);
+    visitor.0
+}
+
+struct CaptureVisitor(Fields);
+
+impl CaptureVisitor {
+    fn push(&mut self, field: &Field, value: impl Value + Send + Sync + 'static) {
+        self.0.push((field.index(), Box::new(value)));
+    }
+}
+
+impl Visit for CaptureVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, value);
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.push(field, value);
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.push(field, value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_owned());
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.push(field, display(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, display(format!("{value:?}")));
+    }
+}
+
+/// Re-dispatches sampled events to the subscriber the layer was registered
+/// with.
+pub(crate) struct ReEmitter {
+    pub(crate) dispatch: Arc<OnceLock<WeakDispatch>>,
+}
+
+impl ReEmitter {
+    /// Dispatch every event in `events`. Returns how many could not be
+    /// dispatched because the subscriber is gone.
+    pub(crate) fn emit<'e>(&self, events: impl Iterator<Item = &'e Buffered>) -> u64 {
+        let dispatch = self.dispatch.get().and_then(WeakDispatch::upgrade);
+        let mut lost = 0;
+        RE_EMITTING.with(|flag| flag.set(true));
+        for event in events {
+            let Some(meta) = event.meta else {
+                continue;
+            };
+            match &dispatch {
+                Some(dispatch) => dispatch_event(dispatch, meta, &event.fields),
+                None => lost += 1,
+            }
+        }
+        RE_EMITTING.with(|flag| flag.set(false));
+        lost
+    }
+}
+
+fn dispatch_event(dispatch: &tracing::Dispatch, meta: &'static Metadata<'static>, fields: &Fields) {
+    if !dispatch.enabled(meta) {
+        return;
+    }
+    let all: Vec<Field> = meta.fields().iter().collect();
+    let Some(last) = all.last() else {
+        dispatch.event(&Event::new_child_of(
+            None,
+            meta,
+            &meta.fields().value_set(&[]),
+        ));
+        return;
+    };
+    let mut values: [(&Field, Option<&dyn Value>); MAX_FIELDS] =
+        array::from_fn(|i| (all.get(i).unwrap_or(last), None));
+    for (index, value) in fields {
+        if let Some(slot) = values.get_mut(*index) {
+            slot.1 = Some(&**value);
+        }
+    }
+    let values = meta.fields().value_set(&values);
+    dispatch.event(&Event::new_child_of(None, meta, &values));
+}
+
+/// A per-layer [`Filter`] that only passes events re-dispatched by a
+/// [`SamplingLayer`](crate::SamplingLayer) built with
+/// [`re_emit`](crate::SamplingLayerBuilder::re_emit).
+///
+/// Wrap the layers that should see sampled events with it, so they don't
+/// also see every original event:
+///
+/// ```
+/// use tracing::Level;
+/// use tracing_log_sample::{ReEmitted, SamplingLayer};
+/// use tracing_subscriber::prelude::*;
+///
+/// let (sampler, _stats) = SamplingLayer::builder()
+///     .budget_level(Level::INFO, 1000)
+///     .re_emit()
+///     .build();
+/// let subscriber = tracing_subscriber::registry()
+///     .with(sampler)
+///     .with(tracing_subscriber::fmt::layer().with_filter(ReEmitted));
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReEmitted;
+
+impl<S> Filter<S> for ReEmitted {
+    fn callsite_enabled(&self, _meta: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, _meta: &Metadata<'_>, _ctx: &Context<'_, S>) -> bool {
+        re_emitting()
+    }
+}
@@ -1,6 +1,189 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::time::Duration;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level};
+
+use crate::summary::level_index;
+
+/// How a budget's reservoir chooses which events to keep.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Weighting {
+    /// Every event is equally likely to be kept.
+    #[default]
+    Uniform,
+    /// Forward decay: an event's weight grows by `e^rate` per second since
+    /// the bucket started, favouring recent events.
+    Decay { rate: f64 },
+    /// Weighted by the numeric value of an event field.
+    Field(&'static str),
+}
+
+impl Weighting {
+    pub(crate) fn decay(half_life: Duration) -> Self {
+        Weighting::Decay {
+            rate: std::f64::consts::LN_2 / half_life.as_secs_f64(),
+        }
+    }
+}
+
+/// What each event counts toward a budget's capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Cost {
+    /// One unit per event.
+    #[default]
+    Events,
+    /// Thousandths of a unit per event, indexed by
+    /// [`level_index`](crate::summary::level_index).
+    Levels([u64; 5]),
+    /// One unit per byte of formatted output, with the budget's limit in
+    /// bytes.
+    Bytes,
+}
+
+/// Thousandths of a unit per event.
+pub(crate) const UNIT: u64 = 1000;
+
+impl Cost {
+    /// What an event at `level` formatted as `bytes` costs, in thousandths,
+    /// or in bytes for [`Cost::Bytes`].
+    pub(crate) fn units(&self, level: &Level, bytes: usize) -> u64 {
+        match self {
+            Cost::Events => UNIT,
+            Cost::Levels(units) => units[level_index(level)],
+            // Unformatted events, when re-emitting, still cost something.
+            Cost::Bytes => bytes.max(1) as u64,
+        }
+    }
+}
+
+/// How many events to skip before keeping one, when keeping each with
+/// probability `p`.
+fn geometric(p: f64) -> usize {
+    if p <= 0.0 {
+        return usize::MAX;
+    }
+    (random().ln() / (1.0 - p).ln()).floor() as usize
+}
+
+/// A random number in `(0, 1]`, so its logarithm is finite.
+pub(crate) fn random() -> f64 {
+    1.0 - fastrand::f64()
+}
+
+/// A key for weighted sampling with A-Res (Efraimidis and Spirakis): the
+/// events with the largest keys are kept. This is `ln(u) / weight` rather
+/// than `u^(1 / weight)`, which orders the same but doesn't underflow.
+///
+/// Events that don't have a positive weight get the smallest key.
+pub(crate) fn weighted_key(weight: f64) -> f64 {
+    if weight > 0.0 {
+        random().ln() / weight
+    } else {
+        f64::NEG_INFINITY
+    }
+}
+
+/// A key for an event emitted `age` into its bucket under forward decay.
+pub(crate) fn decayed_key(rate: f64, age: Duration) -> f64 {
+    // `weight = e^(rate * age)`, divided without overflowing.
+    random().ln() * (-rate * age.as_secs_f64()).exp()
+}
+
+/// The numeric value of `event`'s `field`, or 1 if it has none.
+pub(crate) fn field_weight(event: &Event<'_>, field: &str) -> f64 {
+    let mut visitor = WeightVisitor { field, weight: 1.0 };
+    event.record(&mut visitor);
+    visitor.weight
+}
+
+struct WeightVisitor<'a> {
+    field: &'a str,
+    weight: f64,
+}
+
+impl Visit for WeightVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_f64(field, value as f64);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_f64(field, value as f64);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == self.field {
+            self.weight = value;
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+/// Uniform sampling uses Algorithm L (Li, 1994), which skips ahead to the
+/// next event to keep, so an oversubscribed reservoir only draws random
+/// numbers for the events it keeps rather than for every event offered.
 pub(crate) struct Reservoir<T: Default> {
     count: usize,
-    events: Box<[T]>,
+    capacity: usize,
+    /// Held events. A keyed reservoir grows this as needed, reusing the
+    /// slots in `free`.
+    events: Vec<T>,
+    /// The `count` at which the next event is kept, once full.
+    next: usize,
+    /// Algorithm L's running `w`.
+    w: f64,
+    /// Min-heap of the held events' keys, when sampled by key.
+    keys: BinaryHeap<Keyed>,
+    keyed: bool,
+    free: Vec<usize>,
+    max_events: usize,
+    /// Total cost of the held events, when sampled by key.
+    units: u64,
+    max_units: u64,
+    /// Total size of the held events, when sampled by key.
+    bytes: usize,
+    max_bytes: usize,
+    /// The probability of keeping each event, when every event is kept or
+    /// dropped on its own. `next` then counts events left to skip.
+    fraction: Option<f64>,
+    /// Set when events are decided on with [`admit`](Self::admit) rather
+    /// than held.
+    unbuffered: bool,
+    /// Events admitted in the current bucket, when unbuffered.
+    admitted: usize,
+    /// Events offered in the previous bucket.
+    last_seen: usize,
+}
+
+struct Keyed {
+    key: f64,
+    slot: usize,
+    units: u64,
+    bytes: usize,
+}
+
+impl PartialEq for Keyed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Keyed {}
+
+impl PartialOrd for Keyed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Keyed {
+    /// Reversed, so the heap's top is the smallest key.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key.total_cmp(&self.key)
+    }
 }
 
 impl<T: Default> Reservoir<T> {
@@ -9,26 +192,303 @@ impl<T: Default> Reservoir<T> {
         events.resize_with(capacity, T::default);
         Self {
             count: 0,
-            events: events.into_boxed_slice(),
+            capacity,
+            events,
+            next: 0,
+            w: 1.0,
+            keys: BinaryHeap::new(),
+            keyed: false,
+            free: Vec::new(),
+            max_events: capacity,
+            units: 0,
+            max_units: u64::MAX,
+            bytes: 0,
+            max_bytes: usize::MAX,
+            fraction: None,
+            unbuffered: false,
+            admitted: 0,
+            last_seen: 0,
+        }
+    }
+
+    /// A reservoir that holds nothing, admitting up to `capacity` events per
+    /// bucket as they are offered, or `fraction` of them if set.
+    pub(crate) fn unbuffered(capacity: usize, fraction: Option<f64>) -> Self {
+        Self {
+            capacity,
+            fraction,
+            unbuffered: true,
+            ..Self::new(0)
+        }
+    }
+
+    /// A reservoir keeping each event with probability `fraction`, however
+    /// many are offered.
+    pub(crate) fn fraction(fraction: f64) -> Self {
+        Self {
+            next: geometric(fraction),
+            fraction: Some(fraction),
+            ..Self::new(0)
+        }
+    }
+
+    /// A reservoir for a budget holding `capacity` events' worth of units,
+    /// and at most `max_bytes` of formatted events if set.
+    pub(crate) fn for_budget(
+        capacity: usize,
+        weighting: Weighting,
+        cost: Cost,
+        max_bytes: Option<usize>,
+    ) -> Self {
+        let reservoir = match cost {
+            Cost::Events if weighting == Weighting::Uniform && max_bytes.is_none() => {
+                return Self::new(capacity);
+            }
+            Cost::Events => Self::keyed(capacity, capacity, u64::MAX),
+            Cost::Levels(units) => {
+                let max_units = (capacity as u64).saturating_mul(UNIT);
+                let cheapest = units.into_iter().min().unwrap_or(UNIT).max(1);
+                let max_events = (max_units / cheapest).try_into().unwrap_or(usize::MAX);
+                Self::keyed(capacity, max_events, max_units)
+            }
+            Cost::Bytes => Self::keyed(capacity, capacity, capacity as u64),
+        };
+        Self {
+            max_bytes: max_bytes.unwrap_or(usize::MAX),
+            ..reservoir
+        }
+    }
+
+    /// Like [`for_budget`](Self::for_budget), but allocating slots as events
+    /// are held rather than all up front, for reservoirs usually far from
+    /// full.
+    pub(crate) fn growable(
+        capacity: usize,
+        weighting: Weighting,
+        cost: Cost,
+        max_bytes: Option<usize>,
+    ) -> Self {
+        match cost {
+            Cost::Events if weighting == Weighting::Uniform && max_bytes.is_none() => {
+                Self::keyed(capacity, capacity, u64::MAX)
+            }
+            _ => Self::for_budget(capacity, weighting, cost, max_bytes),
+        }
+    }
+
+    /// A reservoir sampled by key, holding at most `max_events` events that
+    /// cost at most `max_units` in total. `capacity` is only reported.
+    pub(crate) fn keyed(capacity: usize, max_events: usize, max_units: u64) -> Self {
+        Self {
+            capacity,
+            keyed: true,
+            max_events,
+            max_units,
+            ..Self::new(0)
         }
     }
 
     pub(crate) fn sample(&mut self, event: T) -> T {
+        if let Some(fraction) = self.fraction {
+            return self.sample_fraction(event, fraction);
+        }
         self.count += 1;
 
+        let capacity = self.capacity;
         if let Some(slot) = self.events.get_mut(self.count - 1) {
-            std::mem::replace(slot, event)
-        } else if let Some(slot) = self.events.get_mut(fastrand::usize(0..self.count)) {
-            std::mem::replace(slot, event)
+            let ejected = std::mem::replace(slot, event);
+            if self.count == capacity {
+                self.w = 1.0;
+                self.skip();
+            }
+            ejected
+        } else if self.count == self.next {
+            self.skip();
+            std::mem::replace(&mut self.events[fastrand::usize(0..capacity)], event)
         } else {
             event
         }
     }
 
-    pub(crate) fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
-        let iter = self.events.iter_mut().map(std::mem::take).take(self.count);
-        self.count = 0;
-        iter
+    fn sample_fraction(&mut self, event: T, fraction: f64) -> T {
+        self.count += 1;
+        if self.next > 0 {
+            self.next -= 1;
+            return event;
+        }
+        self.next = geometric(fraction);
+        self.events.push(event);
+        T::default()
+    }
+
+    /// Like [`sample`](Self::sample), but a fraction reservoir keeps the
+    /// event if `hash`, in `[0, 1)`, is below its fraction.
+    pub(crate) fn sample_hashed(&mut self, event: T, hash: f64) -> T {
+        let Some(fraction) = self.fraction else {
+            return self.sample(event);
+        };
+        self.count += 1;
+        if hash < fraction {
+            self.events.push(event);
+            T::default()
+        } else {
+            event
+        }
+    }
+
+    /// Choose the next event to keep.
+    fn skip(&mut self) {
+        let k = self.capacity as f64;
+        self.w *= (random().ln() / k).exp();
+        let skip = (random().ln() / (1.0 - self.w).ln()).floor();
+        self.next = self.count.saturating_add(skip as usize).saturating_add(1);
+    }
+
+    /// Whether events are decided on with [`admit`](Self::admit).
+    pub(crate) fn is_unbuffered(&self) -> bool {
+        self.unbuffered
+    }
+
+    /// Decide whether to keep an event straight away, with probability
+    /// `capacity / last_seen` so that about `capacity` are kept per bucket
+    /// if the rate holds steady, but never more.
+    ///
+    /// With a fraction, an event is kept if `hash` is below it, or a random
+    /// number if there is no hash.
+    pub(crate) fn admit(&mut self, hash: Option<f64>) -> bool {
+        self.count += 1;
+        if let Some(fraction) = self.fraction {
+            return hash.unwrap_or_else(fastrand::f64) < fraction;
+        }
+        if self.admitted >= self.capacity {
+            return false;
+        }
+        let p = self.capacity as f64 / self.last_seen.max(1) as f64;
+        let keep = fastrand::f64() < p;
+        self.admitted += usize::from(keep);
+        keep
+    }
+
+    /// Whether this reservoir is sampled with
+    /// [`sample_keyed`](Self::sample_keyed).
+    pub(crate) fn is_keyed(&self) -> bool {
+        self.keyed
+    }
+
+    /// Like [`sample`](Self::sample), but keeps the events with the largest
+    /// `key`s that fit, rather than a uniform sample.
+    ///
+    /// An event costing `units` and `bytes` may eject several cheaper ones:
+    /// the first is returned and the rest are pushed to `overflow`.
+    pub(crate) fn sample_keyed(
+        &mut self,
+        event: T,
+        key: f64,
+        units: u64,
+        bytes: usize,
+        overflow: &mut Vec<T>,
+    ) -> T {
+        self.count += 1;
+        if units > self.max_units || bytes > self.max_bytes {
+            return event;
+        }
+
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.events[slot] = event;
+                slot
+            }
+            None => {
+                self.events.push(event);
+                self.events.len() - 1
+            }
+        };
+        self.keys.push(Keyed {
+            key,
+            slot,
+            units,
+            bytes,
+        });
+        self.units += units;
+        self.bytes += bytes;
+
+        let mut ejected = None;
+        while self.keys.len() > self.max_events
+            || self.units > self.max_units
+            || self.bytes > self.max_bytes
+        {
+            let Some(min) = self.keys.pop() else {
+                break;
+            };
+            self.units -= min.units;
+            self.bytes -= min.bytes;
+            self.free.push(min.slot);
+            let event = std::mem::take(&mut self.events[min.slot]);
+            match ejected {
+                None => ejected = Some(event),
+                Some(_) => overflow.push(event),
+            }
+        }
+        ejected.unwrap_or_default()
+    }
+
+    /// Number of events currently held.
+    pub(crate) fn len(&self) -> usize {
+        if self.unbuffered {
+            0
+        } else if self.fraction.is_some() {
+            self.events.len()
+        } else if self.keyed {
+            self.keys.len()
+        } else {
+            self.count.min(self.capacity)
+        }
+    }
+
+    /// Resize an empty reservoir for a new per-bucket `capacity`, as
+    /// [`for_budget`](Self::for_budget) would build it. Fraction reservoirs
+    /// have no capacity and are left alone.
+    pub(crate) fn resize(&mut self, capacity: usize, weighting: Weighting, cost: Cost) {
+        if self.unbuffered {
+            self.capacity = capacity;
+        } else if self.fraction.is_none() {
+            let max_bytes = (self.max_bytes != usize::MAX).then_some(self.max_bytes);
+            *self = Self::for_budget(capacity, weighting, cost, max_bytes);
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of events offered since the last drain.
+    pub(crate) fn seen(&self) -> usize {
+        self.count
+    }
+
+    pub(crate) fn drain(&mut self) -> std::vec::IntoIter<T> {
+        let held = self.len();
+        self.last_seen = std::mem::take(&mut self.count);
+        if self.unbuffered {
+            self.admitted = 0;
+            return Vec::new().into_iter();
+        }
+        if self.fraction.is_some() {
+            return std::mem::take(&mut self.events).into_iter();
+        }
+        if self.keyed {
+            self.units = 0;
+            self.bytes = 0;
+            self.free.clear();
+            let events = &mut self.events;
+            let held: Vec<T> = (self.keys.drain())
+                .map(|keyed| std::mem::take(&mut events[keyed.slot]))
+                .collect();
+            events.clear();
+            return held.into_iter();
+        }
+        let held: Vec<T> = self.events[..held].iter_mut().map(std::mem::take).collect();
+        held.into_iter()
     }
 }
 
@@ -62,6 +522,95 @@ mod tests {
         assert_eq!(drained.len(), 10);
     }
 
+    #[test]
+    fn keeps_events_from_the_whole_stream() {
+        const CAPACITY: usize = 100;
+        const N: usize = 10_000;
+        // Kept events by which third of the stream they came from.
+        let mut bins = [0usize; 3];
+        for _ in 0..20 {
+            let mut reservoir = Reservoir::new(CAPACITY);
+            let mut ejected = 0;
+            for i in 1..=N {
+                if reservoir.sample(i) != 0 {
+                    ejected += 1;
+                }
+                // Never holds more than its capacity.
+                assert!(i - ejected <= CAPACITY);
+            }
+            assert_eq!(reservoir.len(), CAPACITY);
+            let mut kept: Vec<_> = reservoir.drain().collect();
+            kept.sort();
+            kept.dedup();
+            assert_eq!(kept.len(), CAPACITY);
+            assert!(!kept.contains(&0));
+            for i in kept {
+                bins[(i - 1) * 3 / N] += 1;
+            }
+        }
+        // About 667 each.
+        for kept in bins {
+            assert!((450..900).contains(&kept), "{bins:?}");
+        }
+    }
+
+    #[test]
+    fn keyed_keeps_largest_keys() {
+        let mut reservoir = Reservoir::keyed(3, 3, u64::MAX);
+        for i in 1..=10 {
+            reservoir.sample_keyed(i, i as f64, 1, 0, &mut Vec::new());
+        }
+        let mut drained: Vec<_> = reservoir.drain().collect();
+        drained.sort();
+        assert_eq!(drained, vec![8, 9, 10]);
+    }
+
+    #[test]
+    fn keyed_ejects_until_units_fit() {
+        let mut reservoir = Reservoir::keyed(3, usize::MAX, 10);
+        let mut overflow = Vec::new();
+        for i in 1..=5 {
+            assert_eq!(reservoir.sample_keyed(i, i as f64, 2, 0, &mut overflow), 0);
+        }
+        // Too big to ever fit.
+        assert_eq!(reservoir.sample_keyed(6, 6.0, 11, 0, &mut overflow), 6);
+        assert_eq!(reservoir.sample_keyed(7, 7.0, 5, 0, &mut overflow), 1);
+        assert_eq!(overflow, vec![2, 3]);
+        let mut drained: Vec<_> = reservoir.drain().collect();
+        drained.sort();
+        assert_eq!(drained, vec![4, 5, 7]);
+    }
+
+    #[test]
+    fn fraction_keeps_proportion() {
+        let mut reservoir = Reservoir::fraction(0.1);
+        for i in 1..=100_000 {
+            reservoir.sample(i);
+        }
+        let kept = reservoir.drain().count();
+        assert!((9_000..11_000).contains(&kept), "{kept}");
+
+        let mut reservoir = Reservoir::fraction(1.0);
+        for i in 1..=10 {
+            assert_eq!(reservoir.sample(i), 0);
+        }
+        assert_eq!(reservoir.drain().count(), 10);
+        assert_eq!(reservoir.sample(11), 0);
+        assert_eq!(reservoir.drain().collect::<Vec<_>>(), vec![11]);
+    }
+
+    #[test]
+    fn unbuffered_admits_by_last_rate() {
+        let mut reservoir: Reservoir<usize> = Reservoir::unbuffered(10, None);
+        // Without a previous bucket, the first events are all kept.
+        let kept = (0..100).filter(|_| reservoir.admit(None)).count();
+        assert_eq!(kept, 10);
+        assert_eq!(reservoir.drain().count(), 0);
+
+        let kept = (0..100).filter(|_| reservoir.admit(None)).count();
+        assert!((1..=10).contains(&kept), "{kept}");
+    }
+
     /// Chi-squared goodness-of-fit test for reservoir sampling uniformity.
     ///
     /// Runs many trials of sampling N items into a reservoir of size K,
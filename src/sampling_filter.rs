@@ -0,0 +1,258 @@
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use tracing::subscriber::Interest;
+use tracing::{Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Filter};
+
+use crate::budget::Budget;
+use crate::error::MAX_BUDGETS;
+use crate::filter::BudgetFilter;
+use crate::stats::Stats;
+
+/// A per-layer [`Filter`] that admits events within per-second budgets,
+/// so sampling can be applied to any layer rather than only to formatted
+/// output:
+///
+/// ```
+/// use tracing::Level;
+/// use tracing_log_sample::SamplingFilter;
+/// use tracing_subscriber::prelude::*;
+///
+/// let (filter, stats) = SamplingFilter::builder()
+///     .budget_level(Level::ERROR, 100)
+///     .budget_level(Level::INFO, 1000)
+///     .build();
+/// let subscriber = tracing_subscriber::registry()
+///     .with(tracing_subscriber::fmt::layer().with_filter(filter));
+/// ```
+///
+/// A filter has to decide as each event arrives, so it can't hold events in
+/// a reservoir the way [`SamplingLayer`](crate::SamplingLayer) does. Instead
+/// each budget admits events with a probability based on how many arrived
+/// in the previous bucket, up to its per-bucket capacity. Under steady load
+/// this keeps roughly the same number of events, spread across the bucket;
+/// after a sudden burst the first events of the bucket are favoured.
+///
+/// Events a budget rejects are offered to the next matching budget unless it
+/// was built with [`Budget::no_cascade`]. Per-budget writers are ignored.
+pub struct SamplingFilter<S> {
+    filters: Vec<Box<dyn BudgetFilter<S>>>,
+    no_cascade: u64,
+    bucket_duration: Duration,
+    state: Mutex<FilterState>,
+    stats: Stats,
+}
+
+struct FilterState {
+    bucket_start: Instant,
+    budgets: Vec<BucketCounts>,
+    received: u64,
+    admitted: u64,
+}
+
+#[derive(Clone, Copy)]
+struct BucketCounts {
+    capacity: u64,
+    seen: u64,
+    admitted: u64,
+    last_seen: u64,
+}
+
+/// Builder for [`SamplingFilter`].
+pub struct SamplingFilterBuilder<S> {
+    budgets: Vec<Budget<S>>,
+    bucket_duration: Duration,
+}
+
+impl<S: Subscriber> SamplingFilter<S> {
+    /// Create a new [`SamplingFilterBuilder`] with default settings.
+    pub fn builder() -> SamplingFilterBuilder<S> {
+        SamplingFilterBuilder {
+            budgets: Vec::new(),
+            bucket_duration: Duration::from_millis(50),
+        }
+    }
+}
+
+impl<S: Subscriber> SamplingFilterBuilder<S> {
+    /// Add a budget admitting up to `limit_per_second` events accepted by
+    /// `filter`. See
+    /// [`SamplingLayerBuilder::budget`](crate::SamplingLayerBuilder::budget).
+    pub fn budget(self, filter: impl BudgetFilter<S>, limit_per_second: u64) -> Self {
+        self.budget_with(Budget::new(filter).limit(limit_per_second))
+    }
+
+    /// Add a budget for events at `level` or more severe.
+    pub fn budget_level(self, level: tracing::Level, limit_per_second: u64) -> Self {
+        self.budget_with(Budget::level(level).limit(limit_per_second))
+    }
+
+    /// Add a budget configured with a [`Budget`].
+    pub fn budget_with(mut self, budget: Budget<S>) -> Self {
+        self.budgets.push(budget);
+        self
+    }
+
+    /// Set the time bucket duration. Defaults to 50ms.
+    pub fn bucket_duration(mut self, duration: Duration) -> Self {
+        self.bucket_duration = duration;
+        self
+    }
+
+    /// Create the filter and a [`Stats`] handle for reading its counters.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bucket duration is zero or more than 64 budgets were
+    /// added. Budgets whose per-bucket capacity rounds to zero are skipped.
+    pub fn build(self) -> (SamplingFilter<S>, Stats) {
+        assert!(
+            !self.bucket_duration.is_zero(),
+            "bucket_duration must be > 0"
+        );
+        let bucket_secs = self.bucket_duration.as_secs_f64();
+        let mut filters = Vec::new();
+        let mut names = Vec::new();
+        let mut budgets = Vec::new();
+        let mut no_cascade = 0;
+        for budget in self.budgets {
+            let capacity = (budget.limit_per_second as f64 * bucket_secs).ceil() as u64;
+            if capacity == 0 {
+                continue;
+            }
+            assert!(
+                filters.len() < MAX_BUDGETS,
+                "at most {MAX_BUDGETS} budgets are supported"
+            );
+            if !budget.cascade {
+                no_cascade |= 1 << filters.len();
+            }
+            filters.push(budget.filter);
+            names.push(budget.name);
+            budgets.push(BucketCounts {
+                capacity,
+                seen: 0,
+                admitted: 0,
+                last_seen: 0,
+            });
+        }
+        let stats = Stats::new(
+            names
+                .into_iter()
+                .zip(budgets.iter().map(|budget| budget.capacity as usize)),
+        );
+        let filter = SamplingFilter {
+            filters,
+            no_cascade,
+            bucket_duration: self.bucket_duration,
+            state: Mutex::new(FilterState {
+                bucket_start: Instant::now(),
+                budgets,
+                received: 0,
+                admitted: 0,
+            }),
+            stats: stats.clone(),
+        };
+        (filter, stats)
+    }
+}
+
+impl<S: 'static> SamplingFilter<S> {
+    fn matched(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> u64 {
+        let mut matched = 0;
+        for (i, filter) in self.filters.iter().enumerate() {
+            if filter.enabled(event.metadata(), ctx) && filter.event_enabled(event, ctx) {
+                matched |= 1 << i;
+            }
+        }
+        matched
+    }
+
+    fn rotate(&self, state: &mut FilterState, now: Instant) {
+        if now.duration_since(state.bucket_start) < self.bucket_duration {
+            return;
+        }
+        state.bucket_start = now;
+        for (budget, counters) in state.budgets.iter_mut().zip(&*self.stats.budgets) {
+            counters
+                .last_bucket_received
+                .store(budget.seen, Ordering::Relaxed);
+            counters
+                .last_bucket_sampled
+                .store(budget.admitted, Ordering::Relaxed);
+            counters.fill.store(0, Ordering::Relaxed);
+            budget.last_seen = budget.seen;
+            budget.seen = 0;
+            budget.admitted = 0;
+        }
+        self.stats.record_bucket(
+            std::mem::take(&mut state.received),
+            std::mem::take(&mut state.admitted),
+        );
+    }
+
+    /// Decide whether to admit an event matching the budgets in `matched`.
+    fn admit(&self, meta: &'static Metadata<'static>, matched: u64) -> bool {
+        let stats = &self.stats;
+        stats.received.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        self.rotate(&mut state, Instant::now());
+        state.received += 1;
+        let mut last = None;
+        for (i, budget) in state.budgets.iter_mut().enumerate() {
+            if matched & (1 << i) == 0 {
+                continue;
+            }
+            let counters = &stats.budgets[i];
+            counters.received.fetch_add(1, Ordering::Relaxed);
+            budget.seen += 1;
+            let expected = budget.last_seen.max(1) as f64;
+            let p = budget.capacity as f64 / expected;
+            if budget.admitted < budget.capacity && (p >= 1.0 || fastrand::f64() < p) {
+                budget.admitted += 1;
+                counters.sampled.fetch_add(1, Ordering::Relaxed);
+                counters.fill.store(budget.admitted, Ordering::Relaxed);
+                state.admitted += 1;
+                drop(state);
+                stats.sampled.fetch_add(1, Ordering::Relaxed);
+                stats.record_sampled([meta]);
+                return true;
+            }
+            last = Some(counters);
+            if self.no_cascade & (1 << i) != 0 {
+                break;
+            }
+        }
+        drop(state);
+        stats.dropped.fetch_add(1, Ordering::Relaxed);
+        if let Some(counters) = last {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        stats.record_dropped(meta);
+        false
+    }
+}
+
+impl<S: Subscriber> Filter<S> for SamplingFilter<S> {
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let mut interest = Interest::never();
+        for filter in &self.filters {
+            let budget = filter.callsite_enabled(meta);
+            if !budget.is_never() {
+                interest = Interest::sometimes();
+            }
+        }
+        interest
+    }
+
+    fn enabled(&self, meta: &Metadata<'_>, ctx: &Context<'_, S>) -> bool {
+        self.filters.iter().any(|filter| filter.enabled(meta, ctx))
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> bool {
+        let matched = self.matched(event, ctx);
+        matched != 0 && self.admit(event.metadata(), matched)
+    }
+}
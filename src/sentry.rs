@@ -0,0 +1,35 @@
+use sentry_core::protocol::{Event, Level};
+
+use crate::sink::Buffered;
+
+fn level(level: &tracing::Level) -> Level {
+    match *level {
+        tracing::Level::ERROR => Level::Error,
+        tracing::Level::WARN => Level::Warning,
+        tracing::Level::INFO => Level::Info,
+        tracing::Level::DEBUG | tracing::Level::TRACE => Level::Debug,
+    }
+}
+
+/// Capture every event sampled from one of `budgets`, a bitset of budget
+/// indices, on the current Sentry hub.
+pub(crate) fn forward(events: &[Buffered], budgets: u64) {
+    if budgets == 0 {
+        return;
+    }
+    for event in events {
+        let (Some(meta), Some(budget)) = (event.meta, event.budget) else {
+            continue;
+        };
+        if budgets & (1 << budget) == 0 || event.bytes.is_empty() {
+            continue;
+        }
+        let message = String::from_utf8_lossy(&event.bytes);
+        sentry_core::capture_event(Event {
+            message: Some(message.trim_end().to_owned()),
+            level: level(meta.level()),
+            logger: Some(meta.target().to_owned()),
+            ..Default::default()
+        });
+    }
+}
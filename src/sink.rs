@@ -0,0 +1,396 @@
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use tracing::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+#[cfg(feature = "tokio")]
+use crate::async_sink::AsyncWorker;
+use crate::reemit::{Fields, ReEmitter};
+use crate::stats::Stats;
+
+/// A formatted event held in a reservoir or waiting to be written.
+///
+/// A missing `meta` marks an unused reservoir slot.
+#[derive(Default)]
+pub(crate) struct Buffered {
+    pub(crate) seq: u64,
+    pub(crate) meta: Option<&'static Metadata<'static>>,
+    /// The budget whose reservoir the event was drained from, once drained.
+    pub(crate) budget: Option<usize>,
+    pub(crate) bytes: Vec<u8>,
+    /// Captured field values, instead of `bytes`, when re-emitting.
+    pub(crate) fields: Fields,
+    /// Already written by [`Emit::Immediate`](crate::Emit::Immediate), and
+    /// only holding its reservoir slot.
+    pub(crate) written: bool,
+}
+
+pub(crate) type Batch = Vec<Buffered>;
+
+/// When the layer calls [`flush`](std::io::Write::flush) on its writers.
+///
+/// Writers are always flushed by [`Handle::flush`](crate::Handle::flush)
+/// and when the layer is dropped. To also `fsync`, use a writer whose
+/// `flush` calls [`File::sync_data`](std::fs::File::sync_data).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FlushPolicy {
+    /// Only flush on [`Handle::flush`](crate::Handle::flush) and drop.
+    Never,
+    /// Flush after every write. Consecutive events that share a write are
+    /// flushed together.
+    PerEvent,
+    /// Flush at most once per bucket.
+    ///
+    /// The flush happens with the first write a bucket's length after the
+    /// last, so events written just before a quiet spell stay in a
+    /// buffering writer until the next write,
+    /// [`Handle::flush`](crate::Handle::flush) or drop.
+    #[default]
+    PerBucket,
+    /// Flush at most once per interval, with the first write after it has
+    /// passed, as for [`PerBucket`](Self::PerBucket).
+    Every(Duration),
+}
+
+/// Called with each error returned by a writer.
+pub(crate) type OnWriteError = Arc<dyn Fn(&io::Error) + Send + Sync>;
+
+/// The layer's writer, plus any per-budget writers that override it.
+pub(crate) struct Writers<W> {
+    pub(crate) default: W,
+    /// Indexed by budget.
+    pub(crate) budgets: Vec<Option<BoxMakeWriter>>,
+    /// Re-dispatches events instead of writing them. Summary and report
+    /// lines still go to `default`.
+    pub(crate) re_emit: Option<ReEmitter>,
+    /// Bitset of budgets whose events are also captured by Sentry.
+    #[cfg(feature = "sentry")]
+    pub(crate) sentry: u64,
+    /// [`FlushPolicy::PerBucket`] is resolved to [`FlushPolicy::Every`].
+    pub(crate) flush_policy: FlushPolicy,
+    pub(crate) last_flush: Mutex<Instant>,
+    pub(crate) on_error: Option<OnWriteError>,
+    pub(crate) fallback: Option<Fallback>,
+}
+
+/// A writer that takes over while the primary writers keep failing.
+///
+/// Every failed write is retried on the fallback. After `threshold`
+/// consecutive failures the primary is skipped entirely, except for one
+/// probe write every `retry_after`; the first probe to succeed switches
+/// back.
+pub(crate) struct Fallback {
+    pub(crate) writer: BoxMakeWriter,
+    pub(crate) threshold: u32,
+    pub(crate) retry_after: Duration,
+    pub(crate) state: Mutex<FallbackState>,
+}
+
+#[derive(Default)]
+pub(crate) struct FallbackState {
+    /// Consecutive failed writes to the primary.
+    failures: u32,
+    /// When to next probe the primary, while switched to the fallback.
+    retry_at: Option<Instant>,
+}
+
+impl Fallback {
+    fn use_primary(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .retry_at
+            .is_none_or(|retry_at| Instant::now() >= retry_at)
+    }
+
+    fn record(&self, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        if ok {
+            *state = FallbackState::default();
+            return;
+        }
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.threshold {
+            state.retry_at = Some(Instant::now() + self.retry_after);
+        }
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a>> Writers<W> {
+    #[cold]
+    fn write_batch(&self, events: &[Buffered], stats: &Stats) {
+        let start = Instant::now();
+        #[cfg(feature = "sentry")]
+        crate::sentry::forward(events, self.sentry);
+        if let Some(re_emit) = &self.re_emit {
+            let lost = re_emit.emit(events.iter());
+            stats.lost.fetch_add(lost, Ordering::Relaxed);
+            self.write_to(
+                &self.default,
+                events.iter().filter(|event| event.meta.is_none()),
+                stats,
+            );
+        } else {
+            let routed = |budget: Option<usize>| {
+                budget
+                    .and_then(|i| self.budgets.get(i))
+                    .is_some_and(Option::is_some)
+            };
+            self.write_to(
+                &self.default,
+                events.iter().filter(|event| !routed(event.budget)),
+                stats,
+            );
+            for (i, writer) in self.budgets.iter().enumerate() {
+                if let Some(writer) = writer {
+                    self.write_to(
+                        writer,
+                        events.iter().filter(|event| event.budget == Some(i)),
+                        stats,
+                    );
+                }
+            }
+        }
+        if let FlushPolicy::Every(interval) = self.flush_policy {
+            let mut last_flush = self.last_flush.lock().unwrap();
+            if last_flush.elapsed() >= interval {
+                *last_flush = Instant::now();
+                self.flush(stats);
+            }
+        }
+        stats.record_write(start.elapsed(), events.len());
+    }
+
+    fn flush(&self, stats: &Stats) {
+        self.check(self.default.make_writer().flush(), stats);
+        for writer in self.budgets.iter().flatten() {
+            self.check(writer.make_writer().flush(), stats);
+        }
+        if let Some(fallback) = &self.fallback {
+            self.check(fallback.writer.make_writer().flush(), stats);
+        }
+    }
+
+    /// Write `events` to `writer`, routing each by its metadata with
+    /// [`MakeWriter::make_writer_for`]. Summary lines have no metadata and
+    /// use [`MakeWriter::make_writer`].
+    ///
+    /// Consecutive events with the same level and target are coalesced into
+    /// a single write, so a batch costs a handful of syscalls rather than one
+    /// per line.
+    fn write_to<'e, M: for<'a> MakeWriter<'a>>(
+        &self,
+        writer: &M,
+        events: impl Iterator<Item = &'e Buffered>,
+        stats: &Stats,
+    ) {
+        let key = |event: &Buffered| event.meta.map(|meta| (meta.level(), meta.target()));
+        let mut run: Vec<&Buffered> = Vec::new();
+        let mut buf = Vec::new();
+        for event in events {
+            if run.first().is_some_and(|first| key(first) != key(event)) {
+                self.write_run(writer, &run, &mut buf, stats);
+                run.clear();
+            }
+            run.push(event);
+        }
+        self.write_run(writer, &run, &mut buf, stats);
+    }
+
+    fn write_run<M: for<'a> MakeWriter<'a>>(
+        &self,
+        writer: &M,
+        run: &[&Buffered],
+        buf: &mut Vec<u8>,
+        stats: &Stats,
+    ) {
+        let bytes = match run {
+            [] => return,
+            [event] => &event.bytes,
+            _ => {
+                buf.clear();
+                for event in run {
+                    buf.extend_from_slice(&event.bytes);
+                }
+                &*buf
+            }
+        };
+        let meta = run[0].meta;
+        let fallback = self.fallback.as_ref();
+        if fallback.is_none_or(Fallback::use_primary) {
+            let result = self.write_bytes(writer, meta, bytes);
+            if let Some(fallback) = fallback {
+                fallback.record(result.is_ok());
+            }
+            let failed = result.is_err();
+            self.check(result, stats);
+            if !failed {
+                return;
+            }
+        }
+        if let Some(fallback) = fallback {
+            self.check(self.write_bytes(&fallback.writer, meta, bytes), stats);
+        }
+    }
+
+    fn write_bytes<M: for<'a> MakeWriter<'a>>(
+        &self,
+        writer: &M,
+        meta: Option<&Metadata<'_>>,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let mut writer = match meta {
+            Some(meta) => writer.make_writer_for(meta),
+            None => writer.make_writer(),
+        };
+        writer.write_all(bytes)?;
+        if self.flush_policy == FlushPolicy::PerEvent {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, result: io::Result<()>, stats: &Stats) {
+        if let Err(e) = result {
+            stats.write_errors.fetch_add(1, Ordering::Relaxed);
+            if let Some(on_error) = &self.on_error {
+                on_error(&e);
+            }
+        }
+    }
+}
+
+/// Where released batches of sampled events are written.
+pub(crate) enum Sink<W> {
+    /// Write on the thread that released the batch.
+    Direct(Writers<W>),
+    /// Hand the batch to a dedicated I/O thread.
+    Worker(Worker),
+    /// Hand the batch to a task writing to an `AsyncWrite`.
+    #[cfg(feature = "tokio")]
+    Async(AsyncWorker),
+}
+
+impl<W: for<'a> MakeWriter<'a>> Sink<W> {
+    /// Write a batch of events. When `block` is false and a worker queue is
+    /// full, the batch is discarded rather than waiting for the worker.
+    pub(crate) fn write(&self, events: Batch, block: bool, stats: &Stats) {
+        if events.is_empty() {
+            return;
+        }
+        match self {
+            Sink::Direct(writers) => writers.write_batch(&events, stats),
+            Sink::Worker(worker) => worker.send(events, block),
+            #[cfg(feature = "tokio")]
+            Sink::Async(worker) => worker.send(events),
+        }
+    }
+
+    /// Wait until everything handed to the sink so far has been written,
+    /// then flush the writers.
+    pub(crate) fn sync(&self, stats: &Stats) {
+        match self {
+            Sink::Direct(writers) => writers.flush(stats),
+            Sink::Worker(worker) => worker.sync(),
+            // Waiting could deadlock a runtime thread the task needs.
+            #[cfg(feature = "tokio")]
+            Sink::Async(worker) => worker.flush(),
+        }
+    }
+}
+
+enum Message {
+    Write(Batch),
+    Sync(SyncSender<()>),
+}
+
+/// A bounded queue feeding a dedicated writer thread.
+pub(crate) struct Worker {
+    sender: Option<SyncSender<Message>>,
+    handle: Option<JoinHandle<()>>,
+    stats: Stats,
+}
+
+impl Worker {
+    pub(crate) fn spawn<W>(writers: Writers<W>, capacity: usize, stats: Stats) -> Self
+    where
+        W: for<'a> MakeWriter<'a> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel::<Message>(capacity);
+        let worker_stats = stats.clone();
+        let handle = std::thread::Builder::new()
+            .name("tracing-log-sample-io".into())
+            .spawn(move || {
+                for message in receiver {
+                    match message {
+                        Message::Write(batch) => writers.write_batch(&batch, &worker_stats),
+                        Message::Sync(done) => {
+                            writers.flush(&worker_stats);
+                            let _ = done.send(());
+                        }
+                    }
+                }
+                writers.flush(&worker_stats);
+            })
+            .expect("failed to spawn I/O worker thread");
+        Self {
+            sender: Some(sender),
+            handle: Some(handle),
+            stats,
+        }
+    }
+
+    fn send(&self, events: Batch, block: bool) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let len = events.len() as u64;
+        let message = Message::Write(events);
+        let sent = if block {
+            sender.send(message).is_ok()
+        } else {
+            match sender.try_send(message) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => false,
+            }
+        };
+        if !sent {
+            self.stats.lost.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Worker {
+    fn sync(&self) {
+        let (Some(sender), Some(handle)) = (&self.sender, &self.handle) else {
+            return;
+        };
+        // A writer that logs (or panics) on the worker thread must not wait
+        // for itself.
+        if handle.thread().id() == std::thread::current().id() {
+            return;
+        }
+        let (done, wait) = mpsc::sync_channel(1);
+        if sender.send(Message::Sync(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Disconnect the queue so the thread exits once it has written and
+        // flushed everything already queued.
+        drop(self.sender.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
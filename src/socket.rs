@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+const DEFAULT_MAX_BACKLOG: usize = 1 << 20;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+enum Endpoint {
+    Tcp(Vec<SocketAddr>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+}
+
+struct Connection {
+    stream: Option<Stream>,
+    /// Complete lines not yet written, oldest first.
+    backlog: VecDeque<Vec<u8>>,
+    /// Bytes of the front line already written.
+    written: usize,
+    backlog_bytes: usize,
+    next_attempt: Instant,
+    backoff: Duration,
+}
+
+/// A [`MakeWriter`] that streams sampled lines to a TCP or Unix socket, such
+/// as a local Vector or Fluent Bit agent.
+///
+/// The connection is opened on the first write and re-opened, with
+/// exponential backoff, whenever a write fails. While disconnected, lines
+/// are kept in a backlog of at most [`max_backlog`](Self::max_backlog)
+/// bytes; once it is full the oldest lines are dropped and counted in
+/// [`dropped`](Self::dropped).
+///
+/// Connecting and writing block the thread doing the write, so pair this
+/// with [`non_blocking`](crate::SamplingLayerBuilder::non_blocking).
+pub struct SocketWriter {
+    endpoint: Endpoint,
+    connect_timeout: Duration,
+    max_backlog: usize,
+    connection: Mutex<Connection>,
+    dropped: AtomicU64,
+}
+
+impl SocketWriter {
+    fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_backlog: DEFAULT_MAX_BACKLOG,
+            connection: Mutex::new(Connection {
+                stream: None,
+                backlog: VecDeque::new(),
+                written: 0,
+                backlog_bytes: 0,
+                next_attempt: Instant::now(),
+                backoff: MIN_BACKOFF,
+            }),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Stream to `addr` over TCP, e.g. `"127.0.0.1:9000"`. The address is
+    /// resolved now; the connection is made on the first write.
+    pub fn tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to connect to",
+            ));
+        }
+        Ok(Self::new(Endpoint::Tcp(addrs)))
+    }
+
+    /// Stream to the Unix socket at `path`. The connection is made on the
+    /// first write.
+    #[cfg(unix)]
+    pub fn unix(path: impl AsRef<Path>) -> Self {
+        Self::new(Endpoint::Unix(path.as_ref().to_owned()))
+    }
+
+    /// The most bytes to hold while disconnected. Defaults to 1 MiB.
+    pub fn max_backlog(mut self, bytes: usize) -> Self {
+        self.max_backlog = bytes;
+        self
+    }
+
+    /// How long to wait for a TCP connection before backing off. Defaults to
+    /// one second.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Lines dropped because the backlog was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn connect(&self) -> io::Result<Stream> {
+        match &self.endpoint {
+            Endpoint::Tcp(addrs) => {
+                let mut last_err = None;
+                for addr in addrs {
+                    match TcpStream::connect_timeout(addr, self.connect_timeout) {
+                        Ok(stream) => {
+                            stream.set_write_timeout(Some(self.connect_timeout))?;
+                            return Ok(Stream::Tcp(stream));
+                        }
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err(last_err.expect("at least one address"))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_write_timeout(Some(self.connect_timeout))?;
+                Ok(Stream::Unix(stream))
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn push(&self, conn: &mut Connection, buf: &[u8]) {
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            conn.backlog_bytes += line.len();
+            conn.backlog.push_back(line.to_vec());
+        }
+        // Never drop a line that is partially written, or the receiver would
+        // see half of it spliced onto the next.
+        let keep = usize::from(conn.written > 0);
+        while conn.backlog_bytes > self.max_backlog && conn.backlog.len() > keep {
+            let line = conn.backlog.remove(keep).expect("len checked");
+            conn.backlog_bytes -= line.len();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn drain(&self, conn: &mut Connection) {
+        if conn.stream.is_none() {
+            let now = Instant::now();
+            if now < conn.next_attempt {
+                return;
+            }
+            match self.connect() {
+                Ok(stream) => {
+                    conn.stream = Some(stream);
+                    conn.backoff = MIN_BACKOFF;
+                }
+                Err(_) => {
+                    conn.next_attempt = now + conn.backoff;
+                    conn.backoff = (conn.backoff * 2).min(MAX_BACKOFF);
+                    return;
+                }
+            }
+        }
+        let Some(stream) = &mut conn.stream else {
+            return;
+        };
+        while let Some(line) = conn.backlog.front() {
+            match stream.write(&line[conn.written..]) {
+                Ok(0) | Err(_) => {
+                    // Reconnect on the next write. The partial line is
+                    // resent in full, as the old connection got only part.
+                    conn.stream = None;
+                    conn.written = 0;
+                    return;
+                }
+                Ok(n) => {
+                    conn.written += n;
+                    if conn.written == line.len() {
+                        conn.backlog_bytes -= line.len();
+                        conn.backlog.pop_front();
+                        conn.written = 0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Write for &SocketWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.lock();
+        self.push(&mut conn, buf);
+        self.drain(&mut conn);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.lock();
+        self.drain(&mut conn);
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for SocketWriter {
+    type Writer = &'a SocketWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::callsite::Identifier;
+use tracing::{Level, Metadata};
+
+use crate::histogram::{Histogram, LatencyHistogram};
+use crate::summary::{LEVELS, level_index, level_name};
+
+/// Targets tracked individually before further targets are folded into
+/// [`OTHER_TARGETS`].
+const MAX_TARGETS: usize = 256;
+
+/// Callsites tracked individually for [`Stats::top_dropped_callsites`].
+const MAX_CALLSITES: usize = 1024;
+
+/// Time constant of the exponentially weighted event rates.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Key under which targets beyond the tracking limit are counted.
+pub const OTHER_TARGETS: &str = "<other>";
+
+/// Shared handle for reading layer event counters.
+///
+/// Returned by [`SamplingLayerBuilder::build`](crate::SamplingLayerBuilder::build).
+/// All counts are cumulative since layer creation.
+#[derive(Clone)]
+pub struct Stats {
+    pub(crate) received: Arc<AtomicU64>,
+    pub(crate) sampled: Arc<AtomicU64>,
+    pub(crate) dropped: Arc<AtomicU64>,
+    pub(crate) lost: Arc<AtomicU64>,
+    pub(crate) write_errors: Arc<AtomicU64>,
+    pub(crate) budgets: Arc<[BudgetCounters]>,
+    pub(crate) write_latency: Arc<Histogram>,
+    /// Smoothed nanoseconds spent writing each event, or zero before the
+    /// first write.
+    pub(crate) write_cost: Arc<AtomicU64>,
+    /// `(received, sampled)` for the last completed bucket.
+    last_bucket: Arc<Mutex<(u64, u64)>>,
+    rates: Arc<Mutex<Rates>>,
+    levels: Arc<[Counts; 5]>,
+    targets: Arc<Mutex<HashMap<&'static str, SampleCounts>>>,
+    callsites: Arc<Mutex<HashMap<Identifier, CallsiteDrops>>>,
+}
+
+/// Exponentially weighted per-second rates, updated at every bucket
+/// boundary.
+struct Rates {
+    updated: Instant,
+    /// Cumulative `[received, sampled, dropped]` at `updated`.
+    totals: [u64; 3],
+    /// Smoothed `[received, sampled, dropped]` per second, or `None` until
+    /// the first bucket completes.
+    per_sec: Option<[f64; 3]>,
+}
+
+impl Rates {
+    fn update(&mut self, totals: [u64; 3], now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        let current = std::array::from_fn(|i| (totals[i] - self.totals[i]) as f64 / elapsed);
+        self.per_sec = Some(match self.per_sec {
+            None => current,
+            Some(previous) => {
+                let alpha = 1.0 - (-elapsed / RATE_WINDOW.as_secs_f64()).exp();
+                std::array::from_fn(|i| previous[i] + alpha * (current[i] - previous[i]))
+            }
+        });
+        self.updated = now;
+        self.totals = totals;
+    }
+
+    fn get(&self, index: usize) -> f64 {
+        self.per_sec.map_or(0.0, |rates| rates[index])
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    sampled: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+pub(crate) struct BudgetCounters {
+    name: Option<String>,
+    pub(crate) received: AtomicU64,
+    pub(crate) sampled: AtomicU64,
+    pub(crate) dropped: AtomicU64,
+    pub(crate) capacity: AtomicU64,
+    pub(crate) fill: AtomicU64,
+    pub(crate) last_bucket_received: AtomicU64,
+    pub(crate) last_bucket_sampled: AtomicU64,
+}
+
+/// A snapshot of one budget's counters.
+///
+/// Returned by [`Stats::budget`] and [`Stats::budgets`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct BudgetStats {
+    /// Events offered to this budget's reservoir, including events cascaded
+    /// from earlier budgets.
+    pub received: u64,
+    /// Events written out of this budget's reservoir.
+    pub sampled: u64,
+    /// Events dropped because this was the last matching budget and its
+    /// reservoir was full.
+    pub dropped: u64,
+    /// Maximum events the reservoir holds per bucket.
+    pub capacity: u64,
+    /// Events currently held in the reservoir.
+    pub fill: u64,
+    /// Events offered to the reservoir during the last completed bucket. A
+    /// value far below `capacity` suggests the budget is oversized; one far
+    /// above means it is saturated.
+    pub last_bucket_received: u64,
+    /// Events written out of the reservoir at the end of the last completed
+    /// bucket.
+    pub last_bucket_sampled: u64,
+}
+
+impl BudgetStats {
+    /// The fraction of events offered to this budget in the last completed
+    /// bucket that were kept, between 0 and 1. Returns 1 if no events were
+    /// offered.
+    pub fn sample_ratio(&self) -> f64 {
+        ratio(self.last_bucket_sampled, self.last_bucket_received)
+    }
+}
+
+fn ratio(sampled: u64, received: u64) -> f64 {
+    if received == 0 {
+        1.0
+    } else {
+        (sampled as f64 / received as f64).min(1.0)
+    }
+}
+
+/// Sampled and dropped counts for one level or target.
+///
+/// Returned by [`Stats::level`], [`Stats::target`] and [`Stats::targets`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct SampleCounts {
+    /// Events written out of a reservoir.
+    pub sampled: u64,
+    /// Events dropped after failing to enter any reservoir.
+    pub dropped: u64,
+}
+
+/// Drop count for a single callsite.
+///
+/// Returned by [`Stats::top_dropped_callsites`].
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct CallsiteDrops {
+    /// The callsite's metadata: its name, target, file and line.
+    pub metadata: &'static Metadata<'static>,
+    /// Events from this callsite dropped after failing to enter any
+    /// reservoir.
+    pub dropped: u64,
+}
+
+/// A point-in-time copy of every counter in [`Stats`].
+///
+/// Returned by [`Stats::snapshot`]. With the `serde` feature enabled this
+/// implements `Serialize`, so it can be embedded directly in health-check
+/// responses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[non_exhaustive]
+pub struct StatsSnapshot {
+    /// See [`Stats::received`].
+    pub received: u64,
+    /// See [`Stats::sampled`].
+    pub sampled: u64,
+    /// See [`Stats::dropped`].
+    pub dropped: u64,
+    /// See [`Stats::lost`].
+    pub lost: u64,
+    /// See [`Stats::write_errors`].
+    pub write_errors: u64,
+    /// See [`Stats::budgets`].
+    pub budgets: Vec<BudgetStats>,
+    /// See [`Stats::level`], keyed by lowercase level name.
+    pub levels: BTreeMap<&'static str, SampleCounts>,
+    /// See [`Stats::targets`].
+    pub targets: BTreeMap<&'static str, SampleCounts>,
+}
+
+impl Stats {
+    pub(crate) fn new(budgets: impl IntoIterator<Item = (Option<String>, usize)>) -> Self {
+        Self {
+            received: Arc::new(AtomicU64::new(0)),
+            sampled: Arc::new(AtomicU64::new(0)),
+            dropped: Arc::new(AtomicU64::new(0)),
+            lost: Arc::new(AtomicU64::new(0)),
+            write_errors: Arc::new(AtomicU64::new(0)),
+            budgets: budgets
+                .into_iter()
+                .map(|(name, capacity)| BudgetCounters {
+                    name,
+                    capacity: AtomicU64::new(capacity as u64),
+                    ..BudgetCounters::default()
+                })
+                .collect(),
+            write_latency: Arc::default(),
+            write_cost: Arc::default(),
+            last_bucket: Arc::default(),
+            rates: Arc::new(Mutex::new(Rates {
+                updated: Instant::now(),
+                totals: [0; 3],
+                per_sec: None,
+            })),
+            levels: Arc::default(),
+            targets: Arc::default(),
+            callsites: Arc::default(),
+        }
+    }
+
+    /// Record the totals for a bucket that has just been drained.
+    pub(crate) fn record_bucket(&self, received: u64, sampled: u64) {
+        *self.last_bucket.lock().unwrap() = (received, sampled);
+        let totals = [self.received(), self.sampled(), self.dropped()];
+        self.rates.lock().unwrap().update(totals, Instant::now());
+    }
+
+    /// Record events written out of the reservoirs.
+    pub(crate) fn record_sampled(
+        &self,
+        events: impl IntoIterator<Item = &'static Metadata<'static>>,
+    ) {
+        let mut targets = self.targets.lock().unwrap();
+        for meta in events {
+            self.levels[level_index(meta.level())]
+                .sampled
+                .fetch_add(1, Ordering::Relaxed);
+            Self::target_entry(&mut targets, meta).sampled += 1;
+        }
+    }
+
+    /// Record how long writing a batch of `events` took.
+    pub(crate) fn record_write(&self, elapsed: Duration, events: usize) {
+        self.write_latency.record(elapsed);
+        if events == 0 {
+            return;
+        }
+        let cost = (elapsed.as_nanos() / events as u128).clamp(1, u64::MAX as u128) as u64;
+        let previous = self.write_cost.load(Ordering::Relaxed);
+        let smoothed = if previous == 0 {
+            cost
+        } else {
+            // An exponential moving average over roughly the last 8 writes.
+            previous - previous / 8 + cost / 8
+        };
+        self.write_cost.store(smoothed, Ordering::Relaxed);
+    }
+
+    /// Record an event dropped after failing to enter any reservoir.
+    pub(crate) fn record_dropped(&self, meta: &'static Metadata<'static>) {
+        self.levels[level_index(meta.level())]
+            .dropped
+            .fetch_add(1, Ordering::Relaxed);
+        Self::target_entry(&mut self.targets.lock().unwrap(), meta).dropped += 1;
+        let mut callsites = self.callsites.lock().unwrap();
+        let len = callsites.len();
+        match callsites.entry(meta.callsite()) {
+            Entry::Occupied(mut entry) => entry.get_mut().dropped += 1,
+            Entry::Vacant(entry) if len < MAX_CALLSITES => {
+                entry.insert(CallsiteDrops {
+                    metadata: meta,
+                    dropped: 1,
+                });
+            }
+            Entry::Vacant(_) => {}
+        }
+    }
+
+    fn target_entry<'a>(
+        targets: &'a mut HashMap<&'static str, SampleCounts>,
+        meta: &'static Metadata<'static>,
+    ) -> &'a mut SampleCounts {
+        let key = if targets.len() < MAX_TARGETS || targets.contains_key(meta.target()) {
+            meta.target()
+        } else {
+            OTHER_TARGETS
+        };
+        targets.entry(key).or_default()
+    }
+
+    /// Events that matched at least one filter.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Events that were kept in a reservoir. Those dropped as the bucket
+    /// ends, to fit a [`parent`](crate::Budget::parent) budget or the
+    /// [`global_limit`](crate::SamplingLayerBuilder::global_limit) or to
+    /// share a [`keyed`](crate::Budget::keyed) budget, or evicted with their
+    /// key, count as [`dropped`](Self::dropped) instead.
+    pub fn sampled(&self) -> u64 {
+        self.sampled.load(Ordering::Relaxed)
+    }
+
+    /// Events that were dropped after failing to enter any reservoir.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Sampled events that were discarded because the I/O worker's queue was
+    /// full. Always zero unless
+    /// [`non_blocking`](crate::SamplingLayerBuilder::non_blocking) is used.
+    pub fn lost(&self) -> u64 {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    /// Writes and flushes that returned an error. Events in a failed write
+    /// are lost; see
+    /// [`on_write_error`](crate::SamplingLayerBuilder::on_write_error) to
+    /// find out why.
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    /// Events per second matching at least one filter, smoothed over roughly
+    /// the last minute. Updated at each bucket boundary and zero until the
+    /// first bucket completes.
+    pub fn received_per_sec(&self) -> f64 {
+        self.rates.lock().unwrap().get(0)
+    }
+
+    /// Events per second kept in a reservoir. See
+    /// [`received_per_sec`](Self::received_per_sec).
+    pub fn sampled_per_sec(&self) -> f64 {
+        self.rates.lock().unwrap().get(1)
+    }
+
+    /// Events per second dropped after failing to enter any reservoir. See
+    /// [`received_per_sec`](Self::received_per_sec).
+    pub fn dropped_per_sec(&self) -> f64 {
+        self.rates.lock().unwrap().get(2)
+    }
+
+    /// The fraction of events received in the last completed bucket that were
+    /// kept, between 0 and 1. Returns 1 if no events were received.
+    ///
+    /// Unlike `sampled() / received()` this tracks current load rather than
+    /// the lifetime average, so it can be used to scale counts derived from
+    /// the logs back up to the true event rate. See
+    /// [`BudgetStats::sample_ratio`] for the per-budget equivalent.
+    pub fn sample_ratio(&self) -> f64 {
+        let (received, sampled) = *self.last_bucket.lock().unwrap();
+        ratio(sampled, received)
+    }
+
+    /// How long writes to the output writer have taken. With
+    /// [`non_blocking`](crate::SamplingLayerBuilder::non_blocking) this is
+    /// measured on the I/O thread; otherwise it is time spent on the emitting
+    /// thread.
+    pub fn write_latency(&self) -> LatencyHistogram {
+        self.write_latency.snapshot()
+    }
+
+    /// Counters for the budget at `index`, in the order budgets were added.
+    pub fn budget(&self, index: usize) -> Option<BudgetStats> {
+        self.budgets.get(index).map(BudgetCounters::snapshot)
+    }
+
+    /// Counters for every budget, in the order budgets were added.
+    pub fn budgets(&self) -> Vec<BudgetStats> {
+        self.budgets.iter().map(BudgetCounters::snapshot).collect()
+    }
+
+    /// Counters for the budget added with
+    /// [`budget_named`](crate::SamplingLayerBuilder::budget_named) under
+    /// `name`.
+    pub fn budget_named(&self, name: &str) -> Option<BudgetStats> {
+        self.budget(self.budget_index(name)?)
+    }
+
+    /// The index of the budget named `name`, for use with
+    /// [`budget`](Self::budget).
+    pub fn budget_index(&self, name: &str) -> Option<usize> {
+        self.budgets
+            .iter()
+            .position(|budget| budget.name.as_deref() == Some(name))
+    }
+
+    /// The name of the budget at `index`, if it was given one.
+    pub fn budget_name(&self, index: usize) -> Option<&str> {
+        self.budgets.get(index)?.name.as_deref()
+    }
+
+    /// How the budget at `index` is labelled in exported metrics: its name
+    /// if it has one, otherwise its index.
+    pub(crate) fn budget_label(&self, index: usize) -> String {
+        match self.budget_name(index) {
+            Some(name) => name.to_owned(),
+            None => index.to_string(),
+        }
+    }
+
+    /// Copy every counter into a [`StatsSnapshot`].
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            received: self.received(),
+            sampled: self.sampled(),
+            dropped: self.dropped(),
+            lost: self.lost(),
+            write_errors: self.write_errors(),
+            budgets: self.budgets(),
+            levels: LEVELS
+                .iter()
+                .map(|&level| (level_name(level), self.level(level)))
+                .collect(),
+            targets: self.targets().into_iter().collect(),
+        }
+    }
+
+    /// Sampled and dropped counts for events at `level`.
+    pub fn level(&self, level: Level) -> SampleCounts {
+        let counts = &self.levels[level_index(&level)];
+        SampleCounts {
+            sampled: counts.sampled.load(Ordering::Relaxed),
+            dropped: counts.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sampled and dropped counts for events with the given target.
+    ///
+    /// Only the first 256 targets seen are tracked individually; later ones
+    /// are counted under [`OTHER_TARGETS`].
+    pub fn target(&self, target: &str) -> Option<SampleCounts> {
+        self.targets.lock().unwrap().get(target).copied()
+    }
+
+    /// Sampled and dropped counts for every tracked target, most dropped first.
+    pub fn targets(&self) -> Vec<(&'static str, SampleCounts)> {
+        let mut targets: Vec<_> = self
+            .targets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&target, &counts)| (target, counts))
+            .collect();
+        targets.sort_unstable_by(|a, b| b.1.dropped.cmp(&a.1.dropped).then(a.0.cmp(b.0)));
+        targets
+    }
+
+    /// The `n` callsites with the most dropped events, most dropped first.
+    ///
+    /// Only the first 1024 callsites to drop an event are tracked; drops from
+    /// later callsites are still counted by [`level`](Self::level) and
+    /// [`target`](Self::target) but not here.
+    pub fn top_dropped_callsites(&self, n: usize) -> Vec<CallsiteDrops> {
+        let mut callsites: Vec<_> = self.callsites.lock().unwrap().values().copied().collect();
+        callsites.sort_unstable_by_key(|callsite| std::cmp::Reverse(callsite.dropped));
+        callsites.truncate(n);
+        callsites
+    }
+}
+
+impl BudgetCounters {
+    fn snapshot(&self) -> BudgetStats {
+        BudgetStats {
+            received: self.received.load(Ordering::Relaxed),
+            sampled: self.sampled.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            capacity: self.capacity.load(Ordering::Relaxed),
+            fill: self.fill.load(Ordering::Relaxed),
+            last_bucket_received: self.last_bucket_received.load(Ordering::Relaxed),
+            last_bucket_sampled: self.last_bucket_sampled.load(Ordering::Relaxed),
+        }
+    }
+}
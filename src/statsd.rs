@@ -0,0 +1,158 @@
+use std::fmt::Write;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::stats::Stats;
+
+/// Keep datagrams below a typical Ethernet MTU.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Sends [`Stats`] deltas to a StatsD or DogStatsD endpoint over UDP.
+///
+/// Each report sends the change in every counter since the previous report
+/// as `|c` counters named `<prefix>.received`, `<prefix>.budget.<name>.dropped`
+/// and so on, with unnamed budgets identified by index. Tags added with [`tag`](Self::tag) are appended in the DogStatsD
+/// `|#key:value` syntax.
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// # let (_layer, stats) = tracing_log_sample::SamplingLayer::<tracing_subscriber::Registry>::builder().build();
+/// use std::time::Duration;
+/// use tracing_log_sample::StatsdReporter;
+///
+/// let _guard = StatsdReporter::new("127.0.0.1:8125")?
+///     .prefix("myapp.logs")
+///     .tag("env", "prod")
+///     .spawn(stats, Duration::from_secs(10))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct StatsdReporter {
+    socket: UdpSocket,
+    prefix: String,
+    tags: String,
+    last: Vec<u64>,
+}
+
+impl StatsdReporter {
+    /// Create a reporter sending to `addr`.
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to send to"))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            socket,
+            prefix: "tracing_log_sample".into(),
+            tags: String::new(),
+            last: Vec::new(),
+        })
+    }
+
+    /// Set the metric name prefix. Defaults to `tracing_log_sample`.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Add a DogStatsD tag to every metric.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.push(if self.tags.is_empty() { '#' } else { ',' });
+        let _ = write!(self.tags, "{key}:{value}");
+        self
+    }
+
+    /// Send the counter deltas since the previous report. Counters that have
+    /// not changed are omitted.
+    pub fn report(&mut self, stats: &Stats) -> io::Result<()> {
+        let mut values = vec![
+            ("received".to_owned(), stats.received()),
+            ("sampled".to_owned(), stats.sampled()),
+            ("dropped".to_owned(), stats.dropped()),
+            ("lost".to_owned(), stats.lost()),
+            ("write_errors".to_owned(), stats.write_errors()),
+        ];
+        for (i, budget) in stats.budgets().iter().enumerate() {
+            let i = stats.budget_label(i);
+            values.push((format!("budget.{i}.received"), budget.received));
+            values.push((format!("budget.{i}.sampled"), budget.sampled));
+            values.push((format!("budget.{i}.dropped"), budget.dropped));
+        }
+        self.last.resize(values.len(), 0);
+
+        let mut datagram = String::new();
+        for ((name, value), last) in values.into_iter().zip(&mut self.last) {
+            let delta = value - std::mem::replace(last, value);
+            if delta == 0 {
+                continue;
+            }
+            let mut line = format!("{}.{name}:{delta}|c", self.prefix);
+            if !self.tags.is_empty() {
+                line.push('|');
+                line.push_str(&self.tags);
+            }
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                self.socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Report `stats` every `interval` from a background thread until the
+    /// returned guard is dropped. Send errors are ignored so a missing agent
+    /// doesn't stop reporting.
+    pub fn spawn(mut self, stats: Stats, interval: Duration) -> io::Result<StatsdGuard> {
+        let (stop, wait) = mpsc::channel::<()>();
+        let handle = std::thread::Builder::new()
+            .name("tracing-log-sample-statsd".into())
+            .spawn(move || {
+                loop {
+                    let stopped = match wait.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => false,
+                        Ok(()) | Err(RecvTimeoutError::Disconnected) => true,
+                    };
+                    let _ = self.report(&stats);
+                    if stopped {
+                        break;
+                    }
+                }
+            })?;
+        Ok(StatsdGuard {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+/// Stops a [`StatsdReporter`] thread when dropped, after a final report.
+#[must_use = "dropping the guard immediately stops reporting"]
+pub struct StatsdGuard {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for StatsdGuard {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+
+use tracing::Level;
+
+use crate::stats::Stats;
+
+/// Levels in the order they are reported, most severe first.
+pub(crate) const LEVELS: [Level; 5] = [
+    Level::ERROR,
+    Level::WARN,
+    Level::INFO,
+    Level::DEBUG,
+    Level::TRACE,
+];
+
+pub(crate) fn level_index(level: &Level) -> usize {
+    match *level {
+        Level::ERROR => 0,
+        Level::WARN => 1,
+        Level::INFO => 2,
+        Level::DEBUG => 3,
+        Level::TRACE => 4,
+    }
+}
+
+pub(crate) fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "error",
+        Level::WARN => "warn",
+        Level::INFO => "info",
+        Level::DEBUG => "debug",
+        Level::TRACE => "trace",
+    }
+}
+
+pub(crate) type FormatSummary = Box<dyn Fn(&DropSummary) -> String + Send + Sync>;
+
+/// Events dropped during a single bucket, broken down by level.
+///
+/// Passed to the closure given to
+/// [`drop_summary_format`](crate::SamplingLayerBuilder::drop_summary_format).
+/// The [`Display`](fmt::Display) impl renders e.g.
+/// `342 events dropped (error=12, info=330)`.
+#[derive(Clone, Debug)]
+pub struct DropSummary {
+    level: Level,
+    dropped: [u64; 5],
+}
+
+impl DropSummary {
+    /// The level configured for summary lines.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Total events dropped in the bucket.
+    pub fn total(&self) -> u64 {
+        self.dropped.iter().sum()
+    }
+
+    /// Events at `level` dropped in the bucket.
+    pub fn dropped(&self, level: Level) -> u64 {
+        self.dropped[level_index(&level)]
+    }
+}
+
+impl fmt::Display for DropSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} events dropped (", self.total())?;
+        let mut first = true;
+        for (level, &count) in LEVELS.iter().zip(&self.dropped) {
+            if count == 0 {
+                continue;
+            }
+            if !first {
+                f.write_str(", ")?;
+            }
+            first = false;
+            write!(f, "{}={count}", level_name(*level))?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// How per-bucket drop summaries are rendered.
+pub(crate) struct SummaryConfig {
+    pub(crate) level: Level,
+    pub(crate) format: Option<FormatSummary>,
+}
+
+impl SummaryConfig {
+    /// Render a summary line, or `None` if nothing was dropped.
+    pub(crate) fn render(&self, dropped: [u64; 5]) -> Option<Vec<u8>> {
+        let summary = DropSummary {
+            level: self.level,
+            dropped,
+        };
+        if summary.total() == 0 {
+            return None;
+        }
+        let line = match &self.format {
+            Some(format) => format(&summary),
+            None => format!("{:>5} tracing_log_sample: {summary}\n", self.level),
+        };
+        Some(line.into_bytes())
+    }
+}
+
+/// Render the periodic stats report line.
+pub(crate) fn render_stats(stats: &Stats) -> Vec<u8> {
+    use std::fmt::Write;
+
+    let mut line = format!(
+        "{:>5} tracing_log_sample: sampling stats received={} sampled={} dropped={} lost={}",
+        Level::INFO,
+        stats.received(),
+        stats.sampled(),
+        stats.dropped(),
+        stats.lost(),
+    );
+    for (i, budget) in stats.budgets().iter().enumerate() {
+        let i = stats.budget_label(i);
+        let _ = write!(
+            line,
+            " budget.{i}.received={} budget.{i}.sampled={} budget.{i}.dropped={}",
+            budget.received, budget.sampled, budget.dropped,
+        );
+    }
+    line.push('\n');
+    line.into_bytes()
+}
@@ -0,0 +1,235 @@
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::Path;
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::format::syslog_severity;
+
+/// A syslog facility, combined with the event's level to give the message
+/// priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Facility {
+    /// Generic user-level messages. The default.
+    User,
+    /// Mail system.
+    Mail,
+    /// System daemons.
+    Daemon,
+    /// Security and authorization messages.
+    Auth,
+    /// Reserved for local use.
+    Local0,
+    /// Reserved for local use.
+    Local1,
+    /// Reserved for local use.
+    Local2,
+    /// Reserved for local use.
+    Local3,
+    /// Reserved for local use.
+    Local4,
+    /// Reserved for local use.
+    Local5,
+    /// Reserved for local use.
+    Local6,
+    /// Reserved for local use.
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Mail => 2,
+            Facility::Daemon => 3,
+            Facility::Auth => 4,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+/// Formats each event as an [RFC 5424] syslog message:
+///
+/// ```text
+/// <14>1 2024-01-01T00:00:00.000000Z web-1 myapp 4242 - - hello n=1
+/// ```
+///
+/// The priority combines the [`Facility`] with a severity derived from the
+/// level: error 3, warn 4, info 6, debug and trace 7. The message is the
+/// event's fields as formatted by the layer's field formatter. Pair it with
+/// a [`SyslogWriter`], or select both with
+/// [`SamplingLayerBuilder::syslog`](crate::SamplingLayerBuilder::syslog).
+///
+/// [RFC 5424]: https://datatracker.ietf.org/doc/html/rfc5424
+#[derive(Clone, Debug)]
+pub struct Syslog {
+    facility: Facility,
+    hostname: Option<String>,
+    app_name: Option<String>,
+    pid: u32,
+}
+
+impl Syslog {
+    /// Create a formatter for messages from `facility`. The app name
+    /// defaults to the executable's file name and the hostname to `-`.
+    pub fn new(facility: Facility) -> Self {
+        let app_name = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_name()?.to_str()?.to_owned()));
+        Self {
+            facility,
+            hostname: None,
+            app_name,
+            pid: std::process::id(),
+        }
+    }
+
+    /// Set the HOSTNAME header field.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Set the APP-NAME header field.
+    pub fn app_name(mut self, app_name: impl Into<String>) -> Self {
+        self.app_name = Some(app_name.into());
+        self
+    }
+}
+
+/// Write a header field, which must be printable ASCII without spaces, or
+/// `-` if it is missing or empty.
+fn write_header(w: &mut Writer<'_>, value: Option<&str>) -> fmt::Result {
+    let value = value.unwrap_or_default();
+    if value.is_empty() {
+        return w.write_str(" -");
+    }
+    w.write_char(' ')?;
+    for c in value.chars() {
+        w.write_char(if c.is_ascii_graphic() { c } else { '_' })?;
+    }
+    Ok(())
+}
+
+impl<S, N> FormatEvent<S, N> for Syslog
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let priority = self.facility.code() * 8 + syslog_severity(event.metadata().level());
+        write!(writer, "<{priority}>1 ")?;
+        SystemTime.format_time(&mut writer)?;
+        write_header(&mut writer, self.hostname.as_deref())?;
+        write_header(&mut writer, self.app_name.as_deref())?;
+        write!(writer, " {} - - ", self.pid)?;
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+/// A [`MakeWriter`] sending each line written to it as a datagram to a
+/// syslog endpoint: the local daemon's Unix socket, or a remote host over
+/// UDP. Use it with the [`Syslog`] formatter.
+///
+/// Send errors are ignored, as syslog over datagrams is lossy anyway.
+pub struct SyslogWriter {
+    socket: Socket,
+}
+
+impl SyslogWriter {
+    /// Send to a remote syslog server over UDP, e.g. `"logs.internal:514"`.
+    pub fn udp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to send to"))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            socket: Socket::Udp(socket),
+        })
+    }
+
+    /// Send to a syslog daemon listening on the Unix datagram socket at
+    /// `path`.
+    #[cfg(unix)]
+    pub fn unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket: Socket::Unix(socket),
+        })
+    }
+
+    /// Send to the local syslog daemon via `/dev/log`.
+    #[cfg(unix)]
+    pub fn local() -> io::Result<Self> {
+        Self::unix("/dev/log")
+    }
+
+    fn send(&self, datagram: &[u8]) {
+        let _ = match &self.socket {
+            Socket::Udp(socket) => socket.send(datagram),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(datagram),
+        };
+    }
+}
+
+/// Writer returned by [`SyslogWriter`].
+pub struct SyslogLines<'a> {
+    writer: &'a SyslogWriter,
+}
+
+impl io::Write for SyslogLines<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+            self.writer.send(line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogLines<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogLines { writer: self }
+    }
+}